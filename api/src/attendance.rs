@@ -0,0 +1,387 @@
+use crate::error::{parse_uuid, ApiError};
+use crate::events::find_event;
+use crate::middleware::auth::AuthToken;
+use crate::occurrences::{named_occurrence, Occurrence};
+use crate::store::Events;
+use chrono::Utc;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use rocket::{get, post, put};
+use uuid::Uuid;
+
+/// Whether a user will be at an occurrence.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RsvpStatus {
+    Going,
+    Maybe,
+    Declined,
+}
+
+impl RsvpStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RsvpStatus::Going => "going",
+            RsvpStatus::Maybe => "maybe",
+            RsvpStatus::Declined => "declined",
+        }
+    }
+}
+
+impl std::str::FromStr for RsvpStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "going" => Ok(RsvpStatus::Going),
+            "maybe" => Ok(RsvpStatus::Maybe),
+            "declined" => Ok(RsvpStatus::Declined),
+            other => Err(format!("unknown RSVP status `{}`", other)),
+        }
+    }
+}
+
+/// One user's answer for one occurrence, named like an override by
+/// `(event_id, occurrence_start)`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rsvp {
+    pub event_id: Uuid,
+    pub occurrence_start: i64,
+    pub user_id: Uuid,
+    pub status: RsvpStatus,
+    /// Set for the event's creator.
+    pub is_host: bool,
+    pub updated_at: i64,
+}
+
+/// A comment on one occurrence.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Comment {
+    pub event_id: Uuid,
+    pub occurrence_start: i64,
+    pub comment_id: Uuid,
+    pub user_id: Uuid,
+    pub content: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RsvpRequest {
+    pub status: RsvpStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommentRequest {
+    pub content: String,
+}
+
+/// Comments returned unless `limit` says otherwise.
+const DEFAULT_COMMENTS: usize = 20;
+/// The most comments one request may return.
+const MAX_COMMENTS: usize = 100;
+/// The longest comment accepted, in characters.
+const MAX_COMMENT_CHARS: usize = 2000;
+
+fn check_not_cancelled(occurrence: &Occurrence) -> Result<(), ApiError> {
+    if occurrence.cancelled {
+        return Err(ApiError::conflict(
+            "occurrence_cancelled",
+            "The occurrence has been cancelled",
+        ));
+    }
+    Ok(())
+}
+
+/// Records the caller's answer for the occurrence, replacing an earlier one.
+pub async fn rsvp(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    occurrence: Option<&str>,
+    status: RsvpStatus,
+) -> Result<Rsvp, ApiError> {
+    let event = find_event(events, event_id).await?;
+    let occurrence = named_occurrence(events, &event, occurrence).await?;
+    check_not_cancelled(&occurrence)?;
+
+    let rsvp = Rsvp {
+        event_id,
+        occurrence_start: occurrence.occurrence_start,
+        user_id: auth.user_id,
+        status,
+        is_host: auth.user_id == event.creator_id,
+        updated_at: Utc::now().timestamp_millis(),
+    };
+    events.put_rsvp(&rsvp).await?;
+    Ok(rsvp)
+}
+
+pub async fn add_comment(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    occurrence: Option<&str>,
+    content: &str,
+) -> Result<Comment, ApiError> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err(ApiError::validation(
+            "empty_comment",
+            "A comment needs some text",
+        ));
+    }
+    if content.chars().count() > MAX_COMMENT_CHARS {
+        return Err(ApiError::validation(
+            "comment_too_long",
+            format!("A comment may be at most {} characters", MAX_COMMENT_CHARS),
+        ));
+    }
+    let event = find_event(events, event_id).await?;
+    let occurrence = named_occurrence(events, &event, occurrence).await?;
+    check_not_cancelled(&occurrence)?;
+
+    let now = Utc::now().timestamp_millis();
+    let comment = Comment {
+        event_id,
+        occurrence_start: occurrence.occurrence_start,
+        comment_id: Uuid::new_v4(),
+        user_id: auth.user_id,
+        content: content.to_string(),
+        created_at: now,
+        updated_at: now,
+    };
+    events.insert_comment(&comment).await?;
+    Ok(comment)
+}
+
+/// RSVPs for the caller to one occurrence of an event; `occurrence` is the
+/// start the series gives it, and may be left out for a one-off event.
+#[put("/events/<event_id>/rsvp?<occurrence>", data = "<request>")]
+pub async fn frontend_rsvp(
+    events: &State<Events>,
+    auth: AuthToken,
+    event_id: &str,
+    occurrence: Option<&str>,
+    request: Json<RsvpRequest>,
+) -> Result<Json<Rsvp>, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    Ok(Json(
+        rsvp(events, &auth, event_id, occurrence, request.status).await?,
+    ))
+}
+
+/// Who answered for one occurrence; signed-in users only.
+#[get("/events/<event_id>/rsvps?<occurrence>")]
+pub async fn frontend_event_rsvps(
+    events: &State<Events>,
+    _auth: AuthToken,
+    event_id: &str,
+    occurrence: Option<&str>,
+) -> Result<Json<Vec<Rsvp>>, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    let event = find_event(events, event_id).await?;
+    let occurrence = named_occurrence(events, &event, occurrence).await?;
+    Ok(Json(
+        events.rsvps(event_id, occurrence.occurrence_start).await?,
+    ))
+}
+
+#[post("/events/<event_id>/comments?<occurrence>", data = "<request>")]
+pub async fn frontend_add_comment(
+    events: &State<Events>,
+    auth: AuthToken,
+    event_id: &str,
+    occurrence: Option<&str>,
+    request: Json<CommentRequest>,
+) -> Result<Json<Comment>, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    Ok(Json(
+        add_comment(events, &auth, event_id, occurrence, &request.content).await?,
+    ))
+}
+
+/// The newest comments on one occurrence, newest first; public like the
+/// event itself.
+#[get("/events/<event_id>/comments?<occurrence>&<limit>")]
+pub async fn frontend_event_comments(
+    events: &State<Events>,
+    event_id: &str,
+    occurrence: Option<&str>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<Comment>>, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    let event = find_event(events, event_id).await?;
+    let occurrence = named_occurrence(events, &event, occurrence).await?;
+    let limit = limit.unwrap_or(DEFAULT_COMMENTS).clamp(1, MAX_COMMENTS);
+    Ok(Json(
+        events
+            .comments(event_id, occurrence.occurrence_start, limit)
+            .await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::recurrence::Recurrence;
+    use crate::store::MemoryStore;
+    use crate::users::Role;
+    use chrono::DateTime;
+    use std::sync::Arc;
+
+    // Tuesday 2024-09-03 17:00 UTC, and a week in milliseconds
+    const START: i64 = 1725382800000;
+    const WEEK: i64 = 7 * 24 * 3600 * 1000;
+
+    fn at(millis: i64) -> String {
+        DateTime::from_timestamp_millis(millis)
+            .unwrap()
+            .to_rfc3339()
+    }
+
+    fn member() -> AuthToken {
+        AuthToken {
+            user_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            role: Role::Member,
+            email_verified: true,
+        }
+    }
+
+    async fn event(events: &Events, rrule: Option<&str>) -> Event {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id: Uuid::new_v4(),
+            title: "Weekly meetup".to_string(),
+            description: "Every Tuesday".to_string(),
+            start_time: START,
+            end_time: START + 3600000,
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            created_at: 1725000000000,
+            updated_at: 1725000000000,
+            recurrence: rrule.map(|rrule| Recurrence {
+                rrule: rrule.to_string(),
+                exdates: vec![],
+            }),
+        };
+        events.insert_event(&event).await.unwrap();
+        event
+    }
+
+    #[tokio::test]
+    async fn test_rsvps_belong_to_one_occurrence() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let series = event(&events, Some("FREQ=WEEKLY")).await;
+        let user = member();
+        let second = at(START + WEEK);
+
+        let missing = rsvp(&events, &user, series.event_id, None, RsvpStatus::Going).await;
+        assert_eq!(missing.unwrap_err().code(), "occurrence_required");
+        let off_schedule = at(START + 1);
+        let result = rsvp(
+            &events,
+            &user,
+            series.event_id,
+            Some(off_schedule.as_str()),
+            RsvpStatus::Going,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), "occurrence_not_found");
+
+        rsvp(
+            &events,
+            &user,
+            series.event_id,
+            Some(second.as_str()),
+            RsvpStatus::Going,
+        )
+        .await
+        .unwrap();
+        rsvp(
+            &events,
+            &user,
+            series.event_id,
+            Some(second.as_str()),
+            RsvpStatus::Declined,
+        )
+        .await
+        .unwrap();
+
+        let answers = events.rsvps(series.event_id, START + WEEK).await.unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].status, RsvpStatus::Declined);
+        assert!(events
+            .rsvps(series.event_id, START)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_occurrences_take_no_rsvps_or_comments() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let series = event(&events, Some("FREQ=WEEKLY")).await;
+        let user = member();
+        events
+            .put_override(&Occurrence {
+                cancelled: true,
+                ..Occurrence::of(&series, START)
+            })
+            .await
+            .unwrap();
+        let first = at(START);
+
+        let result = rsvp(
+            &events,
+            &user,
+            series.event_id,
+            Some(first.as_str()),
+            RsvpStatus::Going,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), "occurrence_cancelled");
+        let result = add_comment(&events, &user, series.event_id, Some(first.as_str()), "Hi").await;
+        assert_eq!(result.unwrap_err().code(), "occurrence_cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_comments_on_a_one_off_event_need_no_occurrence() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let one_off = event(&events, None).await;
+        let user = member();
+
+        let result = add_comment(&events, &user, one_off.event_id, None, "   ").await;
+        assert_eq!(result.unwrap_err().code(), "empty_comment");
+        add_comment(&events, &user, one_off.event_id, None, "First")
+            .await
+            .unwrap();
+        let start = at(START);
+        add_comment(
+            &events,
+            &user,
+            one_off.event_id,
+            Some(start.as_str()),
+            "Second",
+        )
+        .await
+        .unwrap();
+
+        let comments = events.comments(one_off.event_id, START, 10).await.unwrap();
+        assert_eq!(comments.len(), 2);
+        assert!(comments[0].created_at >= comments[1].created_at);
+
+        events
+            .delete_event(one_off.event_id, one_off.creator_id, one_off.start_time)
+            .await
+            .unwrap();
+        assert!(events
+            .comments(one_off.event_id, START, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,519 @@
+use cassandra_cpp::Consistency;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::path::PathBuf;
+
+/// Application settings, read through Rocket's figment so they come from the
+/// same places as Rocket's own (`Rocket.toml`, profiles, environment).
+///
+/// Besides `ROCKET_*`, every key can be set with an `OPENMEET_` variable, using
+/// `__` for nesting: `OPENMEET_CASSANDRA__KEYSPACE=openmeet_staging`. Secrets
+/// may instead be read from a file (`token_secret_file`, `password_file`,
+/// `smtp_password_file`), which is how Docker and Kubernetes mount them. The
+/// listen address and port are Rocket's `address` and `port`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub storage: Backend,
+    /// Apply pending schema migrations at launch instead of only verifying them.
+    #[serde(default)]
+    pub migrate_on_startup: bool,
+    pub cassandra: CassandraConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Cassandra,
+    Memory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CassandraConfig {
+    /// Comma separated hosts, as accepted by the driver.
+    #[serde(default)]
+    pub contact_points: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    #[serde(default = "default_keyspace")]
+    pub keyspace: String,
+    /// Replication map used when the migration runner creates the keyspace.
+    #[serde(default = "default_replication")]
+    pub replication: String,
+    /// Applied to every statement the API executes.
+    #[serde(
+        default = "default_consistency",
+        deserialize_with = "deserialize_consistency"
+    )]
+    pub consistency: Consistency,
+    /// Used for the serial phase of lightweight transactions.
+    #[serde(
+        default = "default_serial_consistency",
+        deserialize_with = "deserialize_consistency"
+    )]
+    pub serial_consistency: Consistency,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub token_secret: Option<String>,
+    #[serde(default)]
+    pub token_secret_file: Option<PathBuf>,
+    /// Lifetime of an access token, in seconds. Kept short: a refresh token
+    /// gets a new one.
+    #[serde(default = "default_token_lifetime")]
+    pub token_lifetime_secs: i64,
+    /// Lifetime of a session and its refresh token, in seconds.
+    #[serde(default = "default_refresh_lifetime")]
+    pub refresh_lifetime_secs: i64,
+    #[serde(default = "default_bcrypt_cost")]
+    pub bcrypt_cost: u32,
+    /// How long a password reset link stays usable, in seconds.
+    #[serde(default = "default_reset_token_lifetime")]
+    pub reset_token_lifetime_secs: i64,
+    /// How long an email verification link stays usable, in seconds.
+    #[serde(default = "default_verification_token_lifetime")]
+    pub verification_token_lifetime_secs: i64,
+    /// Minimum time between two verification mails to the same user.
+    #[serde(default = "default_verification_resend_interval")]
+    pub verification_resend_interval_secs: i64,
+    /// Refuse to let users create events until they verify their address.
+    #[serde(default = "default_true")]
+    pub require_verified_email_for_events: bool,
+    /// How long the token from the password step of a two-factor login
+    /// stays usable for the code step, in seconds.
+    #[serde(default = "default_mfa_challenge_lifetime")]
+    pub mfa_challenge_lifetime_secs: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailConfig {
+    #[serde(default)]
+    pub transport: MailTransport,
+    /// The From header, e.g. `OpenMeet <no-reply@openmeet.net>`.
+    #[serde(default = "default_mail_from")]
+    pub from: String,
+    /// Where the frontend is served; links in mail point here.
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
+    /// Directory the spool transport writes `.eml` files to.
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: PathBuf,
+    #[serde(default)]
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    #[serde(default)]
+    pub smtp_username: Option<String>,
+    #[serde(default)]
+    pub smtp_password: Option<String>,
+    #[serde(default)]
+    pub smtp_password_file: Option<PathBuf>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::default(),
+            from: default_mail_from(),
+            public_url: default_public_url(),
+            spool_dir: default_spool_dir(),
+            smtp_host: String::new(),
+            smtp_port: default_smtp_port(),
+            smtp_username: None,
+            smtp_password: None,
+            smtp_password_file: None,
+        }
+    }
+}
+
+/// Limits on failed logins. Failures are counted per account and per client
+/// address; a key forgets its failures once it has been quiet for
+/// `failure_window_secs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginConfig {
+    /// Failures after which an account is locked for `lockout_secs`.
+    #[serde(default = "default_max_account_failures")]
+    pub max_account_failures: i32,
+    #[serde(default = "default_lockout")]
+    pub lockout_secs: i64,
+    /// Delay before the next attempt after the first failure, doubling with
+    /// every further one up to `backoff_max_secs`.
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base_secs: i64,
+    #[serde(default = "default_backoff_max")]
+    pub backoff_max_secs: i64,
+    /// Failures from one address, over any accounts, after which it is
+    /// refused until the window passes.
+    #[serde(default = "default_max_ip_failures")]
+    pub max_ip_failures: i32,
+    #[serde(default = "default_failure_window")]
+    pub failure_window_secs: i64,
+    /// How long failed logins and each user's login history are kept.
+    #[serde(default = "default_audit_retention_days")]
+    pub audit_retention_days: i64,
+    /// Header a reverse proxy in front of the API puts the client's address
+    /// in, e.g. `X-Forwarded-For`. Unset, the address the connection came
+    /// from is used; only set it when every request passes the proxy, since
+    /// clients can send the header themselves.
+    #[serde(default)]
+    pub trusted_proxy_header: Option<String>,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_account_failures: default_max_account_failures(),
+            lockout_secs: default_lockout(),
+            backoff_base_secs: default_backoff_base(),
+            backoff_max_secs: default_backoff_max(),
+            max_ip_failures: default_max_ip_failures(),
+            failure_window_secs: default_failure_window(),
+            audit_retention_days: default_audit_retention_days(),
+            trusted_proxy_header: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Write each message to `spool_dir` and log that it was queued.
+    #[default]
+    Spool,
+    Smtp,
+}
+
+fn default_keyspace() -> String {
+    "openmeet".to_string()
+}
+
+fn default_replication() -> String {
+    "{'class': 'SimpleStrategy', 'replication_factor': 1}".to_string()
+}
+
+fn default_consistency() -> Consistency {
+    Consistency::LOCAL_QUORUM
+}
+
+fn default_serial_consistency() -> Consistency {
+    Consistency::LOCAL_SERIAL
+}
+
+fn default_token_lifetime() -> i64 {
+    900
+}
+
+fn default_refresh_lifetime() -> i64 {
+    30 * 24 * 3600
+}
+
+fn default_bcrypt_cost() -> u32 {
+    bcrypt::DEFAULT_COST
+}
+
+fn default_reset_token_lifetime() -> i64 {
+    3600
+}
+
+fn default_verification_token_lifetime() -> i64 {
+    48 * 3600
+}
+
+fn default_verification_resend_interval() -> i64 {
+    300
+}
+
+fn default_max_account_failures() -> i32 {
+    5
+}
+
+fn default_lockout() -> i64 {
+    900
+}
+
+fn default_backoff_base() -> i64 {
+    1
+}
+
+fn default_backoff_max() -> i64 {
+    60
+}
+
+fn default_max_ip_failures() -> i32 {
+    50
+}
+
+fn default_failure_window() -> i64 {
+    900
+}
+
+fn default_audit_retention_days() -> i64 {
+    90
+}
+
+fn default_mfa_challenge_lifetime() -> i64 {
+    300
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_mail_from() -> String {
+    "OpenMeet <no-reply@openmeet.net>".to_string()
+}
+
+fn default_public_url() -> String {
+    "http://localhost:3000".to_string()
+}
+
+fn default_spool_dir() -> PathBuf {
+    PathBuf::from("mail-spool")
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn deserialize_consistency<'de, D: Deserializer<'de>>(d: D) -> Result<Consistency, D::Error> {
+    let value = String::deserialize(d)?;
+    value
+        .to_ascii_uppercase()
+        .parse()
+        .map_err(|_| serde::de::Error::custom(format!("unknown consistency level `{}`", value)))
+}
+
+/// Rocket's figment plus `OPENMEET_` variables and the `CASSANDRA_*` variables
+/// older deployments (and `.env.dev`) still set.
+pub fn figment() -> Figment {
+    rocket::Config::figment()
+        .merge(
+            Env::raw()
+                .only(&[
+                    "CASSANDRA_CONTACT_POINTS",
+                    "CASSANDRA_USERNAME",
+                    "CASSANDRA_PASSWORD",
+                ])
+                .map(|key| key.as_str().replacen("cassandra_", "cassandra.", 1).into())
+                .global(),
+        )
+        .merge(Env::prefixed("OPENMEET_").split("__").global())
+}
+
+impl Config {
+    /// Extracts the config and resolves secret files, failing on anything
+    /// missing so a misconfigured server never starts.
+    pub fn from_figment(figment: &Figment) -> Result<Config, String> {
+        let mut config: Config = figment.extract().map_err(|e| e.to_string())?;
+
+        if config.auth.token_secret.is_none() {
+            if let Some(path) = &config.auth.token_secret_file {
+                config.auth.token_secret = Some(read_secret(path)?);
+            }
+        }
+        if config.cassandra.password.is_none() {
+            if let Some(path) = &config.cassandra.password_file {
+                config.cassandra.password = Some(read_secret(path)?);
+            }
+        }
+        if config.mail.smtp_password.is_none() {
+            if let Some(path) = &config.mail.smtp_password_file {
+                config.mail.smtp_password = Some(read_secret(path)?);
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match &self.auth.token_secret {
+            None => return Err("auth.token_secret or auth.token_secret_file must be set".into()),
+            Some(secret) if secret.len() < 32 => {
+                return Err("auth.token_secret must be at least 32 bytes".into())
+            }
+            Some(_) => {}
+        }
+        if self.auth.token_lifetime_secs <= 0 {
+            return Err("auth.token_lifetime_secs must be positive".into());
+        }
+        if self.auth.refresh_lifetime_secs < self.auth.token_lifetime_secs {
+            return Err(
+                "auth.refresh_lifetime_secs must not be shorter than auth.token_lifetime_secs"
+                    .into(),
+            );
+        }
+        if !(4..=31).contains(&self.auth.bcrypt_cost) {
+            return Err("auth.bcrypt_cost must be between 4 and 31".into());
+        }
+        if self.auth.reset_token_lifetime_secs <= 0 {
+            return Err("auth.reset_token_lifetime_secs must be positive".into());
+        }
+        if self.auth.verification_token_lifetime_secs <= 0 {
+            return Err("auth.verification_token_lifetime_secs must be positive".into());
+        }
+        if self.auth.mfa_challenge_lifetime_secs <= 0 {
+            return Err("auth.mfa_challenge_lifetime_secs must be positive".into());
+        }
+        if self.login.max_account_failures <= 0 || self.login.max_ip_failures <= 0 {
+            return Err(
+                "login.max_account_failures and login.max_ip_failures must be positive".into(),
+            );
+        }
+        if self.login.lockout_secs <= 0 || self.login.failure_window_secs <= 0 {
+            return Err("login.lockout_secs and login.failure_window_secs must be positive".into());
+        }
+        if self.login.backoff_base_secs < 0
+            || self.login.backoff_max_secs < self.login.backoff_base_secs
+        {
+            return Err(
+                "login.backoff_base_secs must not be negative or above login.backoff_max_secs"
+                    .into(),
+            );
+        }
+        if self.login.audit_retention_days <= 0 {
+            return Err("login.audit_retention_days must be positive".into());
+        }
+        if self.mail.transport == MailTransport::Smtp && self.mail.smtp_host.trim().is_empty() {
+            return Err("mail.smtp_host must be set for the smtp transport".into());
+        }
+        if self.storage == Backend::Cassandra {
+            if self.cassandra.contact_points.trim().is_empty() {
+                return Err("cassandra.contact_points must be set".into());
+            }
+            let keyspace = &self.cassandra.keyspace;
+            if keyspace.is_empty()
+                || !keyspace
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(format!(
+                    "cassandra.keyspace `{}` is not a valid name",
+                    keyspace
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The token signing secret; `from_figment` guarantees it is set.
+    pub fn token_secret(&self) -> &[u8] {
+        self.auth
+            .token_secret
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes()
+    }
+
+    /// In-memory storage, a fixed secret, the cheapest bcrypt cost and mail
+    /// spooled to the temp directory.
+    #[cfg(test)]
+    pub fn for_tests() -> Config {
+        Config {
+            storage: Backend::Memory,
+            migrate_on_startup: false,
+            cassandra: CassandraConfig {
+                contact_points: String::new(),
+                username: None,
+                password: None,
+                password_file: None,
+                keyspace: default_keyspace(),
+                replication: default_replication(),
+                consistency: default_consistency(),
+                serial_consistency: default_serial_consistency(),
+            },
+            auth: AuthConfig {
+                token_secret: Some("test-secret-that-is-at-least-32-bytes".to_string()),
+                token_secret_file: None,
+                token_lifetime_secs: default_token_lifetime(),
+                refresh_lifetime_secs: default_refresh_lifetime(),
+                bcrypt_cost: 4,
+                reset_token_lifetime_secs: default_reset_token_lifetime(),
+                verification_token_lifetime_secs: default_verification_token_lifetime(),
+                verification_resend_interval_secs: default_verification_resend_interval(),
+                require_verified_email_for_events: true,
+                mfa_challenge_lifetime_secs: default_mfa_challenge_lifetime(),
+            },
+            mail: MailConfig {
+                spool_dir: std::env::temp_dir().join("openmeet-test-mail"),
+                ..MailConfig::default()
+            },
+            login: LoginConfig::default(),
+        }
+    }
+}
+
+fn read_secret(path: &PathBuf) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|s| s.trim_end().to_string())
+        .map_err(|e| format!("Failed to read secret file {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::{Format, Toml};
+
+    fn load(toml: &str) -> Result<Config, String> {
+        Config::from_figment(&Figment::from(Toml::string(toml)))
+    }
+
+    #[test]
+    fn test_defaults_fill_optional_values() {
+        let config = load(
+            r#"
+            [cassandra]
+            contact_points = "127.0.0.1"
+            [auth]
+            token_secret = "0123456789abcdef0123456789abcdef"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.storage, Backend::Cassandra);
+        assert_eq!(config.cassandra.keyspace, "openmeet");
+        assert_eq!(config.cassandra.consistency, Consistency::LOCAL_QUORUM);
+        assert_eq!(config.auth.token_lifetime_secs, 900);
+    }
+
+    #[test]
+    fn test_missing_secret_fails() {
+        let err = load(
+            r#"
+            [cassandra]
+            contact_points = "127.0.0.1"
+            [auth]
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.contains("token_secret"));
+    }
+
+    #[test]
+    fn test_unknown_consistency_fails() {
+        let err = load(
+            r#"
+            [cassandra]
+            contact_points = "127.0.0.1"
+            consistency = "most"
+            [auth]
+            token_secret = "0123456789abcdef0123456789abcdef"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.contains("consistency"));
+    }
+}
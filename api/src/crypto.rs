@@ -0,0 +1,178 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Lower-case hex encoding.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Inverse of `hex`; `None` unless `s` is an even number of hex digits.
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Hex SHA-256 of `data`. Used to store single-use secrets (refresh and reset
+/// tokens) so a leaked table does not hand out working tokens.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(digest(&SHA256, data).as_ref())
+}
+
+/// Hex HMAC-SHA256 of `data` under `key`. Signs links that carry their own
+/// state (email verification) so they cannot be forged without the key.
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hex(hmac::sign(&key, data).as_ref())
+}
+
+/// Raw HMAC-SHA256 of `data` under `key`, e.g. to derive a subkey.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(hmac::sign(&key, data).as_ref());
+    out
+}
+
+/// `len` bytes from the system CSPRNG.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system random number generator failed");
+    bytes
+}
+
+/// `len` bytes from the system CSPRNG, hex encoded.
+pub fn random_token(len: usize) -> String {
+    hex(&random_bytes(len))
+}
+
+/// RFC 4648 base32 without padding, as authenticator apps expect secrets.
+pub fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[(buffer >> bits & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[(buffer << (5 - bits) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Encrypts with AES-256-GCM under a fresh random nonce; the result is
+/// `hex(nonce || ciphertext || tag)`. For secrets that must be read back,
+/// unlike tokens, which are only ever hashed.
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> String {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("AES-256 key"));
+    let nonce_bytes = random_bytes(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&nonce_bytes).expect("nonce length");
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
+        .expect("AES-GCM seal");
+    let mut sealed = nonce_bytes;
+    sealed.extend_from_slice(&in_out);
+    hex(&sealed)
+}
+
+/// Inverse of `seal`; `None` if the data is malformed, was tampered with or
+/// was sealed under another key.
+pub fn open(key: &[u8; 32], sealed: &str) -> Option<Vec<u8>> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).ok()?);
+    let sealed = unhex(sealed)?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = key.open_in_place(nonce, Aad::empty(), &mut in_out).ok()?;
+    Some(plaintext.to_vec())
+}
+
+/// Compares two secrets without leaking where they differ through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    verify_slices_are_equal(a.as_bytes(), b.as_bytes()).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_unhex_round_trips() {
+        assert_eq!(unhex(&hex(b"a@b.c")).unwrap(), b"a@b.c");
+        assert!(unhex("abc").is_none());
+        assert!(unhex("zz").is_none());
+    }
+
+    #[test]
+    fn test_base32() {
+        // RFC 4648 section 10, minus the padding
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"fo"), "MZXQ");
+        assert_eq!(base32(b"foob"), "MZXW6YQ");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn test_seal_round_trips_and_detects_tampering() {
+        let key = [7u8; 32];
+        let sealed = seal(&key, b"totp secret");
+        assert_ne!(seal(&key, b"totp secret"), sealed);
+        assert_eq!(open(&key, &sealed).unwrap(), b"totp secret");
+        assert!(open(&[8u8; 32], &sealed).is_none());
+
+        let mut tampered = sealed.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        assert!(open(&key, &String::from_utf8(tampered).unwrap()).is_none());
+        assert!(open(&key, "00").is_none());
+    }
+
+    #[test]
+    fn test_hmac_sha256_hex() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_random_token_is_unique_hex() {
+        let a = random_token(32);
+        let b = random_token(32);
+        assert_eq!(a.len(), 64);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+}
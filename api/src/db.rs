@@ -0,0 +1,130 @@
+use crate::config::CassandraConfig;
+use cassandra_cpp::{
+    Batch, CassErrorCode, CassResult, Cluster, Consistency, ErrorKind, Session, Statement,
+};
+use rocket::tokio::sync::{Mutex, RwLock};
+
+/// A single long-lived Cassandra session shared by every request.
+///
+/// The session is created once at launch and handed to handlers through
+/// `rocket::State`. If the cluster becomes unreachable the session is dropped
+/// and the next caller transparently reconnects.
+pub struct Db {
+    cluster: Mutex<Cluster>,
+    session: RwLock<Option<Session>>,
+    keyspace: String,
+    consistency: Consistency,
+    serial_consistency: Consistency,
+}
+
+impl Db {
+    pub async fn connect(config: &CassandraConfig) -> Result<Db, String> {
+        let db = Db {
+            cluster: Mutex::new(init_cluster(config).await?),
+            session: RwLock::new(None),
+            keyspace: config.keyspace.clone(),
+            consistency: config.consistency,
+            serial_consistency: config.serial_consistency,
+        };
+        db.reconnect().await?;
+        Ok(db)
+    }
+
+    /// The keyspace every table lives in.
+    pub fn keyspace(&self) -> &str {
+        &self.keyspace
+    }
+
+    /// Returns the shared session, reconnecting first if it was dropped.
+    pub async fn session(&self) -> Result<Session, String> {
+        if let Some(session) = self.session.read().await.as_ref() {
+            return Ok(session.clone());
+        }
+        self.reconnect().await
+    }
+
+    async fn reconnect(&self) -> Result<Session, String> {
+        let mut slot = self.session.write().await;
+        // another request may have reconnected while we waited for the lock
+        if let Some(session) = slot.as_ref() {
+            return Ok(session.clone());
+        }
+
+        let session = self
+            .cluster
+            .lock()
+            .await
+            .connect()
+            .await
+            .map_err(|e| format!("Failed to connect to Cassandra: {}", e))?;
+        *slot = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Executes a statement built from this session at the configured
+    /// consistency, dropping the session when the failure means the connection
+    /// itself is gone.
+    pub async fn execute(&self, mut statement: Statement) -> cassandra_cpp::Result<CassResult> {
+        statement.set_consistency(self.consistency)?;
+        statement.set_serial_consistency(self.serial_consistency)?;
+        let result = statement.execute().await;
+        self.check(result).await
+    }
+
+    /// Executes a batch like `execute` does a statement.
+    pub async fn execute_batch(&self, mut batch: Batch) -> cassandra_cpp::Result<CassResult> {
+        batch.set_consistency(self.consistency)?;
+        batch.set_serial_consistency(self.serial_consistency)?;
+        let result = batch.execute().await;
+        self.check(result).await
+    }
+
+    async fn check(
+        &self,
+        result: cassandra_cpp::Result<CassResult>,
+    ) -> cassandra_cpp::Result<CassResult> {
+        let lost = match &result {
+            Err(e) if is_connection_error(e) => {
+                eprintln!("Cassandra connection lost, will reconnect: {}", e);
+                true
+            }
+            _ => false,
+        };
+        if lost {
+            self.session.write().await.take();
+        }
+        result
+    }
+
+    /// Closes the session; dropping the last handle waits for in-flight requests.
+    pub async fn close(&self) {
+        self.session.write().await.take();
+    }
+}
+
+fn is_connection_error(error: &cassandra_cpp::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::CassError(
+            CassErrorCode::LIB_NO_HOSTS_AVAILABLE | CassErrorCode::LIB_UNABLE_TO_CONNECT,
+            _
+        )
+    )
+}
+
+pub async fn init_cluster(config: &CassandraConfig) -> Result<Cluster, String> {
+    let mut cluster = Cluster::default();
+    cluster
+        .set_contact_points(&config.contact_points)
+        .map_err(|e| format!("Failed to set contact points: {}", e))?;
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        if !username.is_empty() && !password.is_empty() {
+            cluster
+                .set_credentials(username, password)
+                .map_err(|e| format!("Failed to set credentials: {}", e))?;
+        }
+    }
+
+    Ok(cluster)
+}
@@ -0,0 +1,347 @@
+use crate::store::StoreError;
+use chrono::DateTime;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::{catch, catchers, Catcher};
+use serde_json::json;
+use std::fmt;
+use std::io::Cursor;
+use uuid::Uuid;
+
+/// Every error a handler can return. Rendered as an RFC 7807
+/// `application/problem+json` body whose `code` is stable, so the frontend can
+/// key its messages off it instead of parsing `detail`.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    Validation {
+        code: &'static str,
+        detail: String,
+    },
+    Conflict {
+        code: &'static str,
+        detail: String,
+    },
+    NotFound {
+        code: &'static str,
+        detail: String,
+    },
+    Unauthorized {
+        code: &'static str,
+        detail: String,
+    },
+    Forbidden {
+        code: &'static str,
+        detail: String,
+    },
+    /// The resource changed since the version the client named.
+    PreconditionFailed {
+        code: &'static str,
+        detail: String,
+    },
+    /// The request must name the version it changes.
+    PreconditionRequired {
+        code: &'static str,
+        detail: String,
+    },
+    /// Sent with a `Retry-After` header.
+    TooManyRequests {
+        code: &'static str,
+        detail: String,
+        retry_after_secs: u64,
+    },
+    /// The resource is locked for a while; also sent with `Retry-After`.
+    Locked {
+        code: &'static str,
+        detail: String,
+        retry_after_secs: u64,
+    },
+    /// The storage backend failed. The message is logged, never sent.
+    Storage(String),
+    /// Anything else that is our fault, e.g. hashing or token encoding.
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn validation(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Validation {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn conflict(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Conflict {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn not_found(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::NotFound {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn unauthorized(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Unauthorized {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn forbidden(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::Forbidden {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn precondition_failed(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::PreconditionFailed {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn precondition_required(code: &'static str, detail: impl Into<String>) -> Self {
+        ApiError::PreconditionRequired {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn too_many_requests(
+        code: &'static str,
+        detail: impl Into<String>,
+        retry_after_secs: u64,
+    ) -> Self {
+        ApiError::TooManyRequests {
+            code,
+            detail: detail.into(),
+            retry_after_secs,
+        }
+    }
+
+    pub fn locked(code: &'static str, detail: impl Into<String>, retry_after_secs: u64) -> Self {
+        ApiError::Locked {
+            code,
+            detail: detail.into(),
+            retry_after_secs,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::Validation { .. } => Status::UnprocessableEntity,
+            ApiError::Conflict { .. } => Status::Conflict,
+            ApiError::NotFound { .. } => Status::NotFound,
+            ApiError::Unauthorized { .. } => Status::Unauthorized,
+            ApiError::Forbidden { .. } => Status::Forbidden,
+            ApiError::PreconditionFailed { .. } => Status::PreconditionFailed,
+            ApiError::PreconditionRequired { .. } => Status::PreconditionRequired,
+            ApiError::TooManyRequests { .. } => Status::TooManyRequests,
+            ApiError::Locked { .. } => Status::Locked,
+            ApiError::Storage(_) | ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation { code, .. }
+            | ApiError::Conflict { code, .. }
+            | ApiError::NotFound { code, .. }
+            | ApiError::Unauthorized { code, .. }
+            | ApiError::Forbidden { code, .. }
+            | ApiError::PreconditionFailed { code, .. }
+            | ApiError::PreconditionRequired { code, .. }
+            | ApiError::TooManyRequests { code, .. }
+            | ApiError::Locked { code, .. } => code,
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::Validation { detail, .. }
+            | ApiError::Conflict { detail, .. }
+            | ApiError::NotFound { detail, .. }
+            | ApiError::Unauthorized { detail, .. }
+            | ApiError::Forbidden { detail, .. }
+            | ApiError::PreconditionFailed { detail, .. }
+            | ApiError::PreconditionRequired { detail, .. }
+            | ApiError::TooManyRequests { detail, .. }
+            | ApiError::Locked { detail, .. } => detail,
+            ApiError::Storage(_) | ApiError::Internal(_) => "The request could not be completed",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Storage(msg) | ApiError::Internal(msg) => write!(f, "{}", msg),
+            other => write!(f, "{}", other.detail()),
+        }
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        ApiError::Storage(e.to_string())
+    }
+}
+
+/// Parses a UUID path segment, naming the parameter in the error.
+pub fn parse_uuid(value: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(value)
+        .map_err(|e| ApiError::validation("invalid_uuid", format!("Invalid UUID {}: {}", value, e)))
+}
+
+/// Parses an RFC 3339 timestamp from a request body into epoch milliseconds.
+pub fn parse_timestamp(field: &str, value: &str) -> Result<i64, ApiError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| {
+            ApiError::validation(
+                "invalid_timestamp",
+                format!("{} must be an RFC 3339 timestamp: {}", field, e),
+            )
+        })
+}
+
+fn problem(status: Status, code: &str, detail: &str) -> serde_json::Value {
+    json!({
+        "type": format!("https://openmeet.net/problems/{}", code),
+        "title": status.reason_lossy(),
+        "status": status.code,
+        "code": code,
+        "detail": detail,
+    })
+}
+
+fn problem_response<'o>(error: &ApiError) -> response::Result<'o> {
+    let status = error.status();
+    let body = problem(status, error.code(), error.detail()).to_string();
+    let mut response = Response::build();
+    response
+        .status(status)
+        .header(ContentType::new("application", "problem+json"));
+    if let ApiError::TooManyRequests {
+        retry_after_secs, ..
+    }
+    | ApiError::Locked {
+        retry_after_secs, ..
+    } = error
+    {
+        response.raw_header("Retry-After", retry_after_secs.to_string());
+    }
+    response.sized_body(body.len(), Cursor::new(body)).ok()
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for ApiError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        match &self {
+            ApiError::Storage(msg) => eprintln!("Storage error: {}", msg),
+            ApiError::Internal(msg) => eprintln!("Internal error: {}", msg),
+            _ => {}
+        }
+        problem_response(&self)
+    }
+}
+
+/// The error a request guard failed with, kept so the catcher can report it.
+struct GuardError(Option<ApiError>);
+
+/// Records why a request guard failed; guards return only a status to Rocket,
+/// so without this the catcher could not tell an expired token from a missing one.
+pub fn remember(request: &Request<'_>, error: &ApiError) {
+    request.local_cache(|| GuardError(Some(error.clone())));
+}
+
+/// Problem bodies for failures Rocket produces itself, such as a failed
+/// request guard or a body that does not deserialize.
+#[catch(default)]
+fn default_catcher(status: Status, request: &Request) -> (Status, (ContentType, String)) {
+    if let GuardError(Some(error)) = request.local_cache(|| GuardError(None)) {
+        if error.status() == status {
+            let body = problem(status, error.code(), error.detail()).to_string();
+            return (
+                status,
+                (ContentType::new("application", "problem+json"), body),
+            );
+        }
+    }
+    let code = match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        422 => "invalid_body",
+        _ => "internal_error",
+    };
+    let body = problem(status, code, status.reason_lossy()).to_string();
+    (
+        status,
+        (ContentType::new("application", "problem+json"), body),
+    )
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+    use rocket::{get, routes};
+
+    #[get("/conflict")]
+    fn conflict() -> Result<(), ApiError> {
+        Err(ApiError::conflict("email_taken", "Email already exists"))
+    }
+
+    #[test]
+    fn test_responder_emits_problem_json() {
+        let rocket = rocket::build()
+            .mount("/", routes![conflict])
+            .register("/", catchers());
+        let client = Client::untracked(rocket).unwrap();
+
+        let response = client.get("/conflict").dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "problem+json"))
+        );
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["code"], "email_taken");
+        assert_eq!(body["status"], 409);
+        assert_eq!(body["detail"], "Email already exists");
+
+        let response = client.get("/missing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["code"], "not_found");
+    }
+
+    #[test]
+    fn test_parse_timestamp_rejects_garbage() {
+        assert_eq!(
+            parse_timestamp("start_time", "2024-09-03T17:39:57Z").unwrap(),
+            1725385197000
+        );
+        let err = parse_timestamp("start_time", "next tuesday").unwrap_err();
+        assert_eq!(err.code(), "invalid_timestamp");
+        assert_eq!(err.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn test_storage_detail_is_not_leaked() {
+        let err = ApiError::Storage("connection refused to 10.0.0.4".to_string());
+        assert_eq!(err.detail(), "The request could not be completed");
+    }
+}
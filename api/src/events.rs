@@ -1,32 +1,42 @@
-use crate::init_cluster;
+use crate::config::Config;
+use crate::crypto::{constant_time_eq, hex, unhex};
+use crate::error::{parse_timestamp, parse_uuid, ApiError};
+use crate::geo;
 use crate::middleware::auth::AuthToken;
-use cassandra_cpp::{BindRustType, LendingIterator, Session, Statement};
-use chrono::{DateTime, Utc};
-use rocket::http::Status;
+use crate::middleware::conditional::{etag, IfMatch};
+use crate::occurrences::{
+    delete_in_scope, edit_scope, newest_occurrences, occurrences_in, prune_overrides,
+    update_in_scope,
+};
+use crate::policy::{authorize, Action};
+use crate::recurrence::{Recurrence, Rule};
+use crate::store::Events;
+use crate::verification::sign;
+use chrono::Utc;
+use rocket::futures::future::{join, join_all};
+use rocket::http::{Header, Status};
 use rocket::serde::{json::Json, Deserialize, Serialize};
 use rocket::State;
-use rocket::{delete, get, post, put};
-use uuid::{uuid, Uuid};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct EventDeleteRequest {
-    pub event_id: Uuid,
-    pub user_id: Uuid,
-}
+use rocket::{delete, get, patch, post, put, FromForm, Responder};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Event {
-    event_id: Uuid,
-    creator_id: Uuid,
-    title: String,
-    description: String,
-    start_time: i64,
-    end_time: i64,
-    lat: f64,
-    lon: f64,
-    address: String,
-    created_at: i64,
-    updated_at: i64,
+    pub event_id: Uuid,
+    pub creator_id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub address: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<Recurrence>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -38,431 +48,840 @@ pub struct CreateEventRequest {
     lat: f64,
     lon: f64,
     address: String,
-    creator_id: Uuid,
+    /// An RFC 5545 `RRULE` such as `FREQ=WEEKLY;BYDAY=TU`, for an event that
+    /// repeats from `start_time`.
+    rrule: Option<String>,
+    /// Occurrences taken out of the series, by their start time.
+    #[serde(default)]
+    exdates: Vec<String>,
+}
+
+/// The body of `PUT /events/<id>`: every field of the event, plus the
+/// `updated_at` it was read at unless `If-Match` carries that instead.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UpdateEventRequest {
+    #[serde(flatten)]
+    event: CreateEventRequest,
+    updated_at: Option<i64>,
+}
+
+/// The body of `PATCH /events/<id>`: only the fields to change.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PatchEventRequest {
+    title: Option<String>,
+    description: Option<String>,
+    start_time: Option<String>,
+    end_time: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    address: Option<String>,
+    /// A new `RRULE`; an empty one makes the event happen once.
+    rrule: Option<String>,
+    exdates: Option<Vec<String>>,
+    /// Cancels or restores one occurrence; only with `scope=this`.
+    cancelled: Option<bool>,
+    updated_at: Option<i64>,
+}
+
+impl From<UpdateEventRequest> for PatchEventRequest {
+    fn from(request: UpdateEventRequest) -> Self {
+        let event = request.event;
+        PatchEventRequest {
+            title: Some(event.title),
+            description: Some(event.description),
+            start_time: Some(event.start_time),
+            end_time: Some(event.end_time),
+            lat: Some(event.lat),
+            lon: Some(event.lon),
+            address: Some(event.address),
+            rrule: Some(event.rrule.unwrap_or_default()),
+            exdates: Some(event.exdates),
+            cancelled: None,
+            updated_at: request.updated_at,
+        }
+    }
+}
+
+impl PatchEventRequest {
+    /// `event` with the fields of the request applied, except the recurrence.
+    pub fn apply_details(&self, event: &Event) -> Result<Event, ApiError> {
+        let mut updated = event.clone();
+        if let Some(title) = &self.title {
+            updated.title = title.clone();
+        }
+        if let Some(description) = &self.description {
+            updated.description = description.clone();
+        }
+        if let Some(start_time) = &self.start_time {
+            updated.start_time = parse_timestamp("start_time", start_time)?;
+        }
+        if let Some(end_time) = &self.end_time {
+            updated.end_time = parse_timestamp("end_time", end_time)?;
+        }
+        if let Some(lat) = self.lat {
+            updated.lat = lat;
+        }
+        if let Some(lon) = self.lon {
+            updated.lon = lon;
+        }
+        if let Some(address) = &self.address {
+            updated.address = address.clone();
+        }
+        check_time_range(updated.start_time, updated.end_time)?;
+        Ok(updated)
+    }
+
+    /// `event` with every field of the request applied. Exdates carry over
+    /// when only the rule changes.
+    pub fn apply(&self, event: &Event) -> Result<Event, ApiError> {
+        let mut updated = self.apply_details(event)?;
+        let exdates = match &self.exdates {
+            Some(exdates) => parse_exdates(exdates)?,
+            None => event
+                .recurrence
+                .as_ref()
+                .map(|r| r.exdates.clone())
+                .unwrap_or_default(),
+        };
+        updated.recurrence = match (&self.rrule, &event.recurrence) {
+            (Some(rrule), _) if rrule.trim().is_empty() => None,
+            (Some(rrule), _) => Some(Recurrence {
+                rrule: rrule.clone(),
+                exdates,
+            }),
+            (None, Some(recurrence)) => Some(Recurrence {
+                rrule: recurrence.rrule.clone(),
+                exdates,
+            }),
+            (None, None) => None,
+        };
+        check_recurrence(&mut updated)?;
+        Ok(updated)
+    }
+
+    pub fn cancelled(&self) -> Option<bool> {
+        self.cancelled
+    }
+
+    pub fn updated_at(&self) -> Option<i64> {
+        self.updated_at
+    }
+}
+
+/// An event sent with its `ETag`, for clients to echo in `If-Match`.
+#[derive(Responder)]
+pub struct TaggedEvent {
+    event: Json<Event>,
+    etag: Header<'static>,
+}
+
+impl From<Event> for TaggedEvent {
+    fn from(event: Event) -> Self {
+        TaggedEvent {
+            etag: Header::new("ETag", etag(event.updated_at)),
+            event: Json(event),
+        }
+    }
+}
+
+/// An event's row in `events_by_location`: enough to list it near a point.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventLocation {
+    #[serde(skip)]
+    pub location_bucket: String,
+    pub event_id: Uuid,
+    pub creator_id: Uuid,
+    pub title: String,
+    pub start_time: i64,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl From<&Event> for EventLocation {
+    fn from(event: &Event) -> Self {
+        EventLocation {
+            location_bucket: geo::bucket(event.lat, event.lon),
+            event_id: event.event_id,
+            creator_id: event.creator_id,
+            title: event.title.clone(),
+            start_time: event.start_time,
+            lat: event.lat,
+            lon: event.lon,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct DeleteEventRequest {
-    user_id: Uuid,
-    event: Event,
+/// An event found by `GET /events/nearby`, with its distance from the point.
+/// `occurrence_start` names the occurrence when the event repeats.
+#[derive(Debug, Serialize)]
+pub struct NearbyEvent {
+    #[serde(flatten)]
+    pub location: EventLocation,
+    pub distance_km: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occurrence_start: Option<i64>,
 }
 
+/// Search radius when the request names none.
+const DEFAULT_RADIUS_KM: f64 = 10.0;
+/// The widest search; it bounds how many buckets one request reads.
+const MAX_RADIUS_KM: f64 = 50.0;
+
+/// How nearby events are ordered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NearbyOrder {
+    Distance,
+    StartTime,
+}
+
+/// The query string of `GET /events/nearby`.
+#[derive(Debug, FromForm)]
+pub struct NearbyParams<'r> {
+    lat: f64,
+    lon: f64,
+    radius_km: Option<f64>,
+    from: Option<&'r str>,
+    to: Option<&'r str>,
+    sort: Option<&'r str>,
+    limit: Option<usize>,
+}
+
+/// A validated nearby search.
+#[derive(Debug, Clone)]
+pub struct NearbyQuery {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_km: f64,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub order: NearbyOrder,
+    pub limit: usize,
+}
+
+/// Events returned per page unless `limit` says otherwise.
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// The most events one page may hold.
+const MAX_PAGE_SIZE: usize = 100;
+
+/// A validated `[from, to)` range and page size, shared by the listings.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeWindow {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: usize,
+}
+
+/// Which events to list: one creator's, starting in `[from, to)`. Every
+/// backend returns them newest first, ties broken by ascending `event_id`,
+/// the clustering order of the Cassandra `events` table.
+#[derive(Debug, Clone)]
+pub struct EventQuery {
+    pub creator_id: Uuid,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: usize,
+}
+
+/// One page of a listing as the store returns it. `paging_state` is opaque to
+/// everyone but the store and fetches the next page of the same query.
+#[derive(Debug)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    pub paging_state: Option<Vec<u8>>,
+}
+
+/// An event as a listing shows it: a one-off event, or one occurrence of a
+/// recurring series with `occurrence_start` naming it and the occurrence's
+/// own times and details in place of the series'.
+#[derive(Debug, Serialize)]
+pub struct ListedEvent {
+    #[serde(flatten)]
+    pub event: Event,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occurrence_start: Option<i64>,
+}
+
+impl From<Event> for ListedEvent {
+    fn from(event: Event) -> Self {
+        ListedEvent {
+            event,
+            occurrence_start: None,
+        }
+    }
+}
+
+/// Where an item sits in a listing, and what a cursor continues after:
+/// newest first, then by event, then by occurrence.
+pub type ListingKey = (Reverse<i64>, Uuid, i64);
+
+pub fn listing_key(item: &ListedEvent) -> ListingKey {
+    let occurrence_start = item.occurrence_start.unwrap_or(item.event.start_time);
+    (
+        Reverse(item.event.start_time),
+        item.event.event_id,
+        occurrence_start,
+    )
+}
+
+/// How far past now, or past `from` when that is later, a listing without
+/// `to` expands recurring series; one that repeats forever has no newest
+/// occurrence to start from otherwise.
+const LISTING_HORIZON_MS: i64 = 366 * 24 * 60 * 60 * 1000;
+
+/// The body of a listing; `next_cursor` is absent on the last page.
+#[derive(Debug, Serialize)]
+pub struct EventList {
+    pub events: Vec<ListedEvent>,
+    pub next_cursor: Option<String>,
+}
+
+fn check_time_range(start_time: i64, end_time: i64) -> Result<(), ApiError> {
+    if end_time < start_time {
+        return Err(ApiError::validation(
+            "invalid_time_range",
+            "end_time must not be before start_time",
+        ));
+    }
+    Ok(())
+}
+
+fn parse_exdates(exdates: &[String]) -> Result<Vec<i64>, ApiError> {
+    exdates
+        .iter()
+        .map(|exdate| parse_timestamp("exdates", exdate))
+        .collect()
+}
+
+/// Checks that a recurring event's rule is supported and starts on
+/// `start_time`, writing it back in canonical form and dropping exdates that
+/// are not occurrences of it.
+pub fn check_recurrence(event: &mut Event) -> Result<(), ApiError> {
+    let start_time = event.start_time;
+    let Some(recurrence) = event.recurrence.as_mut() else {
+        return Ok(());
+    };
+    let invalid = |detail: String| ApiError::validation("invalid_rrule", detail);
+    let rule = Rule::parse(&recurrence.rrule).map_err(invalid)?;
+    if rule.starts(start_time).next() != Some(start_time) {
+        return Err(invalid(
+            "start_time must be the first occurrence of the rule".to_string(),
+        ));
+    }
+    recurrence.rrule = rule.to_string();
+    recurrence.exdates.sort_unstable();
+    recurrence.exdates.dedup();
+    let last = recurrence.exdates.last().copied().unwrap_or(start_time);
+    let starts: Vec<i64> = rule.starts(start_time).take_while(|&s| s <= last).collect();
+    recurrence
+        .exdates
+        .retain(|exdate| starts.binary_search(exdate).is_ok());
+    Ok(())
+}
+
+/// Fails unless the client read `current` at its stored version, named by
+/// `If-Match` or else by the `updated_at` in the body.
+pub fn check_version(
+    if_match: &IfMatch,
+    updated_at: Option<i64>,
+    current: &Event,
+) -> Result<(), ApiError> {
+    let fresh = match (if_match.matches(current.updated_at), updated_at) {
+        (Some(fresh), _) => fresh,
+        (None, Some(updated_at)) => updated_at == current.updated_at,
+        (None, None) => {
+            return Err(ApiError::precondition_required(
+                "version_required",
+                "Send If-Match or updated_at with the version being changed",
+            ))
+        }
+    };
+    if !fresh {
+        return Err(event_modified());
+    }
+    Ok(())
+}
+
+/// The version to store after `current`; it must move even if two writes
+/// land in the same millisecond.
+pub fn next_version(current: &Event) -> i64 {
+    Utc::now().timestamp_millis().max(current.updated_at + 1)
+}
+
+pub fn event_modified() -> ApiError {
+    ApiError::precondition_failed(
+        "event_modified",
+        "The event was changed by someone else; reload it and try again",
+    )
+}
+
+/// Creates an event owned by the caller.
 #[post("/events", data = "<event>")]
-pub async fn frontend_create_event(event: Json<CreateEventRequest>) -> Result<Json<Event>, Status> {
+pub async fn frontend_create_event(
+    events: &State<Events>,
+    config: &State<Config>,
+    auth: AuthToken,
+    event: Json<CreateEventRequest>,
+) -> Result<Json<Event>, ApiError> {
     println!("Creating event: {:?}", event);
+    authorize(
+        &auth,
+        Action::CreateEvent {
+            require_verified: config.auth.require_verified_email_for_events,
+        },
+    )?;
 
-    let start_time = DateTime::parse_from_rfc3339(&event.start_time).unwrap();
-    let end_time = DateTime::parse_from_rfc3339(&event.end_time).unwrap();
+    let start_time = parse_timestamp("start_time", &event.start_time)?;
+    let end_time = parse_timestamp("end_time", &event.end_time)?;
+    check_time_range(start_time, end_time)?;
+    let recurrence = match event.rrule.as_deref().map(str::trim) {
+        Some(rrule) if !rrule.is_empty() => Some(Recurrence {
+            rrule: rrule.to_string(),
+            exdates: parse_exdates(&event.exdates)?,
+        }),
+        _ => None,
+    };
 
-    let new_event = Event {
+    let mut new_event = Event {
         event_id: Uuid::new_v4(),
-        creator_id: event.creator_id.clone(),
+        creator_id: auth.user_id,
         title: event.title.clone(),
         description: event.description.clone(),
-        start_time: start_time.timestamp_millis(),
-        end_time: end_time.timestamp_millis(),
+        start_time,
+        end_time,
         lat: event.lat,
         lon: event.lon,
         address: event.address.clone(),
         created_at: Utc::now().timestamp_millis(),
         updated_at: Utc::now().timestamp_millis(),
+        recurrence,
     };
+    check_recurrence(&mut new_event)?;
 
-    match create_event(&new_event).await {
-        Ok(_) => Ok(Json(new_event)),
-        Err(_) => Err(Status::InternalServerError),
-    }
-}
-
-async fn create_event(event: &Event) -> Result<Json<Event>, Status> {
-    println!("Creating new event wanted event_id: {:?}", event.event_id);
-    println!(
-        "Creating new event wanted creator_id: {:?}",
-        event.creator_id
-    );
-    println!(
-        "Creating new event wanted start_time: {:?}",
-        event.start_time
-    );
-    let new_event_id = Uuid::new_v4();
-    println!("but got: {:?}", new_event_id);
-    let mut cluster = init_cluster()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    let session = cluster
-        .connect()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    let db_name = "openmeet.events";
-    let insert_event_query = format!("INSERT INTO {} (event_id, creator_id, title, description, start_time, end_time, lat, lon, address, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", db_name);
-
-    let mut statement = session.statement(&insert_event_query);
-    statement
-        .bind(0, new_event_id)
-        .map_err(|_| Status::InternalServerError)?;
-    // we should look this up from token or something else
-    statement
-        .bind(1, event.creator_id)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(2, event.title.as_str())
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(3, event.description.as_str())
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(4, event.start_time)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(5, event.end_time)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(6, event.lat)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(7, event.lon)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(8, event.address.as_str())
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(9, event.created_at)
-        .map_err(|_| Status::InternalServerError)?;
-    statement
-        .bind(10, event.updated_at)
-        .map_err(|_| Status::InternalServerError)?;
-
-    if let Err(e) = statement.execute().await {
-        println!("Failed to execute statement: {:?}", e);
-        return Err(Status::InternalServerError);
-    }
+    create_event(events, &new_event).await
+}
 
+async fn create_event(events: &Events, event: &Event) -> Result<Json<Event>, ApiError> {
     let mut new_event = event.clone();
-    new_event.event_id = new_event_id;
+    new_event.event_id = Uuid::new_v4();
+
+    events.insert_event(&new_event).await?;
+
     Ok(Json(new_event))
 }
 
-// #[get("/events")]
-// pub async fn get_events(db: &State<DbConn>) -> Result<Json<Vec<Event>>, Status> {
-//     match db.get_events().await {
-//         Ok(events) => Ok(Json(events)),
-//         Err(_) => Err(Status::InternalServerError),
-//     }
-// }
-
+/// Any event by id; public, so links to an event can be shared.
 #[get("/events/<event_id>")]
-pub async fn frontend_get_event(event_id: &str) -> Result<Json<Event>, Status> {
-    let event_id = Uuid::parse_str(event_id).map_err(|_| Status::BadRequest)?;
-    match get_event(event_id, Uuid::new_v4(), Utc::now().timestamp_millis()).await {
-        Ok(Some(event)) => Ok(Json(event)),
-        Ok(None) => Err(Status::NotFound),
-        Err(_) => Err(Status::InternalServerError),
+pub async fn frontend_get_event(
+    events: &State<Events>,
+    event_id: &str,
+) -> Result<TaggedEvent, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    Ok(TaggedEvent::from(find_event(events, event_id).await?))
+}
+
+pub async fn find_event(events: &Events, event_id: Uuid) -> Result<Event, ApiError> {
+    events
+        .get_event_by_id(event_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("event_not_found", "Event not found"))
+}
+
+pub fn time_window(
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<usize>,
+) -> Result<TimeWindow, ApiError> {
+    let from = from.map(|from| parse_timestamp("from", from)).transpose()?;
+    let to = to.map(|to| parse_timestamp("to", to)).transpose()?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if to < from {
+            return Err(ApiError::validation(
+                "invalid_time_range",
+                "to must not be before from",
+            ));
+        }
     }
+    Ok(TimeWindow {
+        from,
+        to,
+        limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    })
 }
 
-async fn get_events_by_creator_id(creator_id: Uuid) -> Result<Json<Vec<Event>>, Status> {
-    let mut events = Vec::new();
-    let mut cluster = init_cluster()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    let session = cluster
-        .connect()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
+pub fn event_query(
+    creator_id: Uuid,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<usize>,
+) -> Result<EventQuery, ApiError> {
+    let window = time_window(from, to, limit)?;
+    Ok(EventQuery {
+        creator_id,
+        from: window.from,
+        to: window.to,
+        limit: window.limit,
+    })
+}
 
-    let db_name = "openmeet.events";
-    let select_events_query = format!("SELECT event_id, creator_id, title, description, start_time, end_time, lat, lon, address, created_at, updated_at FROM {} WHERE creator_id = ?;", db_name);
+/// Signs a cursor to the query it continues, so a client can neither forge
+/// a position nor carry it over to another query.
+fn cursor_signature(config: &Config, query: &EventQuery, state: &str) -> String {
+    let creator = query.creator_id.to_string();
+    let from = query.from.map(|t| t.to_string()).unwrap_or_default();
+    let to = query.to.map(|t| t.to_string()).unwrap_or_default();
+    sign(config, &["events-cursor", &creator, &from, &to, state])
+}
 
-    let mut statement = session.statement(&select_events_query);
-    statement
-        .bind(0, creator_id)
-        .map_err(|_| Status::InternalServerError)?;
+fn encode_cursor(config: &Config, query: &EventQuery, after: &ListingKey) -> String {
+    let (Reverse(start_time), event_id, occurrence_start) = after;
+    let state = hex(format!("{}/{}/{}", start_time, event_id, occurrence_start).as_bytes());
+    let mac = cursor_signature(config, query, &state);
+    format!("{}.{}", state, mac)
+}
 
-    let result = statement
-        .execute()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    let mut iter = result.iter();
-    while let Some(row) = iter.next() {
-        let event_id = match row.get_column_by_name("event_id") {
-            Ok(col) => match col.get_uuid() {
-                Ok(uuid) => {
-                    println!("event_id: {:?}", uuid);
-
-                    uuid
-                }
-                Err(e) => {
-                    eprintln!("Failed to parse event_id as UUID: {:?}", e);
-                    return Err(Status::InternalServerError);
-                }
-            },
-            Err(e) => {
-                eprintln!("Failed to get event_id as string: {:?}", e);
-                return Err(Status::InternalServerError);
-            }
-        };
+fn decode_cursor(
+    config: &Config,
+    query: &EventQuery,
+    cursor: &str,
+) -> Result<ListingKey, ApiError> {
+    let invalid =
+        || ApiError::validation("invalid_cursor", "The cursor does not belong to this query");
+    let (state, mac) = cursor.split_once('.').ok_or_else(invalid)?;
+    if !constant_time_eq(mac, &cursor_signature(config, query, state)) {
+        return Err(invalid());
+    }
+    let state = unhex(state)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(invalid)?;
+    let mut parts = state.split('/');
+    let mut next = || parts.next().ok_or_else(invalid);
+    let start_time = next()?.parse().map_err(|_| invalid())?;
+    let event_id = next()?.parse().map_err(|_| invalid())?;
+    let occurrence_start = next()?.parse().map_err(|_| invalid())?;
+    Ok((Reverse(start_time), event_id, occurrence_start))
+}
 
-        let creator_id = match row.get_column_by_name("creator_id") {
-            Ok(col) => match col.get_uuid() {
-                Ok(uuid) => uuid,
-                Err(e) => {
-                    eprintln!("Failed to parse creator_id as UUID: {:?}", e);
-                    return Err(Status::InternalServerError);
-                }
-            },
-            Err(e) => {
-                eprintln!("Failed to get creator_id: {:?}", e);
-                return Err(Status::InternalServerError);
-            }
-        };
+/// One page of the events matching `query`, continuing after `cursor`.
+/// Recurring series are expanded into their occurrences in the window,
+/// including series that started before it, down from the cursor and no
+/// further than a page of each; the one-off events are read from the store a
+/// page at a time and the two merged in listing order.
+pub async fn list_events(
+    events: &Events,
+    config: &Config,
+    query: &EventQuery,
+    cursor: Option<&str>,
+) -> Result<EventList, ApiError> {
+    let after = cursor
+        .map(|cursor| decode_cursor(config, query, cursor))
+        .transpose()?;
+    let is_next = |item: &ListedEvent| after.iter().all(|after| listing_key(item) > *after);
+    // nothing after the cursor starts later than the item it points at
+    let to = match after {
+        Some((Reverse(start_time), _, _)) => Some(query.to.unwrap_or(i64::MAX).min(start_time + 1)),
+        None => query.to,
+    };
 
-        events.push(Event {
-            event_id: event_id.into(),
-            creator_id: creator_id.into(),
-            title: row
-                .get_column_by_name("title")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            description: row
-                .get_column_by_name("description")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            start_time: row
-                .get_column_by_name("start_time")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            end_time: row
-                .get_column_by_name("end_time")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            lat: row.get_column_by_name("lat").unwrap().get_f64().unwrap(),
-            lon: row.get_column_by_name("lon").unwrap().get_f64().unwrap(),
-            address: row
-                .get_column_by_name("address")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            created_at: row
-                .get_column_by_name("created_at")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            updated_at: row
-                .get_column_by_name("updated_at")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-        });
-    }
-
-    Ok(Json(events))
-}
-
-async fn get_event(
-    event_id: Uuid,
-    creator_id: Uuid,
-    start_time: i64,
-) -> Result<Option<Event>, Status> {
-    let mut cluster = init_cluster()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    let session = cluster
-        .connect()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
+    let window = EventQuery {
+        to,
+        ..query.clone()
+    };
+    let mut listed = Vec::new();
+    let mut paging_state: Option<Vec<u8>> = None;
+    loop {
+        let page = events.list_events(&window, paging_state.as_deref()).await?;
+        listed.extend(
+            page.events
+                .into_iter()
+                .filter(|e| e.recurrence.is_none())
+                .map(ListedEvent::from)
+                .filter(&is_next),
+        );
+        paging_state = page.paging_state;
+        // one more than a page shows whether another follows
+        if paging_state.is_none() || listed.len() > query.limit {
+            break;
+        }
+    }
 
-    let db_name = "openmeet.events";
-    let select_event_query = format!("SELECT event_id, creator_id, title, description, start_time, end_time, lat, lon, address, created_at, updated_at FROM {} WHERE event_id = ? AND creator_id = ? AND start_time = ?;", db_name);
+    let from = query.from.unwrap_or(i64::MIN);
+    let horizon = from.max(Utc::now().timestamp_millis()) + LISTING_HORIZON_MS;
+    let until = to.unwrap_or(horizon);
+    let series = events
+        .recurring_events(query.creator_id, Some(until))
+        .await?;
+    let occurrences =
+        newest_occurrences(events, &series, from, until, query.limit + 1, &is_next).await?;
+    listed.extend(occurrences);
 
-    let mut statement = session.statement(&select_event_query);
-    statement.bind(0, event_id).map_err(|e| {
-        eprintln!("Failed to bind event_id: {:?}", e);
-        Status::InternalServerError
-    })?;
-    statement.bind(1, creator_id).map_err(|e| {
-        eprintln!("Failed to bind creator_id: {:?}", e);
-        Status::InternalServerError
-    })?;
-    statement.bind(2, start_time).map_err(|e| {
-        eprintln!("Failed to bind start_time: {:?}", e);
-        Status::InternalServerError
-    })?;
-    let result = statement.execute().await.map_err(|e| {
-        eprintln!("Failed to execute select statement: {:?}", e);
-        Status::InternalServerError
+    listed.sort_by_key(listing_key);
+    let more = listed.len() > query.limit;
+    listed.truncate(query.limit);
+    let next_cursor = match listed.last() {
+        Some(last) if more => Some(encode_cursor(config, query, &listing_key(last))),
+        _ => None,
+    };
+    Ok(EventList {
+        events: listed,
+        next_cursor,
+    })
+}
+
+/// Lists the events of one `creator`, optionally starting in `[from, to)`,
+/// a recurring one once per occurrence. The creator is required: listing
+/// everyone's events would scan every partition of the table.
+#[get("/events?<creator>&<from>&<to>&<limit>&<cursor>")]
+pub async fn frontend_list_events(
+    events: &State<Events>,
+    config: &State<Config>,
+    creator: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<usize>,
+    cursor: Option<&str>,
+) -> Result<Json<EventList>, ApiError> {
+    let creator_id = creator.map(parse_uuid).transpose()?.ok_or_else(|| {
+        ApiError::validation("creator_required", "Name the creator whose events to list")
     })?;
+    let query = event_query(creator_id, from, to, limit)?;
+    Ok(Json(list_events(events, config, &query, cursor).await?))
+}
 
-    let mut events = Vec::new();
-    let mut iter = result.iter();
-    while let Some(row) = iter.next() {
-        let event_id = match row.get_column_by_name("event_id") {
-            Ok(col) => match col.get_uuid() {
-                Ok(uuid) => uuid,
-                Err(e) => {
-                    eprintln!("Failed to parse event_id as UUID: {:?}", e);
-                    return Err(Status::InternalServerError);
-                }
-            },
-            Err(e) => {
-                eprintln!("Failed to get event_id: {:?}", e);
-                return Err(Status::InternalServerError);
-            }
-        };
+/// Lists one user's events, newest first, optionally starting in `[from, to)`.
+/// Recurring events are listed once per occurrence.
+#[get("/users/<user_id>/events?<from>&<to>&<limit>&<cursor>")]
+pub async fn frontend_user_events(
+    events: &State<Events>,
+    config: &State<Config>,
+    user_id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<usize>,
+    cursor: Option<&str>,
+) -> Result<Json<EventList>, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    let query = event_query(user_id, from, to, limit)?;
+    Ok(Json(list_events(events, config, &query, cursor).await?))
+}
 
-        let creator_id = match row.get_column_by_name("creator_id") {
-            Ok(col) => match col.get_uuid() {
-                Ok(uuid) => uuid,
-                Err(e) => {
-                    eprintln!("Failed to parse creator_id as UUID: {:?}", e);
-                    return Err(Status::InternalServerError);
-                }
-            },
-            Err(e) => {
-                eprintln!("Failed to get creator_id: {:?}", e);
-                return Err(Status::InternalServerError);
-            }
-        };
+fn nearby_query(params: &NearbyParams<'_>, now: i64) -> Result<NearbyQuery, ApiError> {
+    if !(-90.0..=90.0).contains(&params.lat) || !(-180.0..=180.0).contains(&params.lon) {
+        return Err(ApiError::validation(
+            "invalid_coordinates",
+            "lat must be within [-90, 90] and lon within [-180, 180]",
+        ));
+    }
+    let radius_km = params.radius_km.unwrap_or(DEFAULT_RADIUS_KM);
+    if !(radius_km > 0.0 && radius_km <= MAX_RADIUS_KM) {
+        return Err(ApiError::validation(
+            "invalid_radius",
+            format!("radius_km must be above 0 and at most {}", MAX_RADIUS_KM),
+        ));
+    }
+    let order = match params.sort {
+        None | Some("distance") => NearbyOrder::Distance,
+        Some("start_time") => NearbyOrder::StartTime,
+        Some(_) => {
+            return Err(ApiError::validation(
+                "invalid_sort",
+                "sort must be distance or start_time",
+            ))
+        }
+    };
+    let window = time_window(params.from, params.to, params.limit)?;
+    Ok(NearbyQuery {
+        lat: params.lat,
+        lon: params.lon,
+        radius_km,
+        from: window.from.or(Some(now)),
+        to: window.to,
+        order,
+        limit: window.limit,
+    })
+}
 
-        let event = Event {
-            event_id: event_id.into(),
-            creator_id: creator_id.into(),
-            title: row
-                .get_column_by_name("title")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            description: row
-                .get_column_by_name("description")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            start_time: row
-                .get_column_by_name("start_time")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            end_time: row
-                .get_column_by_name("end_time")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            lat: row.get_column_by_name("lat").unwrap().get_f64().unwrap(),
-            lon: row.get_column_by_name("lon").unwrap().get_f64().unwrap(),
-            address: row
-                .get_column_by_name("address")
-                .unwrap()
-                .get_string()
-                .unwrap(),
-            created_at: row
-                .get_column_by_name("created_at")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-            updated_at: row
-                .get_column_by_name("updated_at")
-                .unwrap()
-                .get_i64()
-                .unwrap(),
-        };
-        events.push(event); // Push each event into the vector
+/// The events within the query's radius, read from every bucket the circle
+/// reaches at once and then cut down to the circle itself. Recurring series
+/// in those buckets count with each occurrence in the window.
+pub async fn nearby_events(
+    events: &Events,
+    query: &NearbyQuery,
+) -> Result<Vec<NearbyEvent>, ApiError> {
+    let buckets = geo::buckets_within(query.lat, query.lon, query.radius_km);
+    let reads = buckets
+        .iter()
+        .map(|bucket| events.events_in_bucket(bucket, query.from, query.to));
+    let series_reads = buckets
+        .iter()
+        .map(|bucket| events.recurring_in_bucket(bucket, query.to));
+    let (locations, series) = join(join_all(reads), join_all(series_reads)).await;
+    let mut recurring = Vec::new();
+    for found in series {
+        recurring.extend(found?);
     }
-    Ok(events.get(0).cloned())
+    // a series' own location row is just its first occurrence
+    let series_ids: HashSet<Uuid> = recurring.iter().map(|e| e.event_id).collect();
+
+    let mut found = Vec::new();
+    let mut keep = |location: EventLocation, occurrence_start: Option<i64>| {
+        let distance_km = geo::haversine_km(query.lat, query.lon, location.lat, location.lon);
+        if distance_km <= query.radius_km {
+            found.push(NearbyEvent {
+                location,
+                distance_km,
+                occurrence_start,
+            });
+        }
+    };
+    for locations in locations {
+        for location in locations? {
+            if !series_ids.contains(&location.event_id) {
+                keep(location, None);
+            }
+        }
+    }
+    let from = query.from.unwrap_or(i64::MIN);
+    for occurrence in occurrences_in(events, &recurring, from, query.to, query.limit).await? {
+        keep(
+            EventLocation::from(&occurrence.event),
+            occurrence.occurrence_start,
+        );
+    }
+    match query.order {
+        NearbyOrder::Distance => found.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km)),
+        NearbyOrder::StartTime => found.sort_by_key(|e| e.location.start_time),
+    }
+    found.truncate(query.limit);
+    Ok(found)
+}
+
+/// Events within `radius_km` of `lat`/`lon` starting in `[from, to)`. `from`
+/// defaults to now, so only upcoming events show, and a recurring event
+/// shows once per occurrence. Closest first unless `sort=start_time` asks
+/// for the soonest.
+#[get("/events/nearby?<params..>")]
+pub async fn frontend_nearby_events(
+    events: &State<Events>,
+    params: NearbyParams<'_>,
+) -> Result<Json<Vec<NearbyEvent>>, ApiError> {
+    let query = nearby_query(&params, Utc::now().timestamp_millis())?;
+    Ok(Json(nearby_events(events, &query).await?))
+}
+
+/// Loads an event the caller may change: their own, or any if they are an admin.
+pub async fn get_owned_event(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+) -> Result<Event, ApiError> {
+    let event = find_event(events, event_id).await?;
+    authorize(
+        auth,
+        Action::ModifyEvent {
+            creator_id: event.creator_id,
+        },
+    )?;
+    Ok(event)
 }
 
-#[delete("/events/<event_id>", data = "<delete_event_request>")]
+/// Deletes an event, or with `scope=this` or `scope=following` one
+/// occurrence of a recurring event or the rest of the series from it.
+#[delete("/events/<event_id>?<scope>&<occurrence>")]
 pub async fn frontend_delete_event(
-    _auth: AuthToken,
+    events: &State<Events>,
+    auth: AuthToken,
     event_id: &str,
-    delete_event_request: Json<DeleteEventRequest>,
-) -> Status {
+    scope: Option<&str>,
+    occurrence: Option<&str>,
+) -> Result<Status, ApiError> {
     println!("Deleting event: {:?}", event_id);
 
-    let event_id = Uuid::parse_str(event_id).map_err(|_| Status::BadRequest);
-    let user_id = delete_event_request.user_id;
-    let event = delete_event_request.event.clone();
-
-    if event.creator_id != user_id {
-        return Status::Forbidden;
-    }
-    let event_id = event_id.unwrap();
-    match delete_event(&event_id, &user_id, &event.start_time).await {
-        Ok(_) => Status::NoContent,
-        Err(_) => Status::InternalServerError,
-    }
+    let event_id = parse_uuid(event_id)?;
+    let scope = edit_scope(scope, occurrence)?;
+    delete_in_scope(events, &auth, event_id, scope).await?;
+    Ok(Status::NoContent)
 }
 
-pub async fn delete_event(event_id: &Uuid, user_id: &Uuid, start_date: &i64) -> Result<(), Status> {
-    println!("Deleting event: {:?}", event_id);
+pub async fn delete_event(
+    events: &Events,
+    event_id: &Uuid,
+    user_id: &Uuid,
+    start_date: &i64,
+) -> Result<(), ApiError> {
+    Ok(events
+        .delete_event(*event_id, *user_id, *start_date)
+        .await?)
+}
 
-    let mut cluster = init_cluster()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    let session = cluster
-        .connect()
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    let delete_query =
-        "DELETE FROM openmeet.events WHERE event_id = ? and start_time = ? and creator_id = ?";
-    let mut statement = session.statement(delete_query);
-    statement.bind(0, *event_id).map_err(|e| {
-        eprintln!("Failed to bind event_id: {:?}", e);
-        Status::InternalServerError
-    })?;
-    statement.bind(1, *start_date).map_err(|e| {
-        eprintln!("Failed to bind start_time: {:?}", e);
-        Status::InternalServerError
-    })?;
-    statement.bind(2, *user_id).map_err(|e| {
-        eprintln!("Failed to bind creator_id: {:?}", e);
-        Status::InternalServerError
-    })?;
+/// Applies `changes` to an event the caller may change, keeping its id and
+/// `created_at`. The write only happens if the event is still at the version
+/// the client read, named by `If-Match` or else `changes.updated_at`. For a
+/// recurring event this changes the whole series; overrides of occurrences
+/// the new schedule no longer has are dropped.
+pub async fn update_event(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    if_match: &IfMatch,
+    changes: PatchEventRequest,
+) -> Result<Event, ApiError> {
+    let current = get_owned_event(events, auth, event_id).await?;
+    check_version(if_match, changes.updated_at, &current)?;
+    if changes.cancelled.is_some() {
+        return Err(ApiError::validation(
+            "invalid_scope",
+            "cancelled applies to one occurrence; send scope=this and occurrence",
+        ));
+    }
 
-    let result = match statement.execute().await {
-        Ok(result) => result,
-        Err(e) => {
-            eprintln!("Failed to execute delete statement: {:?}", e);
-            return Err(Status::InternalServerError);
-        }
-    };
+    let mut updated = changes.apply(&current)?;
+    updated.updated_at = next_version(&current);
 
-    if result.row_count() == 0 {
-        eprintln!(
-            "No event found with event_id: {:?}, start_time: {:?}, and creator_id: {:?}",
-            event_id, start_date, user_id
-        );
-        return Err(Status::NotFound);
+    if !events.update_event(&current, &updated).await? {
+        return Err(event_modified());
+    }
+    if current.recurrence.is_some() {
+        prune_overrides(events, &updated).await?;
     }
+    Ok(updated)
+}
 
-    Ok(())
+/// Replaces every field of an event, or with `scope` of one occurrence or of
+/// the rest of the series from it.
+#[put("/events/<event_id>?<scope>&<occurrence>", data = "<event>")]
+pub async fn frontend_update_event(
+    events: &State<Events>,
+    auth: AuthToken,
+    if_match: IfMatch,
+    event_id: &str,
+    scope: Option<&str>,
+    occurrence: Option<&str>,
+    event: Json<UpdateEventRequest>,
+) -> Result<TaggedEvent, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    let scope = edit_scope(scope, occurrence)?;
+    let changes = PatchEventRequest::from(event.into_inner());
+    let updated = update_in_scope(events, &auth, event_id, &if_match, scope, changes).await?;
+    Ok(TaggedEvent::from(updated))
+}
+
+/// Changes only the fields present in the body, with the same scopes as PUT.
+#[patch("/events/<event_id>?<scope>&<occurrence>", data = "<changes>")]
+pub async fn frontend_patch_event(
+    events: &State<Events>,
+    auth: AuthToken,
+    if_match: IfMatch,
+    event_id: &str,
+    scope: Option<&str>,
+    occurrence: Option<&str>,
+    changes: Json<PatchEventRequest>,
+) -> Result<TaggedEvent, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    let scope = edit_scope(scope, occurrence)?;
+    let changes = changes.into_inner();
+    let updated = update_in_scope(events, &auth, event_id, &if_match, scope, changes).await?;
+    Ok(TaggedEvent::from(updated))
 }
-// #[put("/events/<event_id>", data = "<event>")]
-// pub async fn update_event(event_id: Uuid, event: Json<CreateEventRequest>, db: &State<DbConn>, user_id: Uuid) -> Result<Json<Event>, Status> {
-//     let updated_event = Event {
-//         event_id,
-//         creator_id: user_id,
-//         title: event.title.clone(),
-//         description: event.description.clone(),
-//         start_time: event.start_time,
-//         end_time: event.end_time,
-//         lat: event.lat,
-//         lon: event.lon,
-//         address: event.address.clone(),
-//         created_at: Utc::now(), // You might want to keep the original created_at
-//         updated_at: Utc::now(),
-//     };
-
-//     match db.update_event(&updated_event).await {
-//         Ok(_) => Ok(Json(updated_event)),
-//         Err(_) => Err(Status::InternalServerError),
-//     }
-// }
 
 // #[delete("/events/<event_id>")]
 // pub async fn delete_event(event_id: Uuid, db: &State<DbConn>, user_id: Uuid) -> Status {
@@ -475,14 +894,18 @@ pub async fn delete_event(event_id: &Uuid, user_id: &Uuid, start_date: &i64) ->
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::{TimeZone, Utc};
+    use crate::store::MemoryStore;
+    use crate::users::Role;
+    use chrono::Utc;
+    use std::sync::Arc;
 
     #[tokio::test]
-    async fn test_get_events_by_creator_id_success() {
+    async fn test_list_events_of_a_creator_success() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         let creator_id = Uuid::parse_str("115c9dbd-ccfb-43cd-8341-0f242144c98f").unwrap();
 
         // create 3 events by same creator
-        let mut preload_events: Vec<Event> = Vec::new();
         for i in 0..3 {
             let event = Event {
                 event_id: Uuid::new_v4(),
@@ -496,16 +919,15 @@ mod tests {
                 address: format!("New York, NY {}", i + 1),
                 created_at: Utc::now().timestamp_millis(),
                 updated_at: Utc::now().timestamp_millis(),
+                recurrence: None,
             };
-            create_event(&event).await.unwrap();
+            create_event(&events, &event).await.unwrap();
         }
 
-        let events = match get_events_by_creator_id(creator_id).await {
-            Ok(events) => {
-                println!("events len({:?}): {:?}", events.len(), events);
-                let events = events.into_inner();
-                assert!(events.len() >= 3);
-                events
+        let query = event_query(creator_id, None, None, None).unwrap();
+        match list_events(&events, &config, &query, None).await {
+            Ok(list) => {
+                assert_eq!(list.events.len(), 3);
             }
             Err(e) => {
                 panic!("Failed to get events: {:?}", e);
@@ -515,26 +937,41 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_event_success() {
+        let events: Events = Arc::new(MemoryStore::default());
         let creator_id = Uuid::parse_str("115c9dbd-ccfb-43cd-8341-0f242144c98f").unwrap();
         let start_time: i64 = 1725385197884;
 
-        // look up the event from the test_create_event_success test and delete if it exists
-        let events = match get_events_by_creator_id(creator_id).await {
-            Ok(events) => {
-                let events = events.into_inner();
-                let event = events.get(0).unwrap();
-                let delete_result = delete_event(&event.event_id, &creator_id, &start_time).await;
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id,
+            title: "Test Event".to_string(),
+            description: "This is a test event".to_string(),
+            start_time,
+            end_time: start_time + 3600000,
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            created_at: Utc::now().timestamp_millis(),
+            updated_at: Utc::now().timestamp_millis(),
+            recurrence: None,
+        };
+        let created = create_event(&events, &event).await.unwrap().into_inner();
 
+        // Act: delete the event we just created
+        let delete_result =
+            delete_event(&events, &created.event_id, &creator_id, &start_time).await;
+        assert!(delete_result.is_ok());
 
-            }
-            Err(e) => {
-                panic!("Failed to get events: {:?}", e);
-            }
-        };
+        // Assert: it is gone
+        let result = events
+            .get_event(created.event_id, creator_id, start_time)
+            .await;
+        assert!(result.unwrap().is_none());
     }
 
     #[tokio::test]
     async fn test_create_event_success() {
+        let events: Events = Arc::new(MemoryStore::default());
         // Setup: create an event
         let event = Event {
             event_id: Uuid::parse_str("38408cb9-9c13-4ca7-ad78-c322bb2a38a9").unwrap(),
@@ -548,10 +985,11 @@ mod tests {
             address: "New York, NY".to_string(),
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
+            recurrence: None,
         };
 
         // Act: create the event
-        let create_result = create_event(&event).await;
+        let create_result = create_event(&events, &event).await;
         if create_result.is_err() {
             panic!("Failed to create test event: {:?}", create_result.err());
         }
@@ -560,6 +998,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_event_success() {
+        let events: Events = Arc::new(MemoryStore::default());
         // Setup: create an event and insert it into the database
         let event = Event {
             event_id: Uuid::new_v4(),
@@ -573,10 +1012,11 @@ mod tests {
             address: "New York, NY".to_string(),
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
+            recurrence: None,
         };
 
         // Create the event
-        let create_result = create_event(&event).await;
+        let create_result = create_event(&events, &event).await;
 
         let create_result = match create_result {
             Ok(event) => event,
@@ -586,12 +1026,13 @@ mod tests {
         };
 
         // Act: get the event by id
-        let result = get_event(
-            create_result.into_inner().event_id,
-            event.creator_id,
-            event.start_time,
-        )
-        .await;
+        let result = events
+            .get_event(
+                create_result.into_inner().event_id,
+                event.creator_id,
+                event.start_time,
+            )
+            .await;
 
         match result {
             Ok(Some(retrieved_event)) => {
@@ -609,14 +1050,797 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_event_not_found() {
-        // Act: attempt to get a non-existent event
-        let result = get_event(
-            Uuid::new_v4(),
-            Uuid::new_v4(),
-            Utc::now().timestamp_millis(),
+    async fn test_create_event_rejects_bad_timestamp() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let request = CreateEventRequest {
+            title: "Test Event".to_string(),
+            description: "This is a test event".to_string(),
+            start_time: "tomorrow at noon".to_string(),
+            end_time: "2024-09-03T18:39:57Z".to_string(),
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            rrule: None,
+            exdates: vec![],
+        };
+        let auth = AuthToken {
+            user_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            role: Role::Member,
+            email_verified: true,
+        };
+
+        let config = Config::for_tests();
+        let result = frontend_create_event(
+            rocket::State::from(&events),
+            rocket::State::from(&config),
+            auth,
+            Json(request),
+        )
+        .await;
+
+        match result {
+            Err(e) => assert_eq!(e.code(), "invalid_timestamp"),
+            Ok(_) => panic!("Unparseable start_time should be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_event_uses_caller_as_creator() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let caller = Uuid::new_v4();
+        let request = CreateEventRequest {
+            title: "Test Event".to_string(),
+            description: "This is a test event".to_string(),
+            start_time: "2024-09-03T17:39:57Z".to_string(),
+            end_time: "2024-09-03T18:39:57Z".to_string(),
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            rrule: None,
+            exdates: vec![],
+        };
+
+        let config = Config::for_tests();
+        let created = frontend_create_event(
+            rocket::State::from(&events),
+            rocket::State::from(&config),
+            AuthToken {
+                user_id: caller,
+                session_id: Uuid::new_v4(),
+                role: Role::Member,
+                email_verified: true,
+            },
+            Json(request),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+
+        assert_eq!(created.creator_id, caller);
+    }
+
+    #[tokio::test]
+    async fn test_create_recurring_event_checks_the_rule() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        // 2024-09-03 is a Tuesday
+        let request = |rrule: &str, exdates: &[&str]| CreateEventRequest {
+            title: "Weekly meetup".to_string(),
+            description: "Every Tuesday".to_string(),
+            start_time: "2024-09-03T17:00:00Z".to_string(),
+            end_time: "2024-09-03T18:00:00Z".to_string(),
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            rrule: Some(rrule.to_string()),
+            exdates: exdates.iter().map(|e| e.to_string()).collect(),
+        };
+
+        let created = frontend_create_event(
+            rocket::State::from(&events),
+            rocket::State::from(&config),
+            member(Uuid::new_v4()),
+            Json(request(
+                "freq=weekly;byday=TU;count=4",
+                &["2024-09-10T17:00:00Z", "2024-09-11T17:00:00Z"],
+            )),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        let recurrence = created.recurrence.unwrap();
+        assert_eq!(recurrence.rrule, "FREQ=WEEKLY;COUNT=4;BYDAY=TU");
+        // the Wednesday is not an occurrence
+        assert_eq!(recurrence.exdates, vec![1725987600000]);
+
+        for rrule in ["FREQ=WEEKLY;BYDAY=WE", "FREQ=SECONDLY"] {
+            let result = frontend_create_event(
+                rocket::State::from(&events),
+                rocket::State::from(&config),
+                member(Uuid::new_v4()),
+                Json(request(rrule, &[])),
+            )
+            .await;
+            assert_eq!(result.unwrap_err().code(), "invalid_rrule", "{}", rrule);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_event_of_another_user_is_refused() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id: owner,
+            title: "Test Event".to_string(),
+            description: "This is a test event".to_string(),
+            start_time: 1725385197884,
+            end_time: 1725385197884 + 3600000,
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            created_at: Utc::now().timestamp_millis(),
+            updated_at: Utc::now().timestamp_millis(),
+            recurrence: None,
+        };
+        let created = create_event(&events, &event).await.unwrap().into_inner();
+
+        let intruder = AuthToken {
+            user_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            role: Role::Member,
+            email_verified: true,
+        };
+        let result = frontend_delete_event(
+            rocket::State::from(&events),
+            intruder,
+            &created.event_id.to_string(),
+            None,
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+
+        // the event is still there, and its owner can delete it
+        let result = frontend_delete_event(
+            rocket::State::from(&events),
+            AuthToken {
+                user_id: owner,
+                session_id: Uuid::new_v4(),
+                role: Role::Member,
+                email_verified: true,
+            },
+            &created.event_id.to_string(),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.unwrap(), Status::NoContent);
+        let gone = events
+            .get_event(created.event_id, owner, created.start_time)
+            .await;
+        assert!(gone.unwrap().is_none());
+    }
+
+    fn member(user_id: Uuid) -> AuthToken {
+        AuthToken {
+            user_id,
+            session_id: Uuid::new_v4(),
+            role: Role::Member,
+            email_verified: true,
+        }
+    }
+
+    async fn stored_event(events: &Events, creator_id: Uuid) -> Event {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id,
+            title: "Test Event".to_string(),
+            description: "This is a test event".to_string(),
+            start_time: 1725385197000,
+            end_time: 1725385197000 + 3600000,
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            created_at: 1725000000000,
+            updated_at: 1725000000000,
+            recurrence: None,
+        };
+        create_event(events, &event).await.unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_update_event_keeps_identity_and_bumps_version() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let created = stored_event(&events, owner).await;
+
+        let request = UpdateEventRequest {
+            event: CreateEventRequest {
+                title: "Fixed Title".to_string(),
+                description: "This is a test event".to_string(),
+                start_time: "2024-09-03T17:39:57Z".to_string(),
+                end_time: "2024-09-03T18:39:57Z".to_string(),
+                lat: 40.7128,
+                lon: -74.0060,
+                address: "Brooklyn, NY".to_string(),
+                rrule: None,
+                exdates: vec![],
+            },
+            updated_at: Some(created.updated_at),
+        };
+        let updated = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::default(),
+            request.into(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(updated.event_id, created.event_id);
+        assert_eq!(updated.created_at, created.created_at);
+        assert!(updated.updated_at > created.updated_at);
+        let stored = events
+            .get_event(created.event_id, owner, created.start_time)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.title, "Fixed Title");
+        assert_eq!(stored.address, "Brooklyn, NY");
+        assert_eq!(stored.updated_at, updated.updated_at);
+    }
+
+    #[tokio::test]
+    async fn test_stale_or_unversioned_update_is_refused() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let created = stored_event(&events, owner).await;
+        let rename = |title: &str| PatchEventRequest {
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+
+        let err = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::default(),
+            rename("No Version"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), Status::PreconditionRequired);
+
+        let first = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::new(Some(&etag(created.updated_at))),
+            rename("First"),
+        )
+        .await
+        .unwrap();
+
+        // a second writer still holding the old version loses
+        let err = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::new(Some(&etag(created.updated_at))),
+            rename("Second"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), Status::PreconditionFailed);
+        assert_eq!(err.code(), "event_modified");
+
+        let stored = events
+            .get_event_for_creator(owner, created.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.title, "First");
+        assert_eq!(stored.updated_at, first.updated_at);
+
+        // and the store refuses a write based on a stale read as well
+        let mut late = created.clone();
+        late.title = "Late".to_string();
+        assert!(!events.update_event(&created, &late).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_changing_start_time_moves_the_event() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let created = stored_event(&events, owner).await;
+
+        let changes = PatchEventRequest {
+            start_time: Some("2024-09-10T17:00:00Z".to_string()),
+            end_time: Some("2024-09-10T19:00:00Z".to_string()),
+            updated_at: Some(created.updated_at),
+            ..Default::default()
+        };
+        let moved = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::default(),
+            changes,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(moved.start_time, 1725987600000);
+        let old = events
+            .get_event(created.event_id, owner, created.start_time)
+            .await;
+        assert!(old.unwrap().is_none());
+        let query = event_query(owner, None, None, None).unwrap();
+        let all = list_events(&events, &Config::for_tests(), &query, None)
+            .await
+            .unwrap()
+            .events;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].event.event_id, created.event_id);
+        assert_eq!(all[0].event.start_time, moved.start_time);
+        assert_eq!(all[0].event.title, created.title);
+        let found = find_event(&events, created.event_id).await.unwrap();
+        assert_eq!(found.start_time, moved.start_time);
+
+        // an end before the start is rejected like on create
+        let changes = PatchEventRequest {
+            end_time: Some("2024-09-01T00:00:00Z".to_string()),
+            updated_at: Some(moved.updated_at),
+            ..Default::default()
+        };
+        let err = update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::default(),
+            changes,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "invalid_time_range");
+    }
+
+    #[tokio::test]
+    async fn test_update_of_another_users_event_is_refused() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let created = stored_event(&events, owner).await;
+
+        let changes = PatchEventRequest {
+            title: Some("Hijacked".to_string()),
+            updated_at: Some(created.updated_at),
+            ..Default::default()
+        };
+        let result = update_event(
+            &events,
+            &member(Uuid::new_v4()),
+            created.event_id,
+            &IfMatch::new(Some("*")),
+            changes,
+        )
+        .await;
+        assert!(result.is_err());
+        let stored = events
+            .get_event(created.event_id, owner, created.start_time)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.title, "Test Event");
+    }
+
+    #[tokio::test]
+    async fn test_get_event_by_id_alone() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let created = stored_event(&events, Uuid::new_v4()).await;
+
+        let found = frontend_get_event(rocket::State::from(&events), &created.event_id.to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.event.event_id, created.event_id);
+        assert_eq!(found.etag.value(), etag(created.updated_at));
+
+        let missing =
+            frontend_get_event(rocket::State::from(&events), &Uuid::new_v4().to_string()).await;
+        match missing {
+            Err(e) => assert_eq!(e.code(), "event_not_found"),
+            Ok(_) => panic!("an unknown id should not be found"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admins_moderate_other_users_events() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        let created = stored_event(&events, owner).await;
+        let admin = AuthToken {
+            role: Role::Admin,
+            ..member(Uuid::new_v4())
+        };
+
+        let changes = PatchEventRequest {
+            title: Some("Moderated".to_string()),
+            updated_at: Some(created.updated_at),
+            ..Default::default()
+        };
+        let updated = update_event(
+            &events,
+            &admin,
+            created.event_id,
+            &IfMatch::default(),
+            changes,
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.creator_id, owner);
+
+        let result = frontend_delete_event(
+            rocket::State::from(&events),
+            admin,
+            &created.event_id.to_string(),
+            None,
+            None,
         )
         .await;
+        assert_eq!(result.unwrap(), Status::NoContent);
+        let gone = events.get_event_by_id(created.event_id).await.unwrap();
+        assert!(gone.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_organizers_cannot_change_other_organizers_events() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = AuthToken {
+            role: Role::Organizer,
+            ..member(Uuid::new_v4())
+        };
+        let created = stored_event(&events, owner.user_id).await;
+        let other = AuthToken {
+            role: Role::Organizer,
+            ..member(Uuid::new_v4())
+        };
+
+        let changes = PatchEventRequest {
+            title: Some("Taken over".to_string()),
+            updated_at: Some(created.updated_at),
+            ..Default::default()
+        };
+        let result = update_event(
+            &events,
+            &other,
+            created.event_id,
+            &IfMatch::default(),
+            changes,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), "not_allowed");
+
+        let result = frontend_delete_event(
+            rocket::State::from(&events),
+            other,
+            &created.event_id.to_string(),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), "not_allowed");
+        let kept = events.get_event_by_id(created.event_id).await.unwrap();
+        assert_eq!(kept.unwrap().title, created.title);
+    }
+
+    /// Stores `count` hour-long events for `creator_id`, a day apart from
+    /// 2024-09-01T00:00:00Z on, and returns their start times.
+    async fn stored_days(events: &Events, creator_id: Uuid, count: i64) -> Vec<i64> {
+        let mut starts = Vec::new();
+        for day in 0..count {
+            let start_time = 1725148800000 + day * 86400000;
+            let event = Event {
+                event_id: Uuid::new_v4(),
+                creator_id,
+                title: format!("Day {}", day + 1),
+                description: "This is a test event".to_string(),
+                start_time,
+                end_time: start_time + 3600000,
+                lat: 40.7128,
+                lon: -74.0060,
+                address: "New York, NY".to_string(),
+                created_at: 1725000000000,
+                updated_at: 1725000000000,
+                recurrence: None,
+            };
+            events.insert_event(&event).await.unwrap();
+            starts.push(start_time);
+        }
+        starts
+    }
+
+    #[tokio::test]
+    async fn test_list_events_pages_through_a_creator() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let creator = Uuid::new_v4();
+        let starts = stored_days(&events, creator, 5).await;
+        stored_days(&events, Uuid::new_v4(), 3).await;
+
+        let query = event_query(creator, None, None, Some(2)).unwrap();
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = list_events(&events, &config, &query, cursor.as_deref())
+                .await
+                .unwrap();
+            assert!(page.events.len() <= 2);
+            seen.extend(page.events.iter().map(|e| e.event.start_time));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        let mut newest_first = starts.clone();
+        newest_first.reverse();
+        assert_eq!(seen, newest_first);
+    }
+
+    #[tokio::test]
+    async fn test_list_events_by_time_window() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let creator = Uuid::new_v4();
+        let starts = stored_days(&events, creator, 5).await;
+        stored_days(&events, Uuid::new_v4(), 5).await;
+
+        // [2024-09-02, 2024-09-04) holds the second and third day
+        let from = Some("2024-09-02T00:00:00Z");
+        let to = Some("2024-09-04T00:00:00Z");
+        let query = event_query(creator, from, to, None).unwrap();
+        let page = list_events(&events, &config, &query, None).await.unwrap();
+        let found: Vec<i64> = page.events.iter().map(|e| e.event.start_time).collect();
+        assert_eq!(found, vec![starts[2], starts[1]]);
+        assert!(page.next_cursor.is_none());
+
+        // listing everyone's events would scan every partition
+        let err = frontend_list_events(
+            rocket::State::from(&events),
+            rocket::State::from(&config),
+            None,
+            from,
+            to,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "creator_required");
+
+        let err = event_query(creator, to, from, None).unwrap_err();
+        assert_eq!(err.code(), "invalid_time_range");
+    }
+
+    #[tokio::test]
+    async fn test_cursor_only_continues_its_own_query() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let creator = Uuid::new_v4();
+        stored_days(&events, creator, 3).await;
+
+        let query = event_query(creator, None, None, Some(1)).unwrap();
+        let page = list_events(&events, &config, &query, None).await.unwrap();
+        let cursor = page.next_cursor.unwrap();
+
+        let other = event_query(Uuid::new_v4(), None, None, Some(1)).unwrap();
+        let err = list_events(&events, &config, &other, Some(&cursor))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_cursor");
+
+        let (state, _) = cursor.split_once('.').unwrap();
+        let forged = format!("{}.{}", state, "0".repeat(64));
+        let err = list_events(&events, &config, &query, Some(&forged))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_cursor");
+    }
+
+    fn near(lat: f64, lon: f64) -> NearbyParams<'static> {
+        NearbyParams {
+            lat,
+            lon,
+            radius_km: Some(25.0),
+            from: Some("2024-09-01T00:00:00Z"),
+            to: None,
+            sort: None,
+            limit: None,
+        }
+    }
+
+    async fn stored_at(events: &Events, title: &str, lat: f64, lon: f64, start_time: i64) {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id: Uuid::new_v4(),
+            title: title.to_string(),
+            description: "This is a test event".to_string(),
+            start_time,
+            end_time: start_time + 3600000,
+            lat,
+            lon,
+            address: "New York, NY".to_string(),
+            created_at: 1725000000000,
+            updated_at: 1725000000000,
+            recurrence: None,
+        };
+        events.insert_event(&event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nearby_events_expand_recurring_series() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let (lat, lon) = (40.7128, -74.0060);
+        let day = 86400000;
+        let start = 1725148800000;
+        // a daily series that started a week before the window
+        let series = Event {
+            event_id: Uuid::new_v4(),
+            creator_id: Uuid::new_v4(),
+            title: "daily".to_string(),
+            description: "This is a test event".to_string(),
+            start_time: start - 7 * day,
+            end_time: start - 7 * day + 3600000,
+            lat,
+            lon,
+            address: "New York, NY".to_string(),
+            created_at: 1725000000000,
+            updated_at: 1725000000000,
+            recurrence: Some(Recurrence {
+                rrule: "FREQ=DAILY".to_string(),
+                exdates: vec![start + day],
+            }),
+        };
+        events.insert_event(&series).await.unwrap();
+        stored_at(&events, "one-off", lat, lon, start + 2 * day + 1).await;
+
+        let query = nearby_query(
+            &NearbyParams {
+                sort: Some("start_time"),
+                limit: Some(3),
+                ..near(lat, lon)
+            },
+            0,
+        )
+        .unwrap();
+        let found = nearby_events(&events, &query).await.unwrap();
+        let listed: Vec<(i64, Option<i64>)> = found
+            .iter()
+            .map(|e| (e.location.start_time, e.occurrence_start))
+            .collect();
+        // the exdate is left out
+        assert_eq!(
+            listed,
+            vec![
+                (start, Some(start)),
+                (start + 2 * day, Some(start + 2 * day)),
+                (start + 2 * day + 1, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nearby_events_filters_by_distance() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let (lat, lon) = (40.7128, -74.0060);
+        let day = 86400000;
+        let start = 1725148800000;
+        // about 0, 5, 20 and 30 km north, and one in another city
+        stored_at(&events, "here", lat, lon, start + 3 * day).await;
+        stored_at(&events, "5 km", lat + 0.045, lon, start + 2 * day).await;
+        stored_at(&events, "20 km", lat + 0.18, lon, start + day).await;
+        stored_at(&events, "30 km", lat + 0.27, lon, start).await;
+        stored_at(&events, "Boston", 42.3601, -71.0589, start).await;
+        // a past event next door
+        stored_at(&events, "last year", lat, lon, start - 365 * day).await;
+
+        let query = nearby_query(&near(lat, lon), 0).unwrap();
+        let found = nearby_events(&events, &query).await.unwrap();
+        let titles: Vec<&str> = found.iter().map(|e| e.location.title.as_str()).collect();
+        assert_eq!(titles, vec!["here", "5 km", "20 km"]);
+        assert!(found[0].distance_km < 0.01);
+        assert!((found[2].distance_km - 20.0).abs() < 0.5);
+
+        let query = nearby_query(
+            &NearbyParams {
+                sort: Some("start_time"),
+                limit: Some(2),
+                ..near(lat, lon)
+            },
+            0,
+        )
+        .unwrap();
+        let found = nearby_events(&events, &query).await.unwrap();
+        let titles: Vec<&str> = found.iter().map(|e| e.location.title.as_str()).collect();
+        assert_eq!(titles, vec!["20 km", "5 km"]);
+    }
+
+    #[tokio::test]
+    async fn test_nearby_follows_moved_events() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let owner = Uuid::new_v4();
+        // stored in New York
+        let created = stored_event(&events, owner).await;
+        let changes = PatchEventRequest {
+            lat: Some(42.3601),
+            lon: Some(-71.0589),
+            address: Some("Boston, MA".to_string()),
+            updated_at: Some(created.updated_at),
+            ..Default::default()
+        };
+        update_event(
+            &events,
+            &member(owner),
+            created.event_id,
+            &IfMatch::default(),
+            changes,
+        )
+        .await
+        .unwrap();
+
+        let query = nearby_query(&near(40.7128, -74.0060), 0).unwrap();
+        assert!(nearby_events(&events, &query).await.unwrap().is_empty());
+        let query = nearby_query(&near(42.3601, -71.0589), 0).unwrap();
+        let found = nearby_events(&events, &query).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].location.event_id, created.event_id);
+    }
+
+    #[test]
+    fn test_nearby_query_is_validated() {
+        let base = || near(40.7128, -74.0060);
+        let mut bad = [base(), base(), base(), base(), base()];
+        bad[0].lat = 91.0;
+        bad[1].lon = -180.5;
+        bad[2].radius_km = Some(0.0);
+        bad[3].radius_km = Some(500.0);
+        bad[4].sort = Some("title");
+        let codes: Vec<&str> = bad
+            .iter()
+            .map(|params| nearby_query(params, 0).unwrap_err().code())
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                "invalid_coordinates",
+                "invalid_coordinates",
+                "invalid_radius",
+                "invalid_radius",
+                "invalid_sort"
+            ]
+        );
+
+        // with no window only upcoming events are searched
+        let mut params = base();
+        params.from = None;
+        let query = nearby_query(&params, 1725148800000).unwrap();
+        assert_eq!(query.from, Some(1725148800000));
+        assert_eq!(query.radius_km, 25.0);
+        assert_eq!(query.order, NearbyOrder::Distance);
+    }
+
+    #[tokio::test]
+    async fn test_get_event_not_found() {
+        let events: Events = Arc::new(MemoryStore::default());
+        // Act: attempt to get a non-existent event
+        let result = events
+            .get_event(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                Utc::now().timestamp_millis(),
+            )
+            .await;
 
         // Assert: check that the result is Ok(None)
         assert!(result.is_ok());
@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+
+/// Mean Earth radius used for haversine distances.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Geohash length of an `events_by_location` bucket. Four characters make
+/// cells of about 39 x 20 km at the equator, so a search of a few tens of
+/// kilometres touches a handful of partitions. Changing it orphans every
+/// stored bucket.
+pub const BUCKET_PRECISION: usize = 4;
+
+const ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// The geohash of a point, `precision` characters long.
+pub fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let (mut bits, mut value) = (0, 0usize);
+    while hash.len() < precision {
+        // bits alternate between longitude and latitude, longitude first
+        let (range, coordinate) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        value <<= 1;
+        if coordinate >= mid {
+            value |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(ALPHABET[value] as char);
+            bits = 0;
+            value = 0;
+        }
+    }
+    hash
+}
+
+/// The bucket an event at this point is stored under.
+pub fn bucket(lat: f64, lon: f64) -> String {
+    geohash(lat, lon, BUCKET_PRECISION)
+}
+
+/// Height and width in degrees of a cell of `precision` characters.
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let lon_bits = (bits + 1) / 2;
+    let lat_bits = bits / 2;
+    (180.0 / 2f64.powi(lat_bits), 360.0 / 2f64.powi(lon_bits))
+}
+
+/// Every bucket a circle of `radius_km` around the point can reach: the
+/// centre's cell and whichever neighbours the circle's bounding box covers.
+pub fn buckets_within(lat: f64, lon: f64, radius_km: f64) -> Vec<String> {
+    let (cell_height, cell_width) = cell_size(BUCKET_PRECISION);
+    let lat_span = (radius_km / EARTH_RADIUS_KM).to_degrees();
+    let south = (lat - lat_span).max(-90.0);
+    let north = (lat + lat_span).min(90.0);
+    // near the poles the box can wrap all the way around
+    let widest = south.abs().max(north.abs()).to_radians().cos();
+    let lon_span = if widest > 0.0 {
+        (lat_span / widest).min(180.0)
+    } else {
+        180.0
+    };
+
+    let lat_steps = ((north - south) / cell_height).ceil() as i64;
+    let lon_steps = ((2.0 * lon_span) / cell_width).ceil() as i64;
+    let mut buckets = BTreeSet::new();
+    for i in 0..=lat_steps {
+        let cell_lat = (south + i as f64 * cell_height).min(north);
+        for j in 0..=lon_steps {
+            let cell_lon = (lon - lon_span + j as f64 * cell_width).min(lon + lon_span);
+            buckets.insert(bucket(cell_lat, wrap_longitude(cell_lon)));
+        }
+    }
+    buckets.into_iter().collect()
+}
+
+fn wrap_longitude(lon: f64) -> f64 {
+    let wrapped = (lon + 180.0).rem_euclid(360.0) - 180.0;
+    // 180 and -180 are the same meridian; geohash only takes the latter
+    if wrapped >= 180.0 {
+        -180.0
+    } else {
+        wrapped
+    }
+}
+
+/// Great-circle distance between two points, in kilometres.
+pub fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geohash_matches_reference_values() {
+        // from the original geohash.org examples
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(42.6, -5.6, 5), "ezs42");
+        assert_eq!(bucket(40.7128, -74.0060), "dr5r");
+    }
+
+    #[test]
+    fn test_haversine_distances() {
+        assert_eq!(haversine_km(40.0, -74.0, 40.0, -74.0), 0.0);
+        // New York to London is about 5570 km
+        let d = haversine_km(40.7128, -74.0060, 51.5074, -0.1278);
+        assert!((d - 5570.0).abs() < 10.0, "{}", d);
+    }
+
+    #[test]
+    fn test_buckets_cover_points_within_the_radius() {
+        let (lat, lon) = (40.7128, -74.0060);
+        let buckets = buckets_within(lat, lon, 25.0);
+        assert!(buckets.contains(&bucket(lat, lon)));
+        // points 24 km away in each direction fall in a searched bucket
+        for (d_lat, d_lon) in [(0.216, 0.0), (-0.216, 0.0), (0.0, 0.284), (0.0, -0.284)] {
+            let (p_lat, p_lon) = (lat + d_lat, lon + d_lon);
+            assert!(haversine_km(lat, lon, p_lat, p_lon) < 25.0);
+            assert!(buckets.contains(&bucket(p_lat, p_lon)));
+        }
+        assert!(buckets.len() < 20, "{}", buckets.len());
+    }
+
+    #[test]
+    fn test_buckets_wrap_the_antimeridian() {
+        let buckets = buckets_within(0.0, 179.95, 20.0);
+        assert!(buckets.contains(&bucket(0.0, 179.95)));
+        assert!(buckets.contains(&bucket(0.0, -179.95)));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::config::Config;
+use crate::error::{parse_uuid, ApiError};
+use crate::middleware::auth::AdminOnly;
+use crate::store::{Attempts, Users};
+use crate::users::normalize_email;
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use rocket::{delete, get};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// Failed logins counted under one key, `account:<email>` or `ip:<address>`.
+/// Password reset mails are held off under `reset:<email>`, with only
+/// `locked_until` meaningful.
+#[derive(Debug, Clone)]
+pub struct LoginThrottle {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: i64,
+    /// Zero unless the key was locked out.
+    pub locked_until: i64,
+}
+
+/// One failed login, kept for the audit trail.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedLogin {
+    pub attempt_id: Uuid,
+    pub email: String,
+    pub attempted_at: i64,
+    /// Empty when the client address is unknown.
+    pub ip: String,
+    pub reason: String,
+}
+
+/// What an admin sees before deciding to unlock an account.
+#[derive(Debug, Serialize)]
+pub struct LockoutStatus {
+    pub failures: i32,
+    pub locked_until: i64,
+    pub recent_failures: Vec<FailedLogin>,
+}
+
+/// How many audit entries the lockout status shows.
+const RECENT_FAILURES: usize = 20;
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", normalize_email(email))
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Whole seconds until `until`, for `Retry-After`; never 0.
+fn secs_until(until: i64, now: i64) -> u64 {
+    ((until - now + 999) / 1000).max(1) as u64
+}
+
+/// The counter if it still counts: once a key has been quiet for the failure
+/// window, or has served its lockout, it starts over.
+fn live(throttle: Option<LoginThrottle>, config: &Config, now: i64) -> Option<LoginThrottle> {
+    throttle.filter(|t| {
+        if t.locked_until != 0 {
+            t.locked_until > now
+        } else {
+            now - t.last_failure_at < config.login.failure_window_secs * 1000
+        }
+    })
+}
+
+/// Delay in milliseconds an account must wait after its `failures`-th failure.
+fn backoff_ms(config: &Config, failures: i32) -> i64 {
+    if failures <= 0 {
+        return 0;
+    }
+    let doublings = (failures - 1).min(32) as u32;
+    config
+        .login
+        .backoff_base_secs
+        .saturating_mul(1i64 << doublings)
+        .min(config.login.backoff_max_secs)
+        * 1000
+}
+
+/// Refuses a login attempt before any password is checked, so throttled
+/// attempts do not cost a bcrypt verify.
+pub async fn check(
+    attempts: &Attempts,
+    config: &Config,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp_millis();
+
+    if let Some(ip) = ip {
+        let throttle = live(attempts.get_throttle(&ip_key(ip)).await?, config, now);
+        if let Some(t) = throttle {
+            if t.failures >= config.login.max_ip_failures {
+                return Err(ApiError::too_many_requests(
+                    "too_many_failed_logins",
+                    "Too many failed logins from this address",
+                    secs_until(
+                        t.last_failure_at + config.login.failure_window_secs * 1000,
+                        now,
+                    ),
+                ));
+            }
+        }
+    }
+
+    if let Some(t) = live(
+        attempts.get_throttle(&account_key(email)).await?,
+        config,
+        now,
+    ) {
+        if t.locked_until > now {
+            return Err(ApiError::locked(
+                "account_locked",
+                "Account is temporarily locked after repeated failed logins",
+                secs_until(t.locked_until, now),
+            ));
+        }
+        let wait_until = t.last_failure_at + backoff_ms(config, t.failures);
+        if wait_until > now {
+            return Err(ApiError::too_many_requests(
+                "login_backoff",
+                "Wait before trying to log in again",
+                secs_until(wait_until, now),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Counts a failed login against the account and the client address, locking
+/// the account once it reaches `login.max_account_failures`, and audits it.
+/// The counters are read and rewritten without a transaction, so concurrent
+/// failures may undercount by a few; backoff keeps that window small.
+pub async fn record_failure(
+    attempts: &Attempts,
+    config: &Config,
+    email: &str,
+    ip: Option<IpAddr>,
+    reason: &str,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp_millis();
+    let window_end = now + config.login.failure_window_secs * 1000;
+
+    let key = account_key(email);
+    let mut account =
+        live(attempts.get_throttle(&key).await?, config, now).unwrap_or_else(|| fresh(key));
+    account.failures += 1;
+    account.last_failure_at = now;
+    if account.failures >= config.login.max_account_failures {
+        account.locked_until = now + config.login.lockout_secs * 1000;
+    }
+    attempts
+        .put_throttle(&account, window_end.max(account.locked_until))
+        .await?;
+
+    if let Some(ip) = ip {
+        let key = ip_key(ip);
+        let mut address =
+            live(attempts.get_throttle(&key).await?, config, now).unwrap_or_else(|| fresh(key));
+        address.failures += 1;
+        address.last_failure_at = now;
+        attempts.put_throttle(&address, window_end).await?;
+    }
+
+    let attempt = FailedLogin {
+        attempt_id: Uuid::new_v4(),
+        email: normalize_email(email),
+        attempted_at: now,
+        ip: ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        reason: reason.to_string(),
+    };
+    attempts
+        .insert_failed_login(
+            &attempt,
+            now + config.login.audit_retention_days * 24 * 3600 * 1000,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Forgets the account's failures after a successful login. The address keeps
+/// its count, or one valid account would let it guess at every other.
+pub async fn record_success(attempts: &Attempts, email: &str) -> Result<(), ApiError> {
+    attempts.clear_throttle(&account_key(email)).await?;
+    Ok(())
+}
+
+fn fresh(key: String) -> LoginThrottle {
+    LoginThrottle {
+        key,
+        failures: 0,
+        last_failure_at: 0,
+        locked_until: 0,
+    }
+}
+
+pub async fn status(
+    users: &Users,
+    attempts: &Attempts,
+    config: &Config,
+    user_id: Uuid,
+) -> Result<LockoutStatus, ApiError> {
+    let user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    let now = Utc::now().timestamp_millis();
+    let throttle = live(
+        attempts.get_throttle(&account_key(&user.email)).await?,
+        config,
+        now,
+    );
+    Ok(LockoutStatus {
+        failures: throttle.as_ref().map_or(0, |t| t.failures),
+        locked_until: throttle.as_ref().map_or(0, |t| t.locked_until),
+        recent_failures: attempts
+            .failed_logins(&normalize_email(&user.email), RECENT_FAILURES)
+            .await?,
+    })
+}
+
+/// Lifts a lockout and clears the backoff. The audit trail is kept.
+pub async fn unlock(users: &Users, attempts: &Attempts, user_id: Uuid) -> Result<(), ApiError> {
+    let user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    attempts.clear_throttle(&account_key(&user.email)).await?;
+    Ok(())
+}
+
+#[get("/admin/users/<user_id>/lockout")]
+pub async fn lockout_status(
+    users: &State<Users>,
+    attempts: &State<Attempts>,
+    config: &State<Config>,
+    _admin: AdminOnly,
+    user_id: &str,
+) -> Result<Json<LockoutStatus>, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    Ok(Json(status(users, attempts, config, user_id).await?))
+}
+
+#[delete("/admin/users/<user_id>/lockout")]
+pub async fn unlock_user(
+    users: &State<Users>,
+    attempts: &State<Attempts>,
+    _admin: AdminOnly,
+    user_id: &str,
+) -> Result<Status, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    unlock(users, attempts, user_id).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use std::sync::Arc;
+
+    const EMAIL: &str = "locked@example.com";
+
+    fn setup() -> (Attempts, Config) {
+        let mut config = Config::for_tests();
+        config.login.max_account_failures = 3;
+        config.login.backoff_base_secs = 0;
+        config.login.max_ip_failures = 5;
+        (Arc::new(MemoryStore::default()), config)
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let config = Config::for_tests();
+        assert_eq!(backoff_ms(&config, 0), 0);
+        assert_eq!(backoff_ms(&config, 1), 1000);
+        assert_eq!(backoff_ms(&config, 3), 4000);
+        assert_eq!(backoff_ms(&config, 40), 60_000);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_refuses_an_early_retry() {
+        let (attempts, mut config) = setup();
+        config.login.backoff_base_secs = 10;
+        check(&attempts, &config, EMAIL, None).await.unwrap();
+        record_failure(&attempts, &config, EMAIL, None, "wrong_password")
+            .await
+            .unwrap();
+
+        let err = check(&attempts, &config, EMAIL, None).await.unwrap_err();
+        assert_eq!(err.code(), "login_backoff");
+        assert_eq!(err.status(), Status::TooManyRequests);
+    }
+
+    #[tokio::test]
+    async fn test_account_locks_and_admin_unlock_lifts_it() {
+        let (attempts, config) = setup();
+        for _ in 0..3 {
+            check(&attempts, &config, EMAIL, None).await.unwrap();
+            record_failure(&attempts, &config, EMAIL, None, "wrong_password")
+                .await
+                .unwrap();
+        }
+        let err = check(&attempts, &config, EMAIL, None).await.unwrap_err();
+        assert_eq!(err.code(), "account_locked");
+        assert_eq!(err.status(), Status::Locked);
+        assert_eq!(attempts.failed_logins(EMAIL, 10).await.unwrap().len(), 3);
+
+        attempts.clear_throttle(&account_key(EMAIL)).await.unwrap();
+        check(&attempts, &config, EMAIL, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_address_is_limited_across_accounts() {
+        let (attempts, config) = setup();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        for i in 0..5 {
+            let email = format!("user{}@example.com", i);
+            record_failure(&attempts, &config, &email, Some(ip), "unknown_account")
+                .await
+                .unwrap();
+        }
+        let err = check(&attempts, &config, "fresh@example.com", Some(ip))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "too_many_failed_logins");
+
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+        check(&attempts, &config, "fresh@example.com", Some(other))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_success_resets_the_account_but_not_the_address() {
+        let (attempts, config) = setup();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        record_failure(&attempts, &config, EMAIL, Some(ip), "wrong_password")
+            .await
+            .unwrap();
+        record_success(&attempts, EMAIL).await.unwrap();
+
+        assert!(attempts
+            .get_throttle(&account_key(EMAIL))
+            .await
+            .unwrap()
+            .is_none());
+        let address = attempts.get_throttle(&ip_key(ip)).await.unwrap().unwrap();
+        assert_eq!(address.failures, 1);
+    }
+
+    #[test]
+    fn test_served_lockout_starts_over() {
+        let config = Config::for_tests();
+        let now = Utc::now().timestamp_millis();
+        let served = LoginThrottle {
+            key: account_key(EMAIL),
+            failures: 5,
+            last_failure_at: now - 2000,
+            locked_until: now - 1000,
+        };
+        assert!(live(Some(served), &config, now).is_none());
+        let stale = LoginThrottle {
+            key: account_key(EMAIL),
+            failures: 2,
+            last_failure_at: now - config.login.failure_window_secs * 1000,
+            locked_until: 0,
+        };
+        assert!(live(Some(stale), &config, now).is_none());
+    }
+}
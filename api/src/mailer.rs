@@ -0,0 +1,355 @@
+use crate::config::{MailConfig, MailTransport};
+use chrono::Utc;
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::time::timeout;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A plain-text message to one recipient.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers mail. Handlers only see this trait, so tests and development can
+/// swap in the spool without touching an SMTP server.
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+pub type Mail = Arc<dyn Mailer>;
+
+/// Builds the mailer selected by `mail.transport`.
+pub fn from_config(config: &MailConfig) -> Mail {
+    match config.transport {
+        MailTransport::Spool => Arc::new(SpoolMailer {
+            dir: config.spool_dir.clone(),
+            from: config.from.clone(),
+        }),
+        MailTransport::Smtp => Arc::new(SmtpMailer {
+            host: config.smtp_host.clone(),
+            port: config.smtp_port,
+            from: config.from.clone(),
+            username: config.smtp_username.clone(),
+            password: config.smtp_password.clone(),
+        }),
+    }
+}
+
+/// Writes each message as an `.eml` file and logs where it went, never the
+/// body, since bodies carry tokens.
+pub struct SpoolMailer {
+    pub dir: PathBuf,
+    pub from: String,
+}
+
+#[rocket::async_trait]
+impl Mailer for SpoolMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let message = format_message(&self.from, email)?;
+        fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+        fs::write(&path, message)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        println!("Spooled mail to {} at {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+/// Plain SMTP with optional `AUTH PLAIN`. There is no TLS, so point it at a
+/// relay on the same host or private network (a postfix sidecar, MailHog)
+/// that takes care of onward delivery.
+pub struct SmtpMailer {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let message = format_message(&self.from, email)?;
+        timeout(SMTP_TIMEOUT, self.deliver(email, &message))
+            .await
+            .map_err(|_| format!("SMTP to {}:{} timed out", self.host, self.port))?
+    }
+}
+
+impl SmtpMailer {
+    async fn deliver(&self, email: &Email, message: &str) -> Result<(), String> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("Failed to connect to {}:{}: {}", self.host, self.port, e))?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        expect_reply(&mut reader, 220).await?;
+        command(&mut writer, &mut reader, "EHLO openmeet", 250).await?;
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            let credentials = base64(format!("\0{}\0{}", username, password).as_bytes());
+            command(
+                &mut writer,
+                &mut reader,
+                &format!("AUTH PLAIN {}", credentials),
+                235,
+            )
+            .await?;
+        }
+        command(
+            &mut writer,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", address(&self.from)),
+            250,
+        )
+        .await?;
+        command(
+            &mut writer,
+            &mut reader,
+            &format!("RCPT TO:<{}>", address(&email.to)),
+            250,
+        )
+        .await?;
+        command(&mut writer, &mut reader, "DATA", 354).await?;
+
+        // a line starting with '.' would end DATA early, so it gets another
+        let mut data = String::with_capacity(message.len() + 8);
+        for line in message.split("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        writer
+            .write_all(data.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_reply(&mut reader, 250).await?;
+
+        command(&mut writer, &mut reader, "QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+async fn command<W, R>(writer: &mut W, reader: &mut R, line: &str, code: u16) -> Result<(), String>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    writer
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_reply(reader, code).await
+}
+
+/// Reads a possibly multi-line reply (`250-...` continues, `250 ...` ends) and
+/// checks its code.
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, code: u16) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Err("SMTP server closed the connection".to_string());
+        }
+        let got: u16 = line
+            .get(..3)
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| format!("Malformed SMTP reply: {}", line.trim_end()))?;
+        if got != code {
+            return Err(format!("SMTP server replied: {}", line.trim_end()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// The bare address out of `Name <addr>`.
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// RFC 5322 message with CRLF line endings.
+fn format_message(from: &str, email: &Email) -> Result<String, String> {
+    // a newline in a header would let the caller add headers of their own
+    if [from, &email.to, &email.subject]
+        .iter()
+        .any(|h| h.contains(['\r', '\n']))
+    {
+        return Err("Mail headers must not contain line breaks".to_string());
+    }
+    let body = email.body.replace("\r\n", "\n").replace('\n', "\r\n");
+    Ok(format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@openmeet>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
+        from,
+        email.to,
+        email.subject,
+        Utc::now().to_rfc2822(),
+        Uuid::new_v4(),
+        body
+    ))
+}
+
+/// Keeps sent mail in memory so tests can read it back.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Outbox(pub std::sync::Mutex<Vec<Email>>);
+
+#[cfg(test)]
+#[rocket::async_trait]
+impl Mailer for Outbox {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        self.0.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::net::TcpListener;
+
+    fn email() -> Email {
+        Email {
+            to: "someone@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "line one\n.leading dot\n".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"\0user\0pass"), "AHVzZXIAcGFzcw==");
+    }
+
+    #[test]
+    fn test_headers_cannot_be_injected() {
+        let mut bad = email();
+        bad.subject = "Hi\r\nBcc: everyone@example.com".to_string();
+        assert!(format_message("a@example.com", &bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spool_writes_message() {
+        let dir = std::env::temp_dir().join(format!("openmeet-spool-{}", Uuid::new_v4()));
+        let mailer = SpoolMailer {
+            dir: dir.clone(),
+            from: "OpenMeet <no-reply@example.com>".to_string(),
+        };
+        mailer.send(&email()).await.unwrap();
+
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let message = std::fs::read_to_string(entry.path()).unwrap();
+        assert!(message.contains("To: someone@example.com\r\n"));
+        assert!(message.ends_with("line one\r\n.leading dot\r\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_smtp_conversation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = rocket::tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut transcript = Vec::new();
+            writer.write_all(b"220 test ready\r\n").await.unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-test\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go on\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                transcript.push(line);
+                writer.write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+
+        let mailer = SmtpMailer {
+            host: "127.0.0.1".to_string(),
+            port,
+            from: "OpenMeet <no-reply@example.com>".to_string(),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        };
+        mailer.send(&email()).await.unwrap();
+
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains(&"AUTH PLAIN AHVzZXIAcGFzcw==".to_string()));
+        assert!(transcript.contains(&"MAIL FROM:<no-reply@example.com>".to_string()));
+        assert!(transcript.contains(&"RCPT TO:<someone@example.com>".to_string()));
+        assert!(transcript.contains(&"..leading dot".to_string()));
+        assert_eq!(transcript.last().unwrap(), "QUIT");
+    }
+}
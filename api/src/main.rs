@@ -1,39 +1,85 @@
-use rocket::http::Status;
+use rocket::figment::Figment;
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::{delete, get, launch, post, routes};
+use rocket::{delete, get, post, put, routes, Build, Rocket, State};
 use std::env;
 use uuid::Uuid;
+mod attendance;
+mod config;
+mod crypto;
+mod db;
+mod error;
 mod events;
+mod geo;
+mod lockout;
+mod mailer;
+mod mfa;
+mod migrations;
+mod occurrences;
+mod passwords;
+mod policy;
+mod recurrence;
+mod sessions;
+mod store;
 mod users;
-use crate::events::{frontend_create_event, frontend_delete_event, CreateEventRequest, Event};
+mod verification;
+use crate::attendance::{
+    frontend_add_comment, frontend_event_comments, frontend_event_rsvps, frontend_rsvp,
+};
+use crate::config::Config;
+use crate::error::{parse_uuid, ApiError};
+use crate::events::{
+    frontend_create_event, frontend_delete_event, frontend_get_event, frontend_list_events,
+    frontend_nearby_events, frontend_patch_event, frontend_update_event, frontend_user_events,
+    CreateEventRequest, Event,
+};
+use crate::lockout::{lockout_status, unlock_user};
+use crate::mailer::Mail;
+use crate::mfa::{confirm_mfa, disable_mfa, enroll_mfa, MfaChallenge, MfaLoginRequest};
+use crate::occurrences::frontend_event_occurrences;
+use crate::passwords::{change_password, forgot_password, reset_password};
+use crate::sessions::{frontend_refresh_token, logout, my_sessions, TokenPair};
+use crate::store::{Attempts, Mfa, Sessions, StoreFairing, Users};
 use crate::users::{
-    create_user, delete_user, get_all_users, get_user_by_id, User, UserLogin, UserRegister,
+    create_user, delete_user, get_all_users, get_user_by_id, AdminView, LoginOutcome,
+    PublicProfile, Role, RoleUpdate, SelfView, User, UserLogin, UserRegister,
 };
-use cassandra_cpp::Cluster;
-use serde_json::json;
+use crate::verification::{change_email, confirm_email_change, resend_verification, verify_email};
 mod middleware;
-use crate::middleware::auth::AuthToken;
+use crate::middleware::auth::{AdminOnly, AuthToken};
+use crate::middleware::client::ClientInfo;
+use crate::policy::{authorize, Action};
 
 #[derive(Serialize)]
 struct SuccessResponse {
     message: String,
 }
 
+/// Body of a successful login: the token pair plus the account it is for.
 #[derive(Serialize)]
 struct LoginResponse {
-    token: String,
+    #[serde(flatten)]
+    tokens: TokenPair,
+    user: SelfView,
 }
 
+/// `/login` either logs in or, for accounts with two-factor authentication,
+/// asks for a code (`"mfa_required": true`).
 #[derive(Serialize)]
-struct UserReset {
-    email: String,
-    old_password: String,
-    new_password: String,
+#[serde(untagged)]
+enum LoginReply {
+    LoggedIn(LoginResponse),
+    MfaRequired(MfaChallenge),
 }
+
 use chrono::Utc;
 
 #[post("/register", data = "<user_register>")]
-async fn register(user_register: Json<UserRegister>) -> Result<Json<SuccessResponse>, Status> {
+async fn register(
+    users: &State<Users>,
+    mailer: &State<Mail>,
+    config: &State<Config>,
+    user_register: Json<UserRegister>,
+) -> Result<Json<SuccessResponse>, ApiError> {
     let user_register = user_register.into_inner();
 
     let now = Utc::now().timestamp();
@@ -45,44 +91,44 @@ async fn register(user_register: Json<UserRegister>) -> Result<Json<SuccessRespo
         created_at: now,
         updated_at: now,
         last_login: 0,
+        role: Role::Member,
+        description: String::new(),
+        interests: Vec::new(),
+        email_verified: false,
+        verification_sent_at: 0,
     };
 
-    create_user(new_user).await.map_err(|e| {
-        eprintln!("Failed to create user: {}", e);
-        Status::InternalServerError
-    })?;
+    let user = create_user(users, config, new_user).await?;
+    // the account exists either way; the user can ask for another link
+    if let Err(e) = verification::send_verification(users, mailer, config, &user).await {
+        eprintln!("{}", e);
+    }
 
     Ok(Json(SuccessResponse {
-        message: format!("User {} registered successfully", user_register.email),
+        message: format!(
+            "User {} registered successfully, check your email to verify it",
+            user_register.email
+        ),
     }))
 }
 
 #[delete("/users/<user_id>")]
 async fn frontend_delete_user(
-    _auth: AuthToken,
+    users: &State<Users>,
+    auth: AuthToken,
     user_id: &str,
-) -> Result<Json<SuccessResponse>, Status> {
-    let user_id = Uuid::parse_str(user_id).map_err(|e| {
-        eprintln!("Invalid UUID: {}", e);
-        Status::BadRequest
-    })?;
-
-    let user = get_user_by_id(user_id).await;
-    if user.is_none() {
-        return Err(Status::NotFound);
-    }
-    let user = user.unwrap();
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    authorize(&auth, Action::DeleteUser { user_id })?;
 
-    let result = delete_user(&user_id, &user.email).await;
-    match result {
-        Ok(_) => Ok(Json(SuccessResponse {
-            message: "User deleted successfully".to_string(),
-        })),
-        Err(e) => {
-            eprintln!("Failed to delete user: {}", e);
-            return Err(Status::InternalServerError);
-        }
-    }
+    let user = get_user_by_id(users, user_id)
+        .await
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+
+    delete_user(users, &user_id, &user.email).await?;
+    Ok(Json(SuccessResponse {
+        message: "User deleted successfully".to_string(),
+    }))
 }
 
 // #[get("/user")]
@@ -103,132 +149,182 @@ async fn frontend_delete_user(
 // }
 
 #[get("/users/<user_id>")]
-async fn get_user(_auth: AuthToken, user_id: &str) -> Result<Json<User>, Status> {
-    let user_id = Uuid::parse_str(user_id).map_err(|e| {
-        eprintln!("Invalid UUID: {}", e);
-        Status::BadRequest
-    })?;
-    let user = get_user_by_id(user_id).await;
+async fn get_user(
+    users: &State<Users>,
+    _auth: AuthToken,
+    user_id: &str,
+) -> Result<Json<PublicProfile>, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    let user = get_user_by_id(users, user_id).await;
     match user {
-        Some(user) => Ok(Json(user)),
-        None => Err(Status::NotFound),
+        Some(user) => Ok(Json(PublicProfile::from(&user))),
+        None => Err(ApiError::not_found("user_not_found", "User not found")),
     }
 }
 
 #[get("/whoami/<email>")]
-async fn whoami(_auth: AuthToken, email: &str) -> Result<Json<User>, Status> {
-    let user = users::get_user_by_email(email).await;
+async fn whoami(
+    users: &State<Users>,
+    auth: AuthToken,
+    email: &str,
+) -> Result<Json<SelfView>, ApiError> {
+    let user = users::get_user_by_email(users, email).await;
     match user {
-        Some(user) => Ok(Json(user)),
-        None => Err(Status::NotFound),
+        Some(user) if user.user_id == auth.user_id => Ok(Json(SelfView::from(&user))),
+        Some(_) => Err(ApiError::forbidden(
+            "not_your_account",
+            "Token does not belong to this user",
+        )),
+        None => Err(ApiError::not_found("user_not_found", "User not found")),
     }
 }
 
 #[get("/users")]
-async fn list_users(_auth: AuthToken) -> Result<Json<Vec<User>>, Status> {
-    match get_all_users().await {
-        Ok(users) => Ok(Json(users)),
-        Err(e) => {
-            eprintln!("Failed to retrieve users: {}", e);
-            Err(Status::InternalServerError)
-        }
-    }
+async fn list_users(
+    users: &State<Users>,
+    auth: AuthToken,
+) -> Result<Json<Vec<AdminView>>, ApiError> {
+    authorize(&auth, Action::ListUsers)?;
+    let users = get_all_users(users).await?;
+    Ok(Json(users.iter().map(AdminView::from).collect()))
 }
 
-#[post("/login", data = "<user_login>")]
-async fn frontend_login(user_login: Json<UserLogin>) -> Json<serde_json::Value> {
-    let user = user_login.into_inner();
-    match users::login(&user.email, &user.password).await {
-        Ok(token) => {
-            println!("token: {:?}", token);
-            Json(json!({ "success": true, "message": "Login successful", "token": token }))
-        }
-        Err(e) => {
-            eprintln!("Failed to generate token: {}", e);
-            Json(json!({ "success": false, "message": "Failed to generate token" }))
-        }
-    }
+#[put("/admin/users/<user_id>/role", data = "<update>")]
+async fn set_user_role(
+    users: &State<Users>,
+    admin: AdminOnly,
+    user_id: &str,
+    update: Json<RoleUpdate>,
+) -> Result<Json<AdminView>, ApiError> {
+    let user_id = parse_uuid(user_id)?;
+    let user = users::set_role(users, &admin.auth, user_id, update.role).await?;
+    Ok(Json(AdminView::from(&user)))
 }
-// #[post("/reset_password", data = "<user_reset>")]
-// async fn reset_password(user_reset: Json<UserReset>) -> Result<Json<SuccessResponse>, Status> {
-//     let user_reset = user_reset.into_inner();
-//     let user = user::get_user_by_email(&user_reset.email).await;
-
-//     if user.is_none() {
-//         return Err(Status::NotFound);
-//     }
 
-//     let mut user = user.unwrap();
-//     user.password_hash = hash(&user_reset.new_password, DEFAULT_COST).map_err(|e| e.to_string())?;
-
-//     // Update user in the database (you'll need to implement this)
-//     // update_user(&user).await?;
+#[post("/login", data = "<user_login>")]
+async fn frontend_login(
+    users: &State<Users>,
+    sessions: &State<Sessions>,
+    attempts: &State<Attempts>,
+    mfa: &State<Mfa>,
+    config: &State<Config>,
+    client: ClientInfo,
+    user_login: Json<UserLogin>,
+) -> Result<Json<LoginReply>, ApiError> {
+    let outcome =
+        users::login(users, sessions, attempts, mfa, config, &user_login, &client).await?;
+    Ok(Json(match outcome {
+        LoginOutcome::LoggedIn(user, tokens) => LoginReply::LoggedIn(LoginResponse {
+            tokens,
+            user: SelfView::from(&*user),
+        }),
+        LoginOutcome::MfaRequired(challenge) => LoginReply::MfaRequired(challenge),
+    }))
+}
 
-//     Ok(Json(SuccessResponse {
-//         message: "Password reset successfully".to_string(),
-//     }))
-// }
+#[post("/login/mfa", data = "<request>")]
+async fn mfa_login(
+    users: &State<Users>,
+    sessions: &State<Sessions>,
+    attempts: &State<Attempts>,
+    mfa: &State<Mfa>,
+    config: &State<Config>,
+    client: ClientInfo,
+    request: Json<MfaLoginRequest>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let (user, tokens) =
+        mfa::complete_login(users, sessions, attempts, mfa, config, &request, &client).await?;
+    Ok(Json(LoginResponse {
+        tokens,
+        user: SelfView::from(&user),
+    }))
+}
 
 #[get("/")]
 fn index() -> &'static str {
     "Welcome to the API"
 }
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build().mount(
-        "/",
-        routes![
-            index,
-            register,
-            frontend_login,
-            list_users,
-            frontend_delete_user,
-            frontend_create_event,
-            whoami,
-            frontend_delete_event
-        ],
-    )
-}
+#[rocket::main]
+async fn main() {
+    let figment = config::figment();
+    let config = match Config::from_figment(&figment) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-pub async fn init_cluster() -> Result<Cluster, String> {
-    let mut cluster = Cluster::default();
-    let contact_points = env::var("CASSANDRA_CONTACT_POINTS")
-        .map_err(|_| "CASSANDRA_CONTACT_POINTS environment variable not set".to_string())?;
-    cluster
-        .set_contact_points(&contact_points)
-        .map_err(|e| format!("Failed to set contact points: {}", e))?;
-
-    let username = env::var("CASSANDRA_USERNAME").unwrap_or_default();
-    let password = env::var("CASSANDRA_PASSWORD").unwrap_or_default();
-
-    if !username.is_empty() && !password.is_empty() {
-        cluster
-            .set_credentials(&username, &password)
-            .map_err(|e| format!("Failed to set credentials: {}", e))?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        if let Err(e) = migrations::cli(&config.cassandra, &args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("set-role") {
+        if let Err(e) = users::set_role_cli(&config.cassandra, &args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    Ok(cluster)
+    if let Err(e) = rocket(figment, config).launch().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
 
-// #[put("/events/<event_id>", data = "<event>")]
-// pub async fn update_event(event_id: Uuid, event: Json<CreateEventRequest>, user_id: Uuid) -> Result<Json<Event>, Status> {
-//     let updated_event = Event {
-//         event_id,
-//         creator_id: user_id,
-//         title: event.title.clone(),
-//         description: event.description.clone(),
-//         start_time: event.start_time,
-//         end_time: event.end_time,
-//         lat: event.lat,
-//         lon: event.lon,
-//         address: event.address.clone(),
-//         created_at: Utc::now(), // You might want to keep the original created_at
-//         updated_at: Utc::now(),
-//     };
-
-//     match db.update_event(&updated_event).await {
-//         Ok(_) => Ok(Json(updated_event)),
-//         Err(_) => Err(Status::InternalServerError),
-//     }
-// }
+fn rocket(figment: Figment, config: Config) -> Rocket<Build> {
+    let mailer = mailer::from_config(&config.mail);
+    rocket::custom(figment)
+        .manage(mailer)
+        .manage(config)
+        .attach(StoreFairing)
+        .register("/", error::catchers())
+        .mount(
+            "/",
+            routes![
+                index,
+                register,
+                frontend_login,
+                list_users,
+                get_user,
+                frontend_delete_user,
+                frontend_create_event,
+                frontend_get_event,
+                frontend_list_events,
+                frontend_nearby_events,
+                frontend_event_occurrences,
+                frontend_rsvp,
+                frontend_event_rsvps,
+                frontend_add_comment,
+                frontend_event_comments,
+                frontend_user_events,
+                whoami,
+                frontend_delete_event,
+                frontend_update_event,
+                frontend_patch_event,
+                frontend_refresh_token,
+                logout,
+                set_user_role,
+                forgot_password,
+                reset_password,
+                change_password,
+                verify_email,
+                resend_verification,
+                change_email,
+                confirm_email_change,
+                lockout_status,
+                unlock_user,
+                my_sessions,
+                mfa_login,
+                enroll_mfa,
+                confirm_mfa,
+                disable_mfa
+            ],
+        )
+}
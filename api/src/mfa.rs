@@ -0,0 +1,698 @@
+use crate::config::Config;
+use crate::crypto::{
+    base32, constant_time_eq, hmac_sha256, open, random_bytes, random_token, seal, sha256_hex,
+};
+use crate::error::ApiError;
+use crate::lockout;
+use crate::middleware::auth::AuthToken;
+use crate::middleware::client::ClientInfo;
+use crate::sessions::{self, TokenPair};
+use crate::store::{Attempts, Mfa, Sessions, Users};
+use crate::users::{finish_login, User};
+use crate::verification::sign;
+use bcrypt::verify;
+use chrono::Utc;
+use ring::hmac;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use rocket::{delete, post};
+use uuid::Uuid;
+
+/// A user's TOTP enrollment. `secret` is sealed with `secret_key`.
+#[derive(Debug, Clone)]
+pub struct MfaEnrollment {
+    pub user_id: Uuid,
+    pub secret: String,
+    /// False until the first code has been confirmed.
+    pub enabled: bool,
+    pub created_at: i64,
+    pub enabled_at: i64,
+    /// The newest time step a code was accepted for; older ones are refused.
+    pub last_used_step: i64,
+}
+
+/// What an authenticator app needs to add the account.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    /// Base32, for typing in by hand.
+    pub secret: String,
+    /// For a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Shown once, when two-factor authentication is switched on.
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by the password step of a login when a code is needed too.
+#[derive(Debug, Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    /// Seconds until `mfa_token` expires.
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnrollRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableRequest {
+    pub password: String,
+    /// A current code or an unused recovery code.
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    /// A current code or an unused recovery code.
+    pub code: String,
+}
+
+const ISSUER: &str = "OpenMeet";
+const SECRET_LEN: usize = 20;
+const DIGITS: u32 = 6;
+const STEP_SECS: i64 = 30;
+/// Steps either side of the current one that are still accepted, for clock
+/// drift between server and phone.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+/// RFC 4226 HOTP with HMAC-SHA1, which is what authenticator apps implement.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let mac = hmac::sign(&key, &counter.to_be_bytes());
+    let mac = mac.as_ref();
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        mac[offset] & 0x7f,
+        mac[offset + 1],
+        mac[offset + 2],
+        mac[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// The RFC 6238 time step `code` is valid for at `now_secs`, if any.
+fn matching_step(secret: &[u8], code: &str, now_secs: i64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let current = now_secs / STEP_SECS;
+    (current - SKEW_STEPS..=current + SKEW_STEPS).find(|&step| {
+        let expected = format!(
+            "{:0width$}",
+            hotp(secret, step as u64),
+            width = DIGITS as usize
+        );
+        constant_time_eq(&expected, code)
+    })
+}
+
+/// Secrets are sealed under a key derived from the token secret, so a leaked
+/// table alone does not give out working codes.
+fn secret_key(config: &Config) -> [u8; 32] {
+    hmac_sha256(config.token_secret(), b"openmeet-mfa-secret")
+}
+
+fn open_secret(config: &Config, enrollment: &MfaEnrollment) -> Result<Vec<u8>, ApiError> {
+    open(&secret_key(config), &enrollment.secret).ok_or_else(|| {
+        ApiError::Internal(format!(
+            "MFA secret of user {} does not decrypt; was the token secret rotated?",
+            enrollment.user_id
+        ))
+    })
+}
+
+/// `%`-encodes everything but RFC 3986 unreserved characters.
+fn uri_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn otpauth_uri(email: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = ISSUER,
+        account = uri_component(email),
+    )
+}
+
+/// Codes are typed by hand, so spaces, dashes and case are ignored.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Accepts a current code whose time step has not been used yet or, once
+/// enabled and if `allow_recovery`, an unused recovery code, consuming it.
+async fn accept_code(
+    mfa: &Mfa,
+    config: &Config,
+    enrollment: &MfaEnrollment,
+    code: &str,
+    allow_recovery: bool,
+) -> Result<bool, ApiError> {
+    let code = normalize(code);
+    let secret = open_secret(config, enrollment)?;
+    if let Some(step) = matching_step(&secret, &code, Utc::now().timestamp()) {
+        return Ok(mfa.use_step(enrollment.user_id, step).await?);
+    }
+    if allow_recovery && enrollment.enabled {
+        return Ok(mfa
+            .take_recovery_code(enrollment.user_id, &sha256_hex(code.as_bytes()))
+            .await?);
+    }
+    Ok(false)
+}
+
+pub async fn is_enabled(mfa: &Mfa, user_id: Uuid) -> Result<bool, ApiError> {
+    Ok(mfa
+        .get_mfa(user_id)
+        .await?
+        .is_some_and(|enrollment| enrollment.enabled))
+}
+
+/// The challenge token is `<user_id>.<expires_at>.<hmac>`, signed over the
+/// password hash too, so a password change voids any outstanding challenge.
+fn challenge_signature(config: &Config, user: &User, expires_at: i64) -> String {
+    sign(
+        config,
+        &[
+            "mfa-challenge",
+            &user.user_id.to_string(),
+            &user.password_hash,
+            &expires_at.to_string(),
+        ],
+    )
+}
+
+pub fn challenge(config: &Config, user: &User) -> MfaChallenge {
+    let expires_at = Utc::now().timestamp_millis() + config.auth.mfa_challenge_lifetime_secs * 1000;
+    MfaChallenge {
+        mfa_required: true,
+        mfa_token: format!(
+            "{}.{}.{}",
+            user.user_id,
+            expires_at,
+            challenge_signature(config, user, expires_at)
+        ),
+        expires_in: config.auth.mfa_challenge_lifetime_secs,
+    }
+}
+
+fn invalid_challenge() -> ApiError {
+    ApiError::unauthorized(
+        "invalid_mfa_token",
+        "Login has expired, please enter your password again",
+    )
+}
+
+/// The user a challenge token was issued to, if it is genuine and unexpired.
+async fn challenged_user(users: &Users, config: &Config, token: &str) -> Result<User, ApiError> {
+    let mut parts = token.splitn(3, '.');
+    let (user_id, expires_at, mac) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(user_id), Some(expires_at), Some(mac)) => (user_id, expires_at, mac),
+        _ => return Err(invalid_challenge()),
+    };
+    let user_id = Uuid::parse_str(user_id).map_err(|_| invalid_challenge())?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| invalid_challenge())?;
+    if expires_at <= Utc::now().timestamp_millis() {
+        return Err(invalid_challenge());
+    }
+    let user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(invalid_challenge)?;
+    if !constant_time_eq(mac, &challenge_signature(config, &user, expires_at)) {
+        return Err(invalid_challenge());
+    }
+    Ok(user)
+}
+
+async fn check_password(users: &Users, user_id: Uuid, password: &str) -> Result<User, ApiError> {
+    let user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    let matches =
+        verify(password, &user.password_hash).map_err(|e| ApiError::Internal(e.to_string()))?;
+    if !matches {
+        return Err(ApiError::forbidden(
+            "wrong_password",
+            "Current password is incorrect",
+        ));
+    }
+    Ok(user)
+}
+
+/// Starts enrolling the caller with a fresh secret. Nothing changes for
+/// logins until `confirm`; enrolling again replaces an unconfirmed secret.
+pub async fn enroll(
+    users: &Users,
+    mfa: &Mfa,
+    config: &Config,
+    auth: &AuthToken,
+    password: &str,
+) -> Result<Enrollment, ApiError> {
+    let user = check_password(users, auth.user_id, password).await?;
+    if is_enabled(mfa, user.user_id).await? {
+        return Err(ApiError::conflict(
+            "mfa_already_enabled",
+            "Two-factor authentication is already on",
+        ));
+    }
+
+    let secret = random_bytes(SECRET_LEN);
+    mfa.put_mfa(&MfaEnrollment {
+        user_id: user.user_id,
+        secret: seal(&secret_key(config), &secret),
+        enabled: false,
+        created_at: Utc::now().timestamp_millis(),
+        enabled_at: 0,
+        last_used_step: 0,
+    })
+    .await?;
+
+    let secret = base32(&secret);
+    Ok(Enrollment {
+        otpauth_uri: otpauth_uri(&user.email, &secret),
+        secret,
+    })
+}
+
+/// Switches two-factor authentication on once the caller proves their app
+/// produces the right codes, and hands out the recovery codes.
+pub async fn confirm(
+    mfa: &Mfa,
+    config: &Config,
+    auth: &AuthToken,
+    code: &str,
+) -> Result<RecoveryCodes, ApiError> {
+    let enrollment = mfa.get_mfa(auth.user_id).await?.ok_or_else(|| {
+        ApiError::conflict("mfa_not_enrolled", "Start two-factor enrollment first")
+    })?;
+    if enrollment.enabled {
+        return Err(ApiError::conflict(
+            "mfa_already_enabled",
+            "Two-factor authentication is already on",
+        ));
+    }
+    if !accept_code(mfa, config, &enrollment, code, false).await? {
+        return Err(ApiError::validation(
+            "invalid_mfa_code",
+            "The code is wrong or has expired",
+        ));
+    }
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let raw = random_token(5);
+            format!("{}-{}", &raw[..5], &raw[5..])
+        })
+        .collect();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| sha256_hex(normalize(code).as_bytes()))
+        .collect();
+    mfa.enable_mfa(auth.user_id, Utc::now().timestamp_millis(), &hashes)
+        .await?;
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Switches two-factor authentication off; takes the password and a code so
+/// a stolen session alone cannot do it.
+pub async fn disable(
+    users: &Users,
+    mfa: &Mfa,
+    config: &Config,
+    auth: &AuthToken,
+    password: &str,
+    code: &str,
+) -> Result<(), ApiError> {
+    check_password(users, auth.user_id, password).await?;
+    let enrollment = match mfa.get_mfa(auth.user_id).await? {
+        Some(enrollment) if enrollment.enabled => enrollment,
+        _ => {
+            return Err(ApiError::conflict(
+                "mfa_not_enabled",
+                "Two-factor authentication is not on",
+            ))
+        }
+    };
+    if !accept_code(mfa, config, &enrollment, code, true).await? {
+        return Err(ApiError::forbidden(
+            "invalid_mfa_code",
+            "The code is wrong or has expired",
+        ));
+    }
+    mfa.delete_mfa(auth.user_id).await?;
+    Ok(())
+}
+
+/// The code step of a two-factor login. Wrong codes count towards the
+/// account lockout like wrong passwords do.
+pub async fn complete_login(
+    users: &Users,
+    sessions: &Sessions,
+    attempts: &Attempts,
+    mfa: &Mfa,
+    config: &Config,
+    request: &MfaLoginRequest,
+    client: &ClientInfo,
+) -> Result<(User, TokenPair), ApiError> {
+    let user = challenged_user(users, config, &request.mfa_token).await?;
+    lockout::check(attempts, config, &user.email, client.ip).await?;
+
+    let accepted = match mfa.get_mfa(user.user_id).await? {
+        Some(enrollment) if enrollment.enabled => {
+            accept_code(mfa, config, &enrollment, &request.code, true).await?
+        }
+        // switched off since the password step; the password was right
+        _ => true,
+    };
+    if !accepted {
+        lockout::record_failure(attempts, config, &user.email, client.ip, "wrong_mfa_code").await?;
+        sessions::record_login(
+            sessions,
+            config,
+            user.user_id,
+            None,
+            client,
+            "wrong_mfa_code",
+        )
+        .await?;
+        return Err(ApiError::unauthorized(
+            "invalid_mfa_code",
+            "The code is wrong or has expired",
+        ));
+    }
+    finish_login(users, sessions, attempts, config, user, client).await
+}
+
+#[post("/users/me/mfa", data = "<request>")]
+pub async fn enroll_mfa(
+    users: &State<Users>,
+    mfa: &State<Mfa>,
+    config: &State<Config>,
+    auth: AuthToken,
+    request: Json<EnrollRequest>,
+) -> Result<Json<Enrollment>, ApiError> {
+    Ok(Json(
+        enroll(users, mfa, config, &auth, &request.password).await?,
+    ))
+}
+
+#[post("/users/me/mfa/confirm", data = "<request>")]
+pub async fn confirm_mfa(
+    mfa: &State<Mfa>,
+    config: &State<Config>,
+    auth: AuthToken,
+    request: Json<CodeRequest>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    Ok(Json(confirm(mfa, config, &auth, &request.code).await?))
+}
+
+#[delete("/users/me/mfa", data = "<request>")]
+pub async fn disable_mfa(
+    users: &State<Users>,
+    mfa: &State<Mfa>,
+    config: &State<Config>,
+    auth: AuthToken,
+    request: Json<DisableRequest>,
+) -> Result<Status, ApiError> {
+    disable(users, mfa, config, &auth, &request.password, &request.code).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::auth::verify_token;
+    use crate::store::MemoryStore;
+    use crate::users::{create_user, login, LoginOutcome, Role, UserLogin};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_hotp_matches_rfc_6238_vectors() {
+        let secret = b"12345678901234567890";
+        assert_eq!(hotp(secret, 59 / 30), 287082);
+        assert_eq!(hotp(secret, 1111111109 / 30), 81804);
+        assert_eq!(
+            matching_step(secret, "081804", 1111111109),
+            Some(1111111109 / 30)
+        );
+        // one step of drift either way is fine, two is not
+        assert!(matching_step(secret, "081804", 1111111109 + 30).is_some());
+        assert!(matching_step(secret, "081804", 1111111109 + 60).is_none());
+        assert!(matching_step(secret, "81804", 1111111109).is_none());
+    }
+
+    #[test]
+    fn test_otpauth_uri_escapes_the_account() {
+        assert_eq!(
+            otpauth_uri("a+b@example.com", "ABC"),
+            "otpauth://totp/OpenMeet:a%2Bb%40example.com?secret=ABC&issuer=OpenMeet&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    struct Fixture {
+        users: Users,
+        sessions: Sessions,
+        attempts: Attempts,
+        mfa: Mfa,
+        config: Config,
+        user: User,
+        auth: AuthToken,
+    }
+
+    async fn setup() -> Fixture {
+        let store = Arc::new(MemoryStore::default());
+        let users: Users = store.clone();
+        let sessions: Sessions = store.clone();
+        let mut config = Config::for_tests();
+        config.login.backoff_base_secs = 0;
+        let user = create_user(
+            &users,
+            &config,
+            User {
+                user_id: Uuid::new_v4(),
+                username: "testuser".to_string(),
+                email: "testuserMFA@example.com".to_string(),
+                password_hash: "password123".to_string(),
+                created_at: 0,
+                updated_at: 0,
+                last_login: 0,
+                role: Role::Organizer,
+                description: String::new(),
+                interests: Vec::new(),
+                email_verified: true,
+                verification_sent_at: 0,
+            },
+        )
+        .await
+        .unwrap();
+        let pair = sessions::start_session(&sessions, &config, &user)
+            .await
+            .unwrap();
+        let auth = verify_token(&config, &pair.access_token).unwrap();
+        Fixture {
+            users,
+            sessions,
+            attempts: store.clone(),
+            mfa: store,
+            config,
+            user,
+            auth,
+        }
+    }
+
+    /// The code the user's app would show, `steps` steps from now.
+    async fn code(f: &Fixture, steps: i64) -> String {
+        let enrollment = f.mfa.get_mfa(f.user.user_id).await.unwrap().unwrap();
+        let secret = open_secret(&f.config, &enrollment).unwrap();
+        let step = Utc::now().timestamp() / STEP_SECS + steps;
+        format!("{:06}", hotp(&secret, step as u64))
+    }
+
+    async fn enable(f: &Fixture) -> Vec<String> {
+        enroll(&f.users, &f.mfa, &f.config, &f.auth, "password123")
+            .await
+            .unwrap();
+        let code = code(f, 0).await;
+        confirm(&f.mfa, &f.config, &f.auth, &code)
+            .await
+            .unwrap()
+            .recovery_codes
+    }
+
+    async fn password_step(f: &Fixture) -> MfaChallenge {
+        let credentials = UserLogin {
+            email: f.user.email.clone(),
+            password: "password123".to_string(),
+        };
+        match login(
+            &f.users,
+            &f.sessions,
+            &f.attempts,
+            &f.mfa,
+            &f.config,
+            &credentials,
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap()
+        {
+            LoginOutcome::MfaRequired(challenge) => challenge,
+            LoginOutcome::LoggedIn(..) => panic!("logged in without a code"),
+        }
+    }
+
+    async fn code_step(f: &Fixture, mfa_token: &str, code: &str) -> Result<TokenPair, ApiError> {
+        let request = MfaLoginRequest {
+            mfa_token: mfa_token.to_string(),
+            code: code.to_string(),
+        };
+        complete_login(
+            &f.users,
+            &f.sessions,
+            &f.attempts,
+            &f.mfa,
+            &f.config,
+            &request,
+            &ClientInfo::default(),
+        )
+        .await
+        .map(|(_, tokens)| tokens)
+    }
+
+    #[tokio::test]
+    async fn test_enrollment_needs_a_confirmed_code() {
+        let f = setup().await;
+        let err = enroll(&f.users, &f.mfa, &f.config, &f.auth, "wrong")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "wrong_password");
+
+        let enrollment = enroll(&f.users, &f.mfa, &f.config, &f.auth, "password123")
+            .await
+            .unwrap();
+        assert!(enrollment.otpauth_uri.contains(&enrollment.secret));
+        assert!(!is_enabled(&f.mfa, f.user.user_id).await.unwrap());
+
+        let err = confirm(&f.mfa, &f.config, &f.auth, "000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_code");
+        let code = code(&f, 0).await;
+        let codes = confirm(&f.mfa, &f.config, &f.auth, &code).await.unwrap();
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODES);
+        assert!(is_enabled(&f.mfa, f.user.user_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_login_takes_a_code_once() {
+        let f = setup().await;
+        enable(&f).await;
+        let challenge = password_step(&f).await;
+
+        let err = code_step(&f, &challenge.mfa_token, "000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_code");
+
+        // the confirming code used the current step, so the next one is due
+        let next = code(&f, 1).await;
+        let tokens = code_step(&f, &challenge.mfa_token, &next).await.unwrap();
+        assert!(verify_token(&f.config, &tokens.access_token).is_ok());
+
+        let err = code_step(&f, &challenge.mfa_token, &next)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_code");
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes_are_single_use() {
+        let f = setup().await;
+        let codes = enable(&f).await;
+        let challenge = password_step(&f).await;
+
+        let typed = codes[0].to_uppercase().replace('-', " ");
+        code_step(&f, &challenge.mfa_token, &typed).await.unwrap();
+        let err = code_step(&f, &challenge.mfa_token, &codes[0])
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_code");
+    }
+
+    #[tokio::test]
+    async fn test_forged_or_stale_challenge_is_refused() {
+        let f = setup().await;
+        enable(&f).await;
+        let challenge = password_step(&f).await;
+
+        let forged = format!("{}0", challenge.mfa_token);
+        let err = code_step(&f, &forged, "000000").await.unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_token");
+
+        // a password change voids the challenge
+        f.users
+            .set_password(f.user.user_id, "new-hash", 0)
+            .await
+            .unwrap();
+        let err = code_step(&f, &challenge.mfa_token, "000000")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_token");
+    }
+
+    #[tokio::test]
+    async fn test_disable_needs_password_and_code() {
+        let f = setup().await;
+        let codes = enable(&f).await;
+
+        let err = disable(
+            &f.users,
+            &f.mfa,
+            &f.config,
+            &f.auth,
+            "password123",
+            "000000",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "invalid_mfa_code");
+        disable(
+            &f.users,
+            &f.mfa,
+            &f.config,
+            &f.auth,
+            "password123",
+            &codes[1],
+        )
+        .await
+        .unwrap();
+        assert!(!is_enabled(&f.mfa, f.user.user_id).await.unwrap());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod auth;
-use crate::middleware::auth::AuthToken;
\ No newline at end of file
+pub mod client;
+pub mod conditional;
@@ -1,28 +1,216 @@
 // src/middleware/auth.rs
-use rocket::request::{self, FromRequest};
-use rocket::outcome::Outcome;
+use crate::config::Config;
+use crate::error::{self, ApiError};
+use crate::sessions;
+use crate::store::Sessions;
+use crate::users::{Claims, Role};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
 use rocket::Request;
+use std::marker::PhantomData;
+use uuid::Uuid;
 
-pub struct AuthToken(String);
+/// The authenticated caller, taken from a valid `Authorization: Bearer <jwt>`
+/// whose session has not been revoked.
+pub struct AuthToken {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    /// As of when the token was issued; a role change applies from the next
+    /// refresh.
+    pub role: Role,
+    /// Also as of when the token was issued.
+    pub email_verified: bool,
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthToken {
-    type Error = ();
+    type Error = ApiError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        if let Some(token) = request.headers().get_one("Authorization") {
-            if verify_token(token).is_ok() {
-                return Outcome::Success(AuthToken(token.to_string()));
+        let rocket = request.rocket();
+        let (config, sessions) = match (rocket.state::<Config>(), rocket.state::<Sessions>()) {
+            (Some(config), Some(sessions)) => (config, sessions),
+            _ => {
+                let error = ApiError::Internal("Config or Sessions is not managed".to_string());
+                return Outcome::Error((Status::InternalServerError, error));
+            }
+        };
+
+        let result = match request.headers().get_one("Authorization") {
+            Some(header) => bearer(header).and_then(|token| verify_token(config, token)),
+            None => Err(ApiError::unauthorized(
+                "missing_token",
+                "Authorization header is required",
+            )),
+        };
+        let result = match result {
+            Ok(token) => sessions::check_active(sessions, token.session_id)
+                .await
+                .map(|_| token),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(token) => Outcome::Success(token),
+            Err(error) => {
+                error::remember(request, &error);
+                Outcome::Error((error.status(), error))
             }
         }
-        
-        Outcome::Error((Status::Unauthorized, ()))
     }
 }
 
-fn verify_token(token: &str) -> Result<(), &'static str> {
-    println!("Verifying token: {}", token);
+fn bearer(header: &str) -> Result<&str, ApiError> {
+    match header.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(token.trim()),
+        _ => Err(ApiError::unauthorized(
+            "invalid_token",
+            "Authorization must use the Bearer scheme",
+        )),
+    }
+}
+
+/// Checks the signature and expiry of a token issued by `users::generate_token`
+/// and resolves its subject and session. Revocation is checked separately.
+pub fn verify_token(config: &Config, token: &str) -> Result<AuthToken, ApiError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    validation.leeway = 0;
+
+    let data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.token_secret()),
+        &validation,
+    )
+    .map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => ApiError::unauthorized("token_expired", "Token has expired"),
+        _ => ApiError::unauthorized("invalid_token", "Token is invalid"),
+    })?;
+
+    let user_id = Uuid::parse_str(&data.claims.sub)
+        .map_err(|_| ApiError::unauthorized("invalid_token", "Token subject is invalid"))?;
+    let session_id = Uuid::parse_str(&data.claims.sid)
+        .map_err(|_| ApiError::unauthorized("invalid_token", "Token session is invalid"))?;
+    Ok(AuthToken {
+        user_id,
+        session_id,
+        role: data.claims.role,
+        email_verified: data.claims.email_verified,
+    })
+}
+
+/// A role a `RequireRole` guard can demand.
+pub trait MinimumRole {
+    const ROLE: Role;
+}
+
+pub struct Admin;
 
-    Ok(())
-}
\ No newline at end of file
+impl MinimumRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// An `AuthToken` whose role is at least `R::ROLE`; fails with 403 otherwise.
+pub struct RequireRole<R: MinimumRole> {
+    pub auth: AuthToken,
+    role: PhantomData<R>,
+}
+
+pub type AdminOnly = RequireRole<Admin>;
+
+#[rocket::async_trait]
+impl<'r, R: MinimumRole> FromRequest<'r> for RequireRole<R> {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let auth = match AuthToken::from_request(request).await {
+            Outcome::Success(auth) => auth,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        if auth.role >= R::ROLE {
+            return Outcome::Success(RequireRole {
+                auth,
+                role: PhantomData,
+            });
+        }
+        let error = ApiError::forbidden(
+            "insufficient_role",
+            format!("This requires the {} role", R::ROLE.as_str()),
+        );
+        error::remember(request, &error);
+        Outcome::Error((Status::Forbidden, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token(secret: &[u8], sub: &str, exp: i64) -> String {
+        let claims = Claims {
+            sub: sub.to_string(),
+            exp: exp as usize,
+            sid: Uuid::new_v4().to_string(),
+            role: Role::Member,
+            email_verified: true,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_token_accepts_valid_token() {
+        let config = Config::for_tests();
+        let user_id = Uuid::new_v4();
+        let token = token(
+            config.token_secret(),
+            &user_id.to_string(),
+            Utc::now().timestamp() + 60,
+        );
+
+        let auth = verify_token(&config, &token).unwrap();
+        assert_eq!(auth.user_id, user_id);
+    }
+
+    #[test]
+    fn test_verify_token_rejects_expired_token() {
+        let config = Config::for_tests();
+        let token = token(
+            config.token_secret(),
+            &Uuid::new_v4().to_string(),
+            Utc::now().timestamp() - 60,
+        );
+
+        let err = verify_token(&config, &token).err().unwrap();
+        assert_eq!(err.code(), "token_expired");
+    }
+
+    #[test]
+    fn test_verify_token_rejects_wrong_secret_and_bad_subject() {
+        let config = Config::for_tests();
+        let exp = Utc::now().timestamp() + 60;
+
+        let forged = token(b"some-other-secret", &Uuid::new_v4().to_string(), exp);
+        assert!(verify_token(&config, &forged).is_err());
+
+        let not_a_user = token(config.token_secret(), "admin", exp);
+        assert!(verify_token(&config, &not_a_user).is_err());
+    }
+
+    #[test]
+    fn test_bearer_scheme_is_required() {
+        assert_eq!(bearer("Bearer abc").unwrap(), "abc");
+        assert_eq!(bearer("bearer abc").unwrap(), "abc");
+        assert!(bearer("abc").is_err());
+        assert!(bearer("Basic abc").is_err());
+    }
+}
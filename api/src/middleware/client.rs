@@ -0,0 +1,99 @@
+use crate::config::Config;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+/// Longest `User-Agent` kept; anything past it is cut off.
+const MAX_USER_AGENT: usize = 256;
+
+/// What can be told about the client making a request: its address and its
+/// `User-Agent`. Used for throttling and the login history, never for
+/// authorization. The address is the one the connection came from, or what
+/// `login.trusted_proxy_header` says; Rocket's `client_ip()` is not used, as
+/// it believes an `X-Real-IP` header anyone can send.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let user_agent = request
+            .headers()
+            .get_one("User-Agent")
+            .unwrap_or_default()
+            .chars()
+            .take(MAX_USER_AGENT)
+            .collect();
+        Outcome::Success(ClientInfo {
+            ip: proxied_ip(request).or_else(|| request.remote().map(|remote| remote.ip())),
+            user_agent,
+        })
+    }
+}
+
+/// The address in the configured proxy header. The proxy appends to a list
+/// such as `X-Forwarded-For`, so only its last entry is trusted.
+fn proxied_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let config = request.rocket().state::<Config>()?;
+    let header = config.login.trusted_proxy_header.as_deref()?;
+    request
+        .headers()
+        .get(header)
+        .last()?
+        .rsplit(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+    use rocket::{get, routes};
+
+    #[get("/ip")]
+    fn ip(client: ClientInfo) -> String {
+        client.ip.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_address_comes_from_the_connection() {
+        let client = Client::untracked(rocket::build().mount("/", routes![ip])).unwrap();
+        let response = client
+            .get("/ip")
+            .remote("198.51.100.4:51000".parse().unwrap())
+            .header(rocket::http::Header::new("X-Real-IP", "203.0.113.9"))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "198.51.100.4");
+    }
+
+    #[test]
+    fn test_trusted_proxy_header_names_the_client() {
+        let mut config = Config::for_tests();
+        config.login.trusted_proxy_header = Some("X-Forwarded-For".to_string());
+        let rocket = rocket::build().manage(config).mount("/", routes![ip]);
+        let client = Client::untracked(rocket).unwrap();
+        let proxy = "10.0.0.2:51000".parse().unwrap();
+
+        let response = client
+            .get("/ip")
+            .remote(proxy)
+            .header(rocket::http::Header::new(
+                "X-Forwarded-For",
+                "203.0.113.9, 198.51.100.4",
+            ))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "198.51.100.4");
+        let response = client.get("/ip").remote(proxy).dispatch();
+        assert_eq!(response.into_string().unwrap(), "10.0.0.2");
+    }
+}
@@ -0,0 +1,42 @@
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+use std::convert::Infallible;
+
+/// The entity tag of a resource at `version`, its `updated_at`.
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The `If-Match` header, if the client sent one. Handlers that change a
+/// resource compare it against the stored version before writing.
+#[derive(Debug, Clone, Default)]
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+    pub fn new(value: Option<&str>) -> Self {
+        IfMatch(value.map(str::to_string))
+    }
+
+    /// Whether the header names `version`, or `None` if it was not sent. Weak
+    /// tags never match, as RFC 9110 asks of `If-Match`.
+    pub fn matches(&self, version: i64) -> Option<bool> {
+        let header = self.0.as_deref()?;
+        let current = etag(version);
+        Some(
+            header
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag == current),
+        )
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(IfMatch::new(request.headers().get_one("If-Match")))
+    }
+}
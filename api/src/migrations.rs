@@ -0,0 +1,509 @@
+use crate::config::CassandraConfig;
+use crate::crypto::sha256_hex;
+use crate::db::Db;
+use crate::store::row::{column, nullable};
+use cassandra_cpp::{BindRustType, LendingIterator};
+use chrono::Utc;
+use rocket::futures::future::join_all;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A versioned CQL script embedded from `database/migrations/`.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub cql: &'static str,
+}
+
+/// Every migration the binary knows about, in the order they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        cql: include_str!("../../database/migrations/0001_initial.cql"),
+    },
+    Migration {
+        version: 2,
+        name: "profiles_and_groups",
+        cql: include_str!("../../database/migrations/0002_profiles_and_groups.cql"),
+    },
+    Migration {
+        version: 3,
+        name: "sessions",
+        cql: include_str!("../../database/migrations/0003_sessions.cql"),
+    },
+    Migration {
+        version: 4,
+        name: "roles",
+        cql: include_str!("../../database/migrations/0004_roles.cql"),
+    },
+    Migration {
+        version: 5,
+        name: "password_resets",
+        cql: include_str!("../../database/migrations/0005_password_resets.cql"),
+    },
+    Migration {
+        version: 6,
+        name: "sessions_by_user",
+        cql: include_str!("../../database/migrations/0006_sessions_by_user.cql"),
+    },
+    Migration {
+        version: 7,
+        name: "email_verification",
+        cql: include_str!("../../database/migrations/0007_email_verification.cql"),
+    },
+    Migration {
+        version: 8,
+        name: "login_throttle",
+        cql: include_str!("../../database/migrations/0008_login_throttle.cql"),
+    },
+    Migration {
+        version: 9,
+        name: "login_history",
+        cql: include_str!("../../database/migrations/0009_login_history.cql"),
+    },
+    Migration {
+        version: 10,
+        name: "mfa",
+        cql: include_str!("../../database/migrations/0010_mfa.cql"),
+    },
+    Migration {
+        version: 11,
+        name: "events_by_id",
+        cql: include_str!("../../database/migrations/0011_events_by_id.cql"),
+    },
+    Migration {
+        version: 12,
+        name: "recurring_events",
+        cql: include_str!("../../database/migrations/0012_recurring_events.cql"),
+    },
+    Migration {
+        version: 13,
+        name: "previous_refresh_hash",
+        cql: include_str!("../../database/migrations/0013_previous_refresh_hash.cql"),
+    },
+    Migration {
+        version: 14,
+        name: "recurring_series",
+        cql: include_str!("../../database/migrations/0014_recurring_series.cql"),
+    },
+    Migration {
+        version: 15,
+        name: "occurrence_rsvps_and_comments",
+        cql: include_str!("../../database/migrations/0015_occurrence_rsvps_and_comments.cql"),
+    },
+    Migration {
+        version: 16,
+        name: "backfill_events_by_id",
+        cql: include_str!("../../database/migrations/0016_backfill_events_by_id.cql"),
+    },
+];
+
+/// The scripts name tables as `openmeet.<table>` so they also run as-is in
+/// cqlsh; this is swapped for the configured keyspace before executing.
+const SCRIPT_KEYSPACE: &str = "openmeet.";
+
+fn bootstrap(config: &CassandraConfig) -> Vec<String> {
+    vec![
+        format!(
+            "CREATE KEYSPACE IF NOT EXISTS {} WITH replication = {}",
+            config.keyspace, config.replication
+        ),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.schema_migrations (version INT PRIMARY KEY, name TEXT, checksum TEXT, applied_at TIMESTAMP)",
+            config.keyspace
+        ),
+    ]
+}
+
+impl Migration {
+    /// Hex SHA-256 of the script, recorded when applied so later edits are caught.
+    pub fn checksum(&self) -> String {
+        sha256_hex(self.cql.as_bytes())
+    }
+
+    /// Splits the script into single statements, since the driver executes
+    /// one at a time. `--` comments are dropped; `;` must not appear inside
+    /// string literals.
+    pub fn statements(&self, keyspace: &str) -> Vec<String> {
+        let without_comments: String = self
+            .cql
+            .lines()
+            .map(|line| match line.find("--") {
+                Some(i) => &line[..i],
+                None => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        without_comments
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.replace(SCRIPT_KEYSPACE, &format!("{}.", keyspace)))
+            .collect()
+    }
+}
+
+/// A row of `schema_migrations`.
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i32,
+    pub name: String,
+    pub checksum: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Apply every pending migration.
+    Apply,
+    /// Print the pending migrations and their statements without running them.
+    DryRun,
+    /// Check recorded checksums against the embedded scripts and fail while
+    /// any migration is still pending.
+    Verify,
+}
+
+/// Works out which migrations still have to run, refusing to continue when an
+/// applied script was edited after the fact.
+pub fn pending<'a>(
+    migrations: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Result<Vec<&'a Migration>, String> {
+    let applied: BTreeMap<i32, &AppliedMigration> =
+        applied.iter().map(|m| (m.version, m)).collect();
+
+    let mut pending = Vec::new();
+    for migration in migrations {
+        match applied.get(&migration.version) {
+            Some(record) if record.checksum != migration.checksum() => {
+                return Err(format!(
+                    "Checksum mismatch for migration {} ({}): the database recorded {}, the binary has {}",
+                    migration.version,
+                    migration.name,
+                    record.checksum,
+                    migration.checksum()
+                ));
+            }
+            Some(_) => {}
+            None => pending.push(migration),
+        }
+    }
+
+    if let Some(latest) = migrations.last() {
+        for record in applied.values() {
+            if record.version > latest.version {
+                eprintln!(
+                    "Database has migration {} ({}) which this binary does not know about",
+                    record.version, record.name
+                );
+            }
+        }
+    }
+    Ok(pending)
+}
+
+async fn applied_migrations(db: &Db) -> Result<Vec<AppliedMigration>, String> {
+    let session = db.session().await?;
+    let query = format!(
+        "SELECT version, name, checksum FROM {}.schema_migrations",
+        db.keyspace()
+    );
+    let statement = session.statement(&query);
+    let result = db.execute(statement).await.map_err(|e| e.to_string())?;
+
+    let mut applied = Vec::new();
+    let mut iter = result.iter();
+    while let Some(row) = iter.next() {
+        applied.push(AppliedMigration {
+            version: column(&row, "version").map_err(|e| e.to_string())?,
+            name: nullable(&row, "name")
+                .map_err(|e| e.to_string())?
+                .unwrap_or_default(),
+            checksum: column(&row, "checksum").map_err(|e| e.to_string())?,
+        });
+    }
+    Ok(applied)
+}
+
+/// Whether `error` only says an `ALTER TABLE ... ADD` already took effect.
+/// Cassandra has no `ADD IF NOT EXISTS` before 5.0, so a migration that died
+/// after adding its column would otherwise fail on that column forever.
+fn column_already_added(cql: &str, error: &str) -> bool {
+    let cql = cql.to_ascii_uppercase();
+    cql.starts_with("ALTER TABLE")
+        && cql.contains(" ADD ")
+        && (error.contains("already exists") || error.contains("conflicts with an existing column"))
+}
+
+async fn execute_cql(db: &Db, cql: &str) -> Result<(), String> {
+    let session = db.session().await?;
+    match db.execute(session.statement(cql)).await {
+        Ok(_) => Ok(()),
+        Err(e) if column_already_added(cql, &e.to_string()) => {
+            println!("  column already added, skipping: {}", cql);
+            Ok(())
+        }
+        Err(e) => Err(format!("{}\n  in: {}", e, cql)),
+    }
+}
+
+/// Rows read per page while backfilling.
+const BACKFILL_PAGE_SIZE: i32 = 500;
+
+/// The data step that runs after a migration's statements, for the changes
+/// CQL cannot express. It must be safe to run again, since a migration that
+/// fails part way is retried from the start.
+async fn backfill(db: &Db, migration: &Migration) -> Result<(), String> {
+    match migration.name {
+        "backfill_events_by_id" => backfill_events_by_id(db).await,
+        _ => Ok(()),
+    }
+}
+
+/// Adds every event to `events_by_id`. Existing index rows are left alone:
+/// the app has written those itself and they may be newer than the page read.
+async fn backfill_events_by_id(db: &Db) -> Result<(), String> {
+    let session = db.session().await?;
+    let select = format!(
+        "SELECT event_id, creator_id, start_time FROM {}.events",
+        db.keyspace()
+    );
+    let insert = format!(
+        "INSERT INTO {}.events_by_id (event_id, creator_id, start_time) VALUES (?, ?, ?) IF NOT EXISTS",
+        db.keyspace()
+    );
+
+    let mut indexed = 0;
+    let mut paging_state: Option<Vec<u8>> = None;
+    loop {
+        let mut statement = session.statement(&select);
+        statement
+            .set_paging_size(BACKFILL_PAGE_SIZE)
+            .map_err(|e| e.to_string())?;
+        if let Some(state) = &paging_state {
+            statement
+                .set_paging_state_token(state)
+                .map_err(|e| e.to_string())?;
+        }
+        let result = db.execute(statement).await.map_err(|e| e.to_string())?;
+
+        let mut keys: Vec<(Uuid, Uuid, i64)> = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            keys.push((
+                column(&row, "event_id").map_err(|e| e.to_string())?,
+                column(&row, "creator_id").map_err(|e| e.to_string())?,
+                column(&row, "start_time").map_err(|e| e.to_string())?,
+            ));
+        }
+        let writes = keys.iter().map(|&(event_id, creator_id, start_time)| {
+            let mut statement = session.statement(&insert);
+            async move {
+                statement.bind(0, event_id)?;
+                statement.bind(1, creator_id)?;
+                statement.bind(2, start_time)?;
+                db.execute(statement).await
+            }
+        });
+        for written in join_all(writes).await {
+            written.map_err(|e| e.to_string())?;
+        }
+        indexed += keys.len();
+
+        if !result.has_more_pages() {
+            break;
+        }
+        paging_state = result.paging_state_token().map_err(|e| e.to_string())?;
+        if paging_state.is_none() {
+            break;
+        }
+    }
+    println!("  indexed {} event(s) in events_by_id", indexed);
+    Ok(())
+}
+
+async fn record(db: &Db, migration: &Migration) -> Result<(), String> {
+    let session = db.session().await?;
+    let query = format!(
+        "INSERT INTO {}.schema_migrations (version, name, checksum, applied_at) VALUES (?, ?, ?, ?)",
+        db.keyspace()
+    );
+    let mut statement = session.statement(&query);
+    statement
+        .bind(0, migration.version)
+        .map_err(|e| e.to_string())?;
+    statement
+        .bind(1, migration.name)
+        .map_err(|e| e.to_string())?;
+    statement
+        .bind(2, migration.checksum().as_str())
+        .map_err(|e| e.to_string())?;
+    statement
+        .bind(3, Utc::now().timestamp_millis())
+        .map_err(|e| e.to_string())?;
+    db.execute(statement).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Runs the migrations in the given mode and returns the versions that were
+/// (or, for a dry run, would be) applied.
+pub async fn run(db: &Db, config: &CassandraConfig, mode: Mode) -> Result<Vec<i32>, String> {
+    let applied = match mode {
+        Mode::Apply => {
+            for cql in bootstrap(config) {
+                execute_cql(db, &cql).await?;
+            }
+            applied_migrations(db).await?
+        }
+        // the bookkeeping table may not exist yet; nothing has been applied then
+        Mode::DryRun => applied_migrations(db).await.unwrap_or_default(),
+        // a schema that cannot be read is not a verified one
+        Mode::Verify => applied_migrations(db)
+            .await
+            .map_err(|e| format!("Could not read schema_migrations: {}", e))?,
+    };
+
+    let pending = pending(MIGRATIONS, &applied)?;
+    for migration in &pending {
+        match mode {
+            Mode::Apply => {
+                println!(
+                    "Applying migration {} ({})",
+                    migration.version, migration.name
+                );
+                for cql in migration.statements(db.keyspace()) {
+                    execute_cql(db, &cql).await?;
+                }
+                backfill(db, migration).await?;
+                record(db, migration).await?;
+            }
+            Mode::DryRun => {
+                println!(
+                    "Pending migration {} ({}), checksum {}",
+                    migration.version,
+                    migration.name,
+                    migration.checksum()
+                );
+                for cql in migration.statements(db.keyspace()) {
+                    println!("  {};", cql);
+                }
+            }
+            Mode::Verify => {
+                println!(
+                    "Pending migration {} ({})",
+                    migration.version, migration.name
+                );
+            }
+        }
+    }
+    if mode == Mode::Verify && !pending.is_empty() {
+        return Err(format!(
+            "{} migration(s) pending; run `api migrate` or set migrate_on_startup",
+            pending.len()
+        ));
+    }
+    Ok(pending.iter().map(|m| m.version).collect())
+}
+
+/// Entry point for `api migrate [--dry-run | --verify]`.
+pub async fn cli(config: &CassandraConfig, args: &[String]) -> Result<(), String> {
+    let mode = match args.first().map(String::as_str) {
+        None => Mode::Apply,
+        Some("--dry-run") => Mode::DryRun,
+        Some("--verify") => Mode::Verify,
+        Some(other) => return Err(format!("Unknown migrate option: {}", other)),
+    };
+
+    let db = Db::connect(config).await?;
+    let versions = run(&db, config, mode).await?;
+    if versions.is_empty() {
+        println!("Schema is up to date");
+    }
+    db.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(migration: &Migration) -> AppliedMigration {
+        AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            checksum: migration.checksum(),
+        }
+    }
+
+    #[test]
+    fn test_versions_are_strictly_increasing() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+
+    #[test]
+    fn test_statements_strip_comments_and_split() {
+        let migration = Migration {
+            version: 1,
+            name: "test",
+            cql: "-- a comment\nCREATE TABLE a (id INT PRIMARY KEY); -- trailing\n\nCREATE TABLE b (id INT PRIMARY KEY);\n",
+        };
+        assert_eq!(
+            migration.statements("openmeet"),
+            vec![
+                "CREATE TABLE a (id INT PRIMARY KEY)".to_string(),
+                "CREATE TABLE b (id INT PRIMARY KEY)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_statements_use_configured_keyspace() {
+        let migration = Migration {
+            version: 1,
+            name: "test",
+            cql: "CREATE TABLE IF NOT EXISTS openmeet.a (id INT PRIMARY KEY);",
+        };
+        assert_eq!(
+            migration.statements("openmeet_test"),
+            vec!["CREATE TABLE IF NOT EXISTS openmeet_test.a (id INT PRIMARY KEY)".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_pending_skips_applied_migrations() {
+        let pending = pending(MIGRATIONS, &[applied(&MIGRATIONS[0])]).unwrap();
+        let versions: Vec<i32> = pending.iter().map(|m| m.version).collect();
+        assert_eq!(versions.len(), MIGRATIONS.len() - 1);
+        assert!(!versions.contains(&MIGRATIONS[0].version));
+    }
+
+    #[test]
+    fn test_rerun_column_add_counts_as_applied() {
+        let add = "ALTER TABLE openmeet.events ADD recurrence TEXT";
+        assert!(column_already_added(
+            add,
+            "Invalid column name recurrence because it conflicts with an existing column"
+        ));
+        assert!(column_already_added(
+            add,
+            "Column with name 'recurrence' already exists"
+        ));
+        assert!(!column_already_added(
+            add,
+            "Undefined column name recurrence"
+        ));
+        assert!(!column_already_added(
+            "CREATE TABLE openmeet.a (id INT PRIMARY KEY)",
+            "Table openmeet.a already exists"
+        ));
+    }
+
+    #[test]
+    fn test_pending_rejects_checksum_mismatch() {
+        let mut record = applied(&MIGRATIONS[0]);
+        record.checksum = "edited".to_string();
+        assert!(pending(MIGRATIONS, &[record]).is_err());
+    }
+}
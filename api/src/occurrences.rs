@@ -0,0 +1,917 @@
+use crate::error::{parse_timestamp, parse_uuid, ApiError};
+use crate::events::{
+    check_version, delete_event, event_modified, find_event, get_owned_event, listing_key,
+    next_version, time_window, update_event, Event, ListedEvent, PatchEventRequest,
+};
+use crate::middleware::auth::AuthToken;
+use crate::middleware::conditional::IfMatch;
+use crate::recurrence::Rule;
+use crate::store::Events;
+use chrono::Utc;
+use rocket::futures::future::join_all;
+use rocket::get;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// One occurrence of an event. `occurrence_start` is where the series puts
+/// it and never changes, so `(event_id, occurrence_start)` names the
+/// occurrence even after it is moved; RSVPs and comments name it that way.
+/// Stored in `event_overrides` once it is changed or cancelled on its own.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Occurrence {
+    pub event_id: Uuid,
+    pub occurrence_start: i64,
+    pub creator_id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub address: String,
+    pub cancelled: bool,
+}
+
+impl Occurrence {
+    /// The occurrence of `event` at `occurrence_start`, as the series has it.
+    pub fn of(event: &Event, occurrence_start: i64) -> Self {
+        Occurrence {
+            event_id: event.event_id,
+            occurrence_start,
+            creator_id: event.creator_id,
+            title: event.title.clone(),
+            description: event.description.clone(),
+            start_time: occurrence_start,
+            end_time: occurrence_start + (event.end_time - event.start_time),
+            lat: event.lat,
+            lon: event.lon,
+            address: event.address.clone(),
+            cancelled: false,
+        }
+    }
+
+    /// The occurrence as a one-off event of its series, for editing.
+    fn to_event(&self, series: &Event) -> Event {
+        Event {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            lat: self.lat,
+            lon: self.lon,
+            address: self.address.clone(),
+            recurrence: None,
+            ..series.clone()
+        }
+    }
+}
+
+/// Which part of a recurring event an update or delete applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditScope {
+    /// The whole event, the default.
+    All,
+    /// The occurrence with this `occurrence_start` alone.
+    This(i64),
+    /// That occurrence and every one after it.
+    Following(i64),
+}
+
+pub fn edit_scope(scope: Option<&str>, occurrence: Option<&str>) -> Result<EditScope, ApiError> {
+    let occurrence = || {
+        occurrence
+            .map(|o| parse_timestamp("occurrence", o))
+            .transpose()?
+            .ok_or_else(|| {
+                ApiError::validation(
+                    "occurrence_required",
+                    "scope this or following needs the occurrence's start",
+                )
+            })
+    };
+    match scope.unwrap_or("all") {
+        "all" => Ok(EditScope::All),
+        "this" => Ok(EditScope::This(occurrence()?)),
+        "following" => Ok(EditScope::Following(occurrence()?)),
+        _ => Err(ApiError::validation(
+            "invalid_scope",
+            "scope must be one of all, this, following",
+        )),
+    }
+}
+
+/// The occurrence starts of an event's series, lazily: just `start_time`
+/// for an event that does not repeat.
+fn series_starts(event: &Event) -> Result<Box<dyn Iterator<Item = i64> + '_>, ApiError> {
+    match &event.recurrence {
+        Some(recurrence) => Ok(Box::new(recurrence.starts(event.start_time).map_err(
+            |e| ApiError::Internal(format!("event {} has a bad rule: {}", event.event_id, e)),
+        )?)),
+        None => Ok(Box::new(std::iter::once(event.start_time))),
+    }
+}
+
+fn is_occurrence(event: &Event, start: i64) -> Result<bool, ApiError> {
+    Ok(series_starts(event)?
+        .take_while(|&s| s <= start)
+        .any(|s| s == start))
+}
+
+/// The occurrences of `event` starting in `[from, to)`, at most `limit` of
+/// them, with `overrides` applied. The series is only expanded as far as the
+/// window needs. Moved occurrences count where they were moved to; cancelled
+/// ones are listed with `cancelled` set.
+pub fn expand(
+    event: &Event,
+    overrides: &[Occurrence],
+    from: i64,
+    to: Option<i64>,
+    limit: usize,
+) -> Result<Vec<Occurrence>, ApiError> {
+    let in_window = |t: i64| t >= from && to.iter().all(|&to| t < to);
+    let overrides: HashMap<i64, &Occurrence> = match event.recurrence {
+        Some(_) => overrides.iter().map(|o| (o.occurrence_start, o)).collect(),
+        None => HashMap::new(),
+    };
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+    for start in series_starts(event)? {
+        if found.len() >= limit || to.is_some_and(|to| start >= to) {
+            break;
+        }
+        if start < from {
+            continue;
+        }
+        seen.insert(start);
+        let occurrence = match overrides.get(&start) {
+            Some(&changed) => changed.clone(),
+            None => Occurrence::of(event, start),
+        };
+        if in_window(occurrence.start_time) {
+            found.push(occurrence);
+        }
+    }
+    // occurrences moved into the window from outside the part expanded
+    for changed in overrides.values() {
+        if !seen.contains(&changed.occurrence_start)
+            && in_window(changed.start_time)
+            && is_occurrence(event, changed.occurrence_start)?
+        {
+            found.push((*changed).clone());
+        }
+    }
+    found.sort_by_key(|o| (o.start_time, o.occurrence_start));
+    found.truncate(limit);
+    Ok(found)
+}
+
+/// The occurrences of each of the recurring `series` starting in
+/// `[from, to)`, at most `limit` of each, as listings show them: overrides
+/// applied and cancelled occurrences left out.
+pub async fn occurrences_in(
+    events: &Events,
+    series: &[Event],
+    from: i64,
+    to: Option<i64>,
+    limit: usize,
+) -> Result<Vec<ListedEvent>, ApiError> {
+    let reads = series.iter().map(|event| events.overrides(event.event_id));
+    let mut listed = Vec::new();
+    for (event, overrides) in series.iter().zip(join_all(reads).await) {
+        for occurrence in expand(event, &overrides?, from, to, limit)? {
+            if !occurrence.cancelled {
+                listed.push(listed_occurrence(event, &occurrence));
+            }
+        }
+    }
+    Ok(listed)
+}
+
+fn listed_occurrence(series: &Event, occurrence: &Occurrence) -> ListedEvent {
+    ListedEvent {
+        event: Event {
+            recurrence: series.recurrence.clone(),
+            ..occurrence.to_event(series)
+        },
+        occurrence_start: Some(occurrence.occurrence_start),
+    }
+}
+
+/// The newest `limit` occurrences of each of the recurring `series` starting
+/// in `[from, to)` that `keep` accepts, in listing order, as listings show
+/// them. A rule can only be walked forwards, from the series' first
+/// occurrence, but no more than about `limit` of each are held on the way.
+pub async fn newest_occurrences(
+    events: &Events,
+    series: &[Event],
+    from: i64,
+    to: i64,
+    limit: usize,
+    keep: impl Fn(&ListedEvent) -> bool,
+) -> Result<Vec<ListedEvent>, ApiError> {
+    let newest_first = |kept: &mut Vec<ListedEvent>| {
+        kept.sort_by_key(listing_key);
+        kept.truncate(limit);
+    };
+    let reads = series.iter().map(|event| events.overrides(event.event_id));
+    let mut listed = Vec::new();
+    for (event, overrides) in series.iter().zip(join_all(reads).await) {
+        let overrides: HashMap<i64, Occurrence> = overrides?
+            .into_iter()
+            .map(|o| (o.occurrence_start, o))
+            .collect();
+        let mut kept = Vec::new();
+        let mut offer = |occurrence: &Occurrence| {
+            if occurrence.cancelled || occurrence.start_time < from || occurrence.start_time >= to {
+                return;
+            }
+            let item = listed_occurrence(event, occurrence);
+            if keep(&item) {
+                kept.push(item);
+            }
+            if kept.len() > 2 * limit {
+                newest_first(&mut kept);
+            }
+        };
+        for start in series_starts(event)? {
+            if start >= to {
+                break;
+            }
+            if start >= from {
+                match overrides.get(&start) {
+                    Some(changed) => offer(changed),
+                    None => offer(&Occurrence::of(event, start)),
+                }
+            }
+        }
+        // occurrences moved into the window from outside the part walked
+        for changed in overrides.values() {
+            let walked = changed.occurrence_start >= from && changed.occurrence_start < to;
+            if !walked && is_occurrence(event, changed.occurrence_start)? {
+                offer(changed);
+            }
+        }
+        newest_first(&mut kept);
+        listed.extend(kept);
+    }
+    Ok(listed)
+}
+
+/// The occurrence of `event` at `occurrence_start`, overridden or not.
+fn find_occurrence(
+    event: &Event,
+    overrides: &[Occurrence],
+    occurrence_start: i64,
+) -> Result<Occurrence, ApiError> {
+    if event.recurrence.is_none() {
+        return Err(ApiError::validation(
+            "not_recurring",
+            "Only a recurring event has occurrences to change on their own",
+        ));
+    }
+    if let Some(changed) = overrides
+        .iter()
+        .find(|o| o.occurrence_start == occurrence_start)
+    {
+        return Ok(changed.clone());
+    }
+    if !is_occurrence(event, occurrence_start)? {
+        return Err(ApiError::not_found(
+            "occurrence_not_found",
+            "The event has no occurrence starting then",
+        ));
+    }
+    Ok(Occurrence::of(event, occurrence_start))
+}
+
+/// The occurrence of `event` that RSVPs and comments with `occurrence` are
+/// about. A recurring event needs the occurrence's start; a one-off event has
+/// one occurrence, at its `start_time`, so there it may be left out.
+pub async fn named_occurrence(
+    events: &Events,
+    event: &Event,
+    occurrence: Option<&str>,
+) -> Result<Occurrence, ApiError> {
+    let start = occurrence
+        .map(|o| parse_timestamp("occurrence", o))
+        .transpose()?;
+    if event.recurrence.is_none() {
+        return match start {
+            Some(start) if start != event.start_time => Err(ApiError::not_found(
+                "occurrence_not_found",
+                "The event has no occurrence starting then",
+            )),
+            _ => Ok(Occurrence::of(event, event.start_time)),
+        };
+    }
+    let start = start.ok_or_else(|| {
+        ApiError::validation(
+            "occurrence_required",
+            "A recurring event needs the occurrence's start",
+        )
+    })?;
+    let overrides = events.overrides(event.event_id).await?;
+    find_occurrence(event, &overrides, start)
+}
+
+/// Drops the overrides of occurrences `event` no longer has, after its
+/// schedule changed.
+pub async fn prune_overrides(events: &Events, event: &Event) -> Result<(), ApiError> {
+    let mut stale = Vec::new();
+    for changed in events.overrides(event.event_id).await? {
+        if event.recurrence.is_none() || !is_occurrence(event, changed.occurrence_start)? {
+            stale.push(changed.occurrence_start);
+        }
+    }
+    events.delete_overrides(event.event_id, &stale).await?;
+    Ok(())
+}
+
+/// The series of `current` cut short before the occurrence at `split`.
+fn head_of(current: &Event, split: i64) -> Result<Event, ApiError> {
+    let mut head = current.clone();
+    if let Some(recurrence) = head.recurrence.as_mut() {
+        let rule = Rule::parse(&recurrence.rrule).map_err(ApiError::Internal)?;
+        recurrence.rrule = rule.ending_before(current.start_time, split).to_string();
+        recurrence.exdates.retain(|&exdate| exdate < split);
+    }
+    head.updated_at = next_version(current);
+    Ok(head)
+}
+
+/// Updates the whole event, one occurrence of it, or the occurrences from
+/// one on. Returns the series the caller should now hold: the same one,
+/// or for `Following` the new series the changed occurrences moved to.
+pub async fn update_in_scope(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    if_match: &IfMatch,
+    scope: EditScope,
+    changes: PatchEventRequest,
+) -> Result<Event, ApiError> {
+    match scope {
+        EditScope::All => update_event(events, auth, event_id, if_match, changes).await,
+        EditScope::This(start) => {
+            update_occurrence(events, auth, event_id, if_match, start, changes).await
+        }
+        EditScope::Following(start) => {
+            update_following(events, auth, event_id, if_match, start, changes).await
+        }
+    }
+}
+
+/// Changes or cancels one occurrence through an override. The series'
+/// version moves with it, so `If-Match` on the series guards its occurrences
+/// as well. The rule and exdates in `changes` belong to the series and are
+/// ignored here.
+async fn update_occurrence(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    if_match: &IfMatch,
+    occurrence_start: i64,
+    changes: PatchEventRequest,
+) -> Result<Event, ApiError> {
+    let current = get_owned_event(events, auth, event_id).await?;
+    check_version(if_match, changes.updated_at(), &current)?;
+    let overrides = events.overrides(event_id).await?;
+    let occurrence = find_occurrence(&current, &overrides, occurrence_start)?;
+
+    let edited = changes.apply_details(&occurrence.to_event(&current))?;
+    let changed = Occurrence {
+        start_time: edited.start_time,
+        end_time: edited.end_time,
+        cancelled: changes.cancelled().unwrap_or(occurrence.cancelled),
+        ..Occurrence::of(&edited, occurrence_start)
+    };
+
+    let mut updated = current.clone();
+    updated.updated_at = next_version(&current);
+    if !events.update_event(&current, &updated).await? {
+        return Err(event_modified());
+    }
+    events.put_override(&changed).await?;
+    Ok(updated)
+}
+
+/// Splits the series at an occurrence: the original ends before it, and it
+/// and the ones after become a new series with `changes` applied. Overrides
+/// follow to the new series if they still fall on it. Changing from the
+/// first occurrence changes the whole series instead.
+async fn update_following(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    if_match: &IfMatch,
+    occurrence_start: i64,
+    changes: PatchEventRequest,
+) -> Result<Event, ApiError> {
+    let current = get_owned_event(events, auth, event_id).await?;
+    check_version(if_match, changes.updated_at(), &current)?;
+    if changes.cancelled().is_some() {
+        return Err(ApiError::validation(
+            "invalid_scope",
+            "cancelled applies to one occurrence; send scope=this",
+        ));
+    }
+    let overrides = events.overrides(event_id).await?;
+    find_occurrence(&current, &overrides, occurrence_start)?;
+    if occurrence_start == current.start_time {
+        return update_event(events, auth, event_id, if_match, changes).await;
+    }
+
+    let head = head_of(&current, occurrence_start)?;
+    let now = Utc::now().timestamp_millis();
+    let mut tail = Event {
+        event_id: Uuid::new_v4(),
+        start_time: occurrence_start,
+        end_time: occurrence_start + (current.end_time - current.start_time),
+        created_at: now,
+        updated_at: now,
+        ..current.clone()
+    };
+    if let Some(recurrence) = tail.recurrence.as_mut() {
+        let rule = Rule::parse(&recurrence.rrule).map_err(ApiError::Internal)?;
+        recurrence.rrule = rule
+            .continuing_from(current.start_time, occurrence_start)
+            .to_string();
+        recurrence
+            .exdates
+            .retain(|&exdate| exdate >= occurrence_start);
+    }
+    let tail = changes.apply(&tail)?;
+
+    // the new series goes in first, so a lost race leaves nothing cut short
+    events.insert_event(&tail).await?;
+    if !events.update_event(&current, &head).await? {
+        events
+            .delete_event(tail.event_id, tail.creator_id, tail.start_time)
+            .await?;
+        return Err(event_modified());
+    }
+
+    let mut moved = Vec::new();
+    for changed in overrides
+        .into_iter()
+        .filter(|o| o.occurrence_start >= occurrence_start)
+    {
+        moved.push(changed.occurrence_start);
+        if tail.recurrence.is_some() && is_occurrence(&tail, changed.occurrence_start)? {
+            let changed = Occurrence {
+                event_id: tail.event_id,
+                ..changed
+            };
+            events.put_override(&changed).await?;
+        }
+    }
+    events.delete_overrides(event_id, &moved).await?;
+    Ok(tail)
+}
+
+/// Deletes the whole event, one occurrence of it (as an exdate), or the
+/// occurrences from one on. The series' stored version is the precondition
+/// for the last two, since they rewrite it.
+pub async fn delete_in_scope(
+    events: &Events,
+    auth: &AuthToken,
+    event_id: Uuid,
+    scope: EditScope,
+) -> Result<(), ApiError> {
+    let current = get_owned_event(events, auth, event_id).await?;
+    let occurrence_start = match scope {
+        EditScope::All => {
+            return delete_event(events, &event_id, &current.creator_id, &current.start_time).await
+        }
+        EditScope::This(start) | EditScope::Following(start) => start,
+    };
+    let overrides = events.overrides(event_id).await?;
+    find_occurrence(&current, &overrides, occurrence_start)?;
+
+    let (updated, dropped): (Event, Vec<i64>) = match scope {
+        EditScope::Following(start) if start == current.start_time => {
+            return delete_event(events, &event_id, &current.creator_id, &current.start_time).await
+        }
+        EditScope::Following(start) => (
+            head_of(&current, start)?,
+            overrides
+                .iter()
+                .map(|o| o.occurrence_start)
+                .filter(|&s| s >= start)
+                .collect(),
+        ),
+        _ => {
+            let mut updated = current.clone();
+            if let Some(recurrence) = updated.recurrence.as_mut() {
+                recurrence.exdates.push(occurrence_start);
+                recurrence.exdates.sort_unstable();
+            }
+            updated.updated_at = next_version(&current);
+            (updated, vec![occurrence_start])
+        }
+    };
+    if !events.update_event(&current, &updated).await? {
+        return Err(event_modified());
+    }
+    events.delete_overrides(event_id, &dropped).await?;
+    Ok(())
+}
+
+/// Lists the occurrences of an event starting in `[from, to)`, `from`
+/// defaulting to now; public like the event itself.
+#[get("/events/<event_id>/occurrences?<from>&<to>&<limit>")]
+pub async fn frontend_event_occurrences(
+    events: &State<Events>,
+    event_id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<Occurrence>>, ApiError> {
+    let event_id = parse_uuid(event_id)?;
+    let query = time_window(from, to, limit)?;
+    let event = find_event(events, event_id).await?;
+    let overrides = match event.recurrence {
+        Some(_) => events.overrides(event_id).await?,
+        None => Vec::new(),
+    };
+    let from = query.from.unwrap_or_else(|| Utc::now().timestamp_millis());
+    Ok(Json(expand(
+        &event,
+        &overrides,
+        from,
+        query.to,
+        query.limit,
+    )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::events::{event_query, list_events};
+    use crate::recurrence::Recurrence;
+    use crate::store::MemoryStore;
+    use crate::users::Role;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    // Tuesday 2024-09-03 17:00 UTC, and a week in milliseconds
+    const START: i64 = 1725382800000;
+    const WEEK: i64 = 7 * 24 * 3600 * 1000;
+
+    fn owner(user_id: Uuid) -> AuthToken {
+        AuthToken {
+            user_id,
+            session_id: Uuid::new_v4(),
+            role: Role::Member,
+            email_verified: true,
+        }
+    }
+
+    async fn weekly(events: &Events, creator_id: Uuid, rrule: &str) -> Event {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id,
+            title: "Weekly meetup".to_string(),
+            description: "Every Tuesday".to_string(),
+            start_time: START,
+            end_time: START + 3600000,
+            lat: 40.7128,
+            lon: -74.0060,
+            address: "New York, NY".to_string(),
+            created_at: 1725000000000,
+            updated_at: 1725000000000,
+            recurrence: Some(Recurrence {
+                rrule: rrule.to_string(),
+                exdates: vec![],
+            }),
+        };
+        events.insert_event(&event).await.unwrap();
+        event
+    }
+
+    fn changes(body: serde_json::Value) -> PatchEventRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn starts(occurrences: &[Occurrence]) -> Vec<i64> {
+        occurrences.iter().map(|o| o.start_time).collect()
+    }
+
+    #[test]
+    fn test_expand_applies_overrides_within_the_window() {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            creator_id: Uuid::new_v4(),
+            title: "Weekly meetup".to_string(),
+            description: String::new(),
+            start_time: START,
+            end_time: START + 3600000,
+            lat: 0.0,
+            lon: 0.0,
+            address: String::new(),
+            created_at: 0,
+            updated_at: 0,
+            recurrence: Some(Recurrence {
+                rrule: "FREQ=WEEKLY".to_string(),
+                exdates: vec![START + WEEK],
+            }),
+        };
+        // the fourth is moved back into the window, the third cancelled
+        let moved = Occurrence {
+            start_time: START + WEEK + 3600000,
+            end_time: START + WEEK + 7200000,
+            ..Occurrence::of(&event, START + 3 * WEEK)
+        };
+        let cancelled = Occurrence {
+            cancelled: true,
+            ..Occurrence::of(&event, START + 2 * WEEK)
+        };
+        let overrides = vec![moved.clone(), cancelled.clone()];
+
+        let found = expand(&event, &overrides, START, Some(START + 3 * WEEK), 10).unwrap();
+        assert_eq!(found, vec![Occurrence::of(&event, START), moved, cancelled]);
+
+        // an unbounded window stops at the limit
+        let found = expand(&event, &[], START, None, 3).unwrap();
+        assert_eq!(
+            starts(&found),
+            vec![START, START + 2 * WEEK, START + 3 * WEEK]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listings_expand_series_started_before_the_window() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let creator = Uuid::new_v4();
+        let series = weekly(&events, creator, "FREQ=WEEKLY").await;
+        let cancelled = Occurrence {
+            cancelled: true,
+            ..Occurrence::of(&series, START + 2 * WEEK)
+        };
+        events.put_override(&cancelled).await.unwrap();
+        let one_off = Event {
+            event_id: Uuid::new_v4(),
+            start_time: START + 2 * WEEK + 86400000,
+            end_time: START + 2 * WEEK + 90000000,
+            recurrence: None,
+            ..series.clone()
+        };
+        events.insert_event(&one_off).await.unwrap();
+
+        // [2024-09-10, 2024-10-01) holds the second to fourth occurrences
+        let from = Some("2024-09-10T00:00:00Z");
+        let to = Some("2024-10-01T00:00:00Z");
+        let query = event_query(creator, from, to, Some(2)).unwrap();
+        let first = list_events(&events, &config, &query, None).await.unwrap();
+        let cursor = first.next_cursor.unwrap();
+        let second = list_events(&events, &config, &query, Some(&cursor))
+            .await
+            .unwrap();
+        assert!(second.next_cursor.is_none());
+
+        let listed: Vec<(i64, Option<i64>)> = first
+            .events
+            .iter()
+            .chain(&second.events)
+            .map(|e| (e.event.start_time, e.occurrence_start))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (START + 3 * WEEK, Some(START + 3 * WEEK)),
+                (one_off.start_time, None),
+                (START + WEEK, Some(START + WEEK)),
+            ]
+        );
+        assert_eq!(first.events[0].event.event_id, series.event_id);
+        assert_eq!(first.events[0].event.end_time, START + 3 * WEEK + 3600000);
+    }
+
+    #[tokio::test]
+    async fn test_newest_occurrences_keep_a_page_below_the_cursor() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let series = weekly(&events, Uuid::new_v4(), "FREQ=WEEKLY").await;
+        // the occurrence ten weeks in moves into the window; five weeks in is cancelled
+        let moved = Occurrence {
+            start_time: START + 5 * WEEK + 3600000,
+            end_time: START + 5 * WEEK + 7200000,
+            ..Occurrence::of(&series, START + 10 * WEEK)
+        };
+        events.put_override(&moved).await.unwrap();
+        let cancelled = Occurrence {
+            cancelled: true,
+            ..Occurrence::of(&series, START + 5 * WEEK)
+        };
+        events.put_override(&cancelled).await.unwrap();
+
+        let below = |start: i64| move |item: &ListedEvent| item.event.start_time < start;
+        let listed = newest_occurrences(
+            &events,
+            std::slice::from_ref(&series),
+            START + WEEK,
+            START + 8 * WEEK,
+            3,
+            below(START + 7 * WEEK),
+        )
+        .await
+        .unwrap();
+        let starts: Vec<i64> = listed.iter().map(|e| e.event.start_time).collect();
+        assert_eq!(
+            starts,
+            vec![START + 6 * WEEK, moved.start_time, START + 4 * WEEK]
+        );
+        assert_eq!(listed[1].occurrence_start, Some(START + 10 * WEEK));
+    }
+
+    #[test]
+    fn test_edit_scope_needs_an_occurrence() {
+        assert_eq!(edit_scope(None, None).unwrap(), EditScope::All);
+        assert_eq!(
+            edit_scope(Some("this"), Some("2024-09-03T17:00:00Z")).unwrap(),
+            EditScope::This(START)
+        );
+        let missing = edit_scope(Some("following"), None).unwrap_err();
+        assert_eq!(missing.code(), "occurrence_required");
+        let unknown = edit_scope(Some("some"), None).unwrap_err();
+        assert_eq!(unknown.code(), "invalid_scope");
+    }
+
+    #[tokio::test]
+    async fn test_update_one_occurrence_overrides_it() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let creator = Uuid::new_v4();
+        let series = weekly(&events, creator, "FREQ=WEEKLY;COUNT=4").await;
+        let if_match = IfMatch::new(Some("\"1725000000000\""));
+
+        let updated = update_in_scope(
+            &events,
+            &owner(creator),
+            series.event_id,
+            &if_match,
+            EditScope::This(START + WEEK),
+            changes(json!({"title": "Moved meetup", "start_time": "2024-09-11T17:00:00Z", "end_time": "2024-09-11T18:00:00Z"})),
+        )
+        .await
+        .unwrap();
+        assert!(updated.updated_at > series.updated_at);
+        assert_eq!(updated.title, "Weekly meetup");
+
+        let overrides = events.overrides(series.event_id).await.unwrap();
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides[0].occurrence_start, START + WEEK);
+        assert_eq!(overrides[0].title, "Moved meetup");
+        let found = expand(&updated, &overrides, START, None, 10).unwrap();
+        let day = 24 * 3600 * 1000;
+        assert_eq!(
+            starts(&found),
+            vec![
+                START,
+                START + WEEK + day,
+                START + 2 * WEEK,
+                START + 3 * WEEK
+            ]
+        );
+
+        // the series moved on, so the old tag is stale
+        let stale = update_in_scope(
+            &events,
+            &owner(creator),
+            series.event_id,
+            &if_match,
+            EditScope::This(START + 2 * WEEK),
+            changes(json!({"cancelled": true})),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(stale.code(), "event_modified");
+
+        let missing = update_in_scope(
+            &events,
+            &owner(creator),
+            series.event_id,
+            &IfMatch::new(Some("*")),
+            EditScope::This(START + 3600000),
+            changes(json!({"cancelled": true})),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(missing.code(), "occurrence_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_update_following_splits_the_series() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let creator = Uuid::new_v4();
+        let series = weekly(&events, creator, "FREQ=WEEKLY;COUNT=5").await;
+        let later = Occurrence {
+            title: "Guest speaker".to_string(),
+            ..Occurrence::of(&series, START + 3 * WEEK)
+        };
+        events.put_override(&later).await.unwrap();
+
+        let tail = update_in_scope(
+            &events,
+            &owner(creator),
+            series.event_id,
+            &IfMatch::new(Some("*")),
+            EditScope::Following(START + 2 * WEEK),
+            changes(json!({"address": "Brooklyn, NY"})),
+        )
+        .await
+        .unwrap();
+        assert_ne!(tail.event_id, series.event_id);
+        assert_eq!(tail.start_time, START + 2 * WEEK);
+        assert_eq!(tail.address, "Brooklyn, NY");
+        assert_eq!(
+            tail.recurrence.as_ref().unwrap().rrule,
+            "FREQ=WEEKLY;COUNT=3"
+        );
+
+        let head = find_event(&events, series.event_id).await.unwrap();
+        assert_eq!(
+            head.recurrence.as_ref().unwrap().rrule,
+            "FREQ=WEEKLY;COUNT=2"
+        );
+        assert!(events.overrides(head.event_id).await.unwrap().is_empty());
+
+        let moved = events.overrides(tail.event_id).await.unwrap();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].title, "Guest speaker");
+        let found = expand(&tail, &moved, START, None, 10).unwrap();
+        assert_eq!(
+            starts(&found),
+            vec![START + 2 * WEEK, START + 3 * WEEK, START + 4 * WEEK]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_in_scope() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let creator = Uuid::new_v4();
+        let series = weekly(&events, creator, "FREQ=WEEKLY").await;
+        let auth = owner(creator);
+
+        delete_in_scope(
+            &events,
+            &auth,
+            series.event_id,
+            EditScope::This(START + WEEK),
+        )
+        .await
+        .unwrap();
+        let event = find_event(&events, series.event_id).await.unwrap();
+        assert_eq!(
+            event.recurrence.as_ref().unwrap().exdates,
+            vec![START + WEEK]
+        );
+
+        delete_in_scope(
+            &events,
+            &auth,
+            series.event_id,
+            EditScope::Following(START + 3 * WEEK),
+        )
+        .await
+        .unwrap();
+        let event = find_event(&events, series.event_id).await.unwrap();
+        let found = expand(&event, &[], START, None, 10).unwrap();
+        assert_eq!(starts(&found), vec![START, START + 2 * WEEK]);
+
+        // from the first occurrence on is the whole event
+        delete_in_scope(&events, &auth, series.event_id, EditScope::Following(START))
+            .await
+            .unwrap();
+        assert!(events
+            .get_event_by_id(series.event_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_one_off_events_have_no_scoped_edits() {
+        let events: Events = Arc::new(MemoryStore::default());
+        let creator = Uuid::new_v4();
+        let mut event = weekly(&events, creator, "FREQ=DAILY").await;
+        event.event_id = Uuid::new_v4();
+        event.recurrence = None;
+        events.insert_event(&event).await.unwrap();
+
+        let result = delete_in_scope(
+            &events,
+            &owner(creator),
+            event.event_id,
+            EditScope::This(START),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().code(), "not_recurring");
+        assert_eq!(
+            expand(&event, &[], START, None, 10).unwrap(),
+            vec![Occurrence::of(&event, START)]
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use crate::config::Config;
+use crate::crypto::{random_token, sha256_hex};
+use crate::error::ApiError;
+use crate::lockout::LoginThrottle;
+use crate::mailer::{Email, Mail};
+use crate::middleware::auth::AuthToken;
+use crate::sessions;
+use crate::store::{Attempts, Resets, Sessions, Users};
+use crate::users::{normalize_email, user_with_email};
+use bcrypt::{hash, verify};
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize};
+use rocket::tokio::task::JoinHandle;
+use rocket::State;
+use rocket::{post, put};
+use uuid::Uuid;
+
+/// An outstanding reset link. Only the SHA-256 of the mailed token is kept.
+#[derive(Debug, Clone)]
+pub struct PasswordReset {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeRequest {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+const MIN_PASSWORD_LEN: usize = 8;
+
+pub fn validate_password(password: &str) -> Result<(), ApiError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(ApiError::validation(
+            "weak_password",
+            format!("Password must be at least {} characters", MIN_PASSWORD_LEN),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_reset() -> ApiError {
+    ApiError::validation(
+        "invalid_reset_token",
+        "Reset link is invalid or has expired",
+    )
+}
+
+/// Holds off a second reset mail to `email` for
+/// `verification_resend_interval_secs`, like verification links. Keyed by the
+/// address asked for, whether it has an account or not, so being throttled
+/// gives nothing away either.
+async fn throttle_reset(
+    attempts: &Attempts,
+    config: &Config,
+    email: &str,
+    now: i64,
+) -> Result<(), ApiError> {
+    let key = format!("reset:{}", normalize_email(email));
+    if let Some(throttle) = attempts.get_throttle(&key).await? {
+        if throttle.locked_until > now {
+            let wait = ((throttle.locked_until - now) as u64).div_ceil(1000);
+            return Err(ApiError::too_many_requests(
+                "reset_throttled",
+                format!("Wait {} seconds before asking for another link", wait),
+                wait,
+            ));
+        }
+    }
+    let until = now + config.auth.verification_resend_interval_secs * 1000;
+    let throttle = LoginThrottle {
+        key,
+        failures: 0,
+        last_failure_at: now,
+        locked_until: until,
+    };
+    attempts.put_throttle(&throttle, until).await?;
+    Ok(())
+}
+
+/// Mails a reset link if `email` belongs to an account. The caller cannot tell
+/// whether it did, so the endpoint does not reveal who has an account: the
+/// reset is stored and mailed by a background task, leaving a known address
+/// as quick to answer as an unknown one. Returns that task, if there is one.
+pub async fn forgot(
+    users: &Users,
+    resets: &Resets,
+    attempts: &Attempts,
+    mailer: &Mail,
+    config: &Config,
+    email: &str,
+) -> Result<Option<JoinHandle<()>>, ApiError> {
+    let now = Utc::now().timestamp_millis();
+    throttle_reset(attempts, config, email, now).await?;
+    let user = match user_with_email(users, email).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let token = random_token(32);
+    let reset = PasswordReset {
+        token_hash: sha256_hex(token.as_bytes()),
+        user_id: user.user_id,
+        created_at: now,
+        expires_at: now + config.auth.reset_token_lifetime_secs * 1000,
+    };
+
+    let link = format!(
+        "{}/ResetPassword?token={}",
+        config.mail.public_url.trim_end_matches('/'),
+        token
+    );
+    let message = Email {
+        to: user.email.clone(),
+        subject: "Reset your OpenMeet password".to_string(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password for your OpenMeet account.\nTo choose a new one, open this link within {} minutes:\n\n{}\n\nIf it was not you, ignore this message; your password has not changed.\n",
+            user.username,
+            config.auth.reset_token_lifetime_secs / 60,
+            link
+        ),
+    };
+    let resets = resets.clone();
+    let mailer = mailer.clone();
+    Ok(Some(rocket::tokio::spawn(async move {
+        if let Err(e) = resets.insert_reset(&reset).await {
+            eprintln!("Failed to store password reset: {}", e);
+            return;
+        }
+        if let Err(e) = mailer.send(&message).await {
+            eprintln!("Failed to send password reset mail: {}", e);
+        }
+    })))
+}
+
+/// Consumes a reset token and sets the new password. Every session of the
+/// user ends, since whoever knew the old password may hold one.
+pub async fn reset(
+    users: &Users,
+    resets: &Resets,
+    sessions: &Sessions,
+    config: &Config,
+    token: &str,
+    new_password: &str,
+) -> Result<(), ApiError> {
+    // checked first, so a rejected password does not burn the token
+    validate_password(new_password)?;
+
+    let reset = resets
+        .take_reset(&sha256_hex(token.as_bytes()))
+        .await?
+        .ok_or_else(invalid_reset)?;
+    let now = Utc::now().timestamp_millis();
+    if reset.expires_at <= now {
+        return Err(invalid_reset());
+    }
+
+    set_password(users, config, reset.user_id, new_password).await?;
+    sessions::revoke_all(sessions, reset.user_id, None).await
+}
+
+/// Changes the caller's password after checking the current one. The
+/// caller's own session stays; every other one is revoked.
+pub async fn change(
+    users: &Users,
+    sessions: &Sessions,
+    config: &Config,
+    auth: &AuthToken,
+    old_password: &str,
+    new_password: &str,
+) -> Result<(), ApiError> {
+    let user = users
+        .get_user_by_id(auth.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    let matches =
+        verify(old_password, &user.password_hash).map_err(|e| ApiError::Internal(e.to_string()))?;
+    if !matches {
+        return Err(ApiError::forbidden(
+            "wrong_password",
+            "Current password is incorrect",
+        ));
+    }
+    validate_password(new_password)?;
+
+    set_password(users, config, user.user_id, new_password).await?;
+    sessions::revoke_all(sessions, user.user_id, Some(auth.session_id)).await
+}
+
+async fn set_password(
+    users: &Users,
+    config: &Config,
+    user_id: Uuid,
+    password: &str,
+) -> Result<(), ApiError> {
+    let password_hash =
+        hash(password, config.auth.bcrypt_cost).map_err(|e| ApiError::Internal(e.to_string()))?;
+    users
+        .set_password(user_id, &password_hash, Utc::now().timestamp_millis())
+        .await?;
+    Ok(())
+}
+
+#[post("/password/forgot", data = "<request>")]
+pub async fn forgot_password(
+    users: &State<Users>,
+    resets: &State<Resets>,
+    attempts: &State<Attempts>,
+    mailer: &State<Mail>,
+    config: &State<Config>,
+    request: Json<ForgotRequest>,
+) -> Result<Status, ApiError> {
+    forgot(users, resets, attempts, mailer, config, &request.email).await?;
+    Ok(Status::Accepted)
+}
+
+#[post("/password/reset", data = "<request>")]
+pub async fn reset_password(
+    users: &State<Users>,
+    resets: &State<Resets>,
+    sessions: &State<Sessions>,
+    config: &State<Config>,
+    request: Json<ResetRequest>,
+) -> Result<Status, ApiError> {
+    reset(
+        users,
+        resets,
+        sessions,
+        config,
+        &request.token,
+        &request.new_password,
+    )
+    .await?;
+    Ok(Status::NoContent)
+}
+
+#[put("/users/me/password", data = "<request>")]
+pub async fn change_password(
+    users: &State<Users>,
+    sessions: &State<Sessions>,
+    config: &State<Config>,
+    auth: AuthToken,
+    request: Json<ChangeRequest>,
+) -> Result<Status, ApiError> {
+    change(
+        users,
+        sessions,
+        config,
+        &auth,
+        &request.old_password,
+        &request.new_password,
+    )
+    .await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::Outbox;
+    use crate::middleware::auth::verify_token;
+    use crate::store::MemoryStore;
+    use crate::users::{create_user, Role, User};
+    use std::sync::Arc;
+
+    /// The token from the reset link in the last mail sent.
+    fn last_token(outbox: &Outbox) -> String {
+        let sent = outbox.0.lock().unwrap();
+        let body = &sent.last().unwrap().body;
+        let start = body.find("token=").unwrap() + "token=".len();
+        body[start..].split_whitespace().next().unwrap().to_string()
+    }
+
+    struct Fixture {
+        users: Users,
+        resets: Resets,
+        attempts: Attempts,
+        sessions: Sessions,
+        outbox: Arc<Outbox>,
+        config: Config,
+        user: User,
+    }
+
+    async fn setup() -> Fixture {
+        let store = Arc::new(MemoryStore::default());
+        let users: Users = store.clone();
+        let config = Config::for_tests();
+        let user = User {
+            user_id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: "testuserRESET@example.com".to_string(),
+            password_hash: "password123".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            last_login: 0,
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
+        };
+        let user = create_user(&users, &config, user).await.unwrap();
+        Fixture {
+            users,
+            resets: store.clone(),
+            attempts: store.clone(),
+            sessions: store,
+            outbox: Arc::new(Outbox::default()),
+            config,
+            user,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_sets_password_once_and_ends_sessions() {
+        let f = setup().await;
+        let pair = sessions::start_session(&f.sessions, &f.config, &f.user)
+            .await
+            .unwrap();
+        let mailer: Mail = f.outbox.clone();
+        let task = forgot(
+            &f.users,
+            &f.resets,
+            &f.attempts,
+            &mailer,
+            &f.config,
+            &f.user.email,
+        )
+        .await
+        .unwrap();
+        task.unwrap().await.unwrap();
+        let token = last_token(&f.outbox);
+
+        reset(
+            &f.users,
+            &f.resets,
+            &f.sessions,
+            &f.config,
+            &token,
+            "new-password",
+        )
+        .await
+        .unwrap();
+        let stored = f
+            .users
+            .get_user_by_id(f.user.user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(verify("new-password", &stored.password_hash).unwrap());
+        assert!(
+            sessions::refresh(&f.users, &f.sessions, &f.config, &pair.refresh_token)
+                .await
+                .is_err()
+        );
+
+        let err = reset(
+            &f.users,
+            &f.resets,
+            &f.sessions,
+            &f.config,
+            &token,
+            "another-password",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "invalid_reset_token");
+    }
+
+    #[tokio::test]
+    async fn test_forgot_is_silent_for_unknown_email() {
+        let f = setup().await;
+        let mailer: Mail = f.outbox.clone();
+        let task = forgot(
+            &f.users,
+            &f.resets,
+            &f.attempts,
+            &mailer,
+            &f.config,
+            "nobody@example.com",
+        )
+        .await
+        .unwrap();
+        assert!(task.is_none());
+        assert!(f.outbox.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forgot_is_throttled_per_address_known_or_not() {
+        let f = setup().await;
+        let mailer: Mail = f.outbox.clone();
+        for email in [f.user.email.as_str(), "nobody@example.com"] {
+            forgot(&f.users, &f.resets, &f.attempts, &mailer, &f.config, email)
+                .await
+                .unwrap();
+            let err = forgot(&f.users, &f.resets, &f.attempts, &mailer, &f.config, email)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "reset_throttled");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expired_or_weak_reset_is_refused() {
+        let f = setup().await;
+        let now = Utc::now().timestamp_millis();
+        f.resets
+            .insert_reset(&PasswordReset {
+                token_hash: sha256_hex(b"expired"),
+                user_id: f.user.user_id,
+                created_at: now - 2000,
+                expires_at: now - 1000,
+            })
+            .await
+            .unwrap();
+        let err = reset(
+            &f.users,
+            &f.resets,
+            &f.sessions,
+            &f.config,
+            "expired",
+            "new-password",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "invalid_reset_token");
+
+        let err = reset(
+            &f.users,
+            &f.resets,
+            &f.sessions,
+            &f.config,
+            "anything",
+            "short",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "weak_password");
+    }
+
+    #[tokio::test]
+    async fn test_change_keeps_only_the_callers_session() {
+        let f = setup().await;
+        let mine = sessions::start_session(&f.sessions, &f.config, &f.user)
+            .await
+            .unwrap();
+        let other = sessions::start_session(&f.sessions, &f.config, &f.user)
+            .await
+            .unwrap();
+        let auth = verify_token(&f.config, &mine.access_token).unwrap();
+
+        let err = change(
+            &f.users,
+            &f.sessions,
+            &f.config,
+            &auth,
+            "not-my-password",
+            "new-password",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "wrong_password");
+
+        change(
+            &f.users,
+            &f.sessions,
+            &f.config,
+            &auth,
+            "password123",
+            "new-password",
+        )
+        .await
+        .unwrap();
+        let stored = f
+            .users
+            .get_user_by_id(f.user.user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(verify("new-password", &stored.password_hash).unwrap());
+        assert!(stored.updated_at >= f.user.updated_at);
+
+        assert!(sessions::check_active(&f.sessions, auth.session_id)
+            .await
+            .is_ok());
+        let other = verify_token(&f.config, &other.access_token).unwrap();
+        assert!(sessions::check_active(&f.sessions, other.session_id)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::ApiError;
+use crate::middleware::auth::AuthToken;
+use crate::users::Role;
+use uuid::Uuid;
+
+/// Something a caller wants to do that not every caller may.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    ListUsers,
+    DeleteUser {
+        user_id: Uuid,
+    },
+    ManageRoles,
+    /// `require_verified` comes from `auth.require_verified_email_for_events`.
+    CreateEvent {
+        require_verified: bool,
+    },
+    /// Edit or delete an event; owners may, and admins moderate.
+    ModifyEvent {
+        creator_id: Uuid,
+    },
+}
+
+/// The one place that decides who may do what. Handlers call this rather than
+/// comparing roles or ids themselves.
+pub fn authorize(actor: &AuthToken, action: Action) -> Result<(), ApiError> {
+    let allowed = match action {
+        Action::CreateEvent { require_verified } if require_verified && !actor.email_verified => {
+            return Err(ApiError::forbidden(
+                "email_unverified",
+                "Verify your email address first",
+            ))
+        }
+        Action::ListUsers | Action::ManageRoles => actor.role >= Role::Admin,
+        Action::DeleteUser { user_id } => actor.user_id == user_id || actor.role >= Role::Admin,
+        Action::CreateEvent { .. } => true,
+        Action::ModifyEvent { creator_id } => {
+            actor.user_id == creator_id || actor.role >= Role::Admin
+        }
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(ApiError::forbidden(
+            "not_allowed",
+            "You are not allowed to do this",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATE_EVENT: Action = Action::CreateEvent {
+        require_verified: true,
+    };
+
+    fn actor(role: Role) -> AuthToken {
+        AuthToken {
+            user_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            role,
+            email_verified: true,
+        }
+    }
+
+    #[test]
+    fn test_only_admins_list_users_and_manage_roles() {
+        assert!(authorize(&actor(Role::Member), Action::ListUsers).is_err());
+        assert!(authorize(&actor(Role::Organizer), Action::ManageRoles).is_err());
+        assert!(authorize(&actor(Role::Admin), Action::ListUsers).is_ok());
+        assert!(authorize(&actor(Role::Admin), Action::ManageRoles).is_ok());
+    }
+
+    #[test]
+    fn test_users_delete_themselves_admins_anyone() {
+        let member = actor(Role::Member);
+        let other = Uuid::new_v4();
+        assert!(authorize(
+            &member,
+            Action::DeleteUser {
+                user_id: member.user_id
+            }
+        )
+        .is_ok());
+        assert!(authorize(&member, Action::DeleteUser { user_id: other }).is_err());
+        assert!(authorize(&actor(Role::Admin), Action::DeleteUser { user_id: other }).is_ok());
+    }
+
+    #[test]
+    fn test_events_are_modified_by_owner_or_admins() {
+        let member = actor(Role::Member);
+        let creator_id = Uuid::new_v4();
+        assert!(authorize(&member, CREATE_EVENT).is_ok());
+        assert!(authorize(&member, Action::ModifyEvent { creator_id }).is_err());
+        assert!(authorize(
+            &member,
+            Action::ModifyEvent {
+                creator_id: member.user_id
+            }
+        )
+        .is_ok());
+        assert!(authorize(&actor(Role::Organizer), Action::ModifyEvent { creator_id }).is_err());
+        assert!(authorize(&actor(Role::Admin), Action::ModifyEvent { creator_id }).is_ok());
+    }
+
+    #[test]
+    fn test_unverified_users_create_events_only_if_allowed() {
+        let mut member = actor(Role::Member);
+        member.email_verified = false;
+        let err = authorize(&member, CREATE_EVENT).unwrap_err();
+        assert_eq!(err.code(), "email_unverified");
+        assert!(authorize(
+            &member,
+            Action::CreateEvent {
+                require_verified: false
+            }
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,595 @@
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Weekday};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Periods (days, weeks, months or years) scanned before expansion gives up;
+/// bounds the work for rules that match rarely or never.
+const MAX_PERIODS: i64 = 100_000;
+
+/// The largest `INTERVAL` accepted; anything wider than a thousand years
+/// only ever yields its first occurrence anyway.
+const MAX_INTERVAL: i64 = 1000;
+
+/// How an event repeats: an RFC 5545 `RRULE` and the `EXDATE`s taken out of
+/// it. Occurrences repeat in UTC, since events carry no time zone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recurrence {
+    pub rrule: String,
+    #[serde(default)]
+    pub exdates: Vec<i64>,
+}
+
+impl Recurrence {
+    /// The recurrence as iCalendar content lines, the way it is stored.
+    pub fn to_ical(&self) -> String {
+        let mut ical = format!("RRULE:{}", self.rrule);
+        if !self.exdates.is_empty() {
+            let dates: Vec<String> = self.exdates.iter().map(|&t| format_utc(t)).collect();
+            ical.push_str("\r\nEXDATE:");
+            ical.push_str(&dates.join(","));
+        }
+        ical
+    }
+
+    pub fn from_ical(ical: &str) -> Result<Recurrence, String> {
+        let mut rrule = None;
+        let mut exdates = Vec::new();
+        for line in ical.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(rule) = line.strip_prefix("RRULE:") {
+                rrule = Some(rule.to_string());
+            } else if let Some(dates) = line.strip_prefix("EXDATE:") {
+                for date in dates.split(',') {
+                    exdates.push(parse_utc(date)?);
+                }
+            } else {
+                return Err(format!("unexpected line {}", line));
+            }
+        }
+        Ok(Recurrence {
+            rrule: rrule.ok_or("no RRULE line")?,
+            exdates,
+        })
+    }
+
+    /// Starts of the occurrences of a series beginning at `dtstart`, in order
+    /// and without the `EXDATE`s.
+    pub fn starts(&self, dtstart: i64) -> Result<impl Iterator<Item = i64> + '_, String> {
+        let rule = Rule::parse(&self.rrule)?;
+        Ok(rule
+            .starts(dtstart)
+            .filter(move |start| !self.exdates.contains(start)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// The part of RFC 5545 `RRULE` supported here: `FREQ` of `DAILY` to
+/// `YEARLY` with `INTERVAL`, `COUNT` or `UNTIL`, `BYDAY` (with ordinals such
+/// as `-1FR` for monthly rules) and `BYMONTHDAY` for monthly rules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    frequency: Frequency,
+    interval: i64,
+    pub count: Option<u32>,
+    pub until: Option<i64>,
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+}
+
+impl Rule {
+    pub fn parse(rrule: &str) -> Result<Rule, String> {
+        let rrule = rrule.trim();
+        let rrule = rrule.strip_prefix("RRULE:").unwrap_or(rrule);
+        let mut frequency = None;
+        let mut rule = Rule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+        };
+        for part in rrule.split(';').filter(|p| !p.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not NAME=VALUE", part))?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => return Err(format!("FREQ={} is not supported", other)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|i| (1..=MAX_INTERVAL).contains(i))
+                        .ok_or(format!("INTERVAL must be within 1..{}", MAX_INTERVAL))?
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&c| c > 0)
+                            .ok_or("COUNT must be a positive number")?,
+                    )
+                }
+                "UNTIL" => rule.until = Some(parse_until(value)?),
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        rule.by_day.push(parse_by_day(day)?);
+                    }
+                }
+                "BYMONTHDAY" => {
+                    for day in value.split(',') {
+                        let day: i32 = day
+                            .parse()
+                            .ok()
+                            .filter(|d: &i32| (1..=31).contains(&d.abs()))
+                            .ok_or("BYMONTHDAY must be within 1..31 or -31..-1")?;
+                        rule.by_month_day.push(day);
+                    }
+                }
+                "WKST" if value.eq_ignore_ascii_case("MO") => {}
+                other => return Err(format!("{} is not supported", other)),
+            }
+        }
+        rule.frequency = frequency.ok_or("FREQ is required")?;
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("COUNT and UNTIL cannot both be set".to_string());
+        }
+        if rule.frequency != Frequency::Monthly {
+            if !rule.by_month_day.is_empty() {
+                return Err("BYMONTHDAY is only supported with FREQ=MONTHLY".to_string());
+            }
+            if rule.by_day.iter().any(|(ordinal, _)| ordinal.is_some()) {
+                return Err("BYDAY ordinals are only supported with FREQ=MONTHLY".to_string());
+            }
+        }
+        if rule.frequency == Frequency::Yearly && !rule.by_day.is_empty() {
+            return Err("BYDAY is not supported with FREQ=YEARLY".to_string());
+        }
+        Ok(rule)
+    }
+
+    /// Occurrence starts of a series beginning at `dtstart`, generated lazily.
+    /// Candidates before `dtstart` are skipped, so a `dtstart` the rule does
+    /// not match is not itself an occurrence.
+    pub fn starts(&self, dtstart: i64) -> Starts {
+        Starts {
+            rule: self.clone(),
+            dtstart: to_naive(dtstart),
+            period: 0,
+            pending: VecDeque::new(),
+            generated: 0,
+            done: false,
+        }
+    }
+
+    /// How many occurrences come before `start`. `COUNT` counts these, before
+    /// any `EXDATE` is taken out.
+    pub fn count_before(&self, dtstart: i64, start: i64) -> u32 {
+        self.starts(dtstart).take_while(|&s| s < start).count() as u32
+    }
+
+    /// The same rule ending just before `start`.
+    pub fn ending_before(&self, dtstart: i64, start: i64) -> Rule {
+        let mut rule = self.clone();
+        if rule.count.is_some() {
+            rule.count = Some(self.count_before(dtstart, start));
+        } else {
+            rule.until = Some(start - 1000);
+        }
+        rule
+    }
+
+    /// The same rule for the part of the series from `start` on.
+    pub fn continuing_from(&self, dtstart: i64, start: i64) -> Rule {
+        let mut rule = self.clone();
+        if let Some(count) = rule.count {
+            rule.count = Some(count.saturating_sub(self.count_before(dtstart, start)));
+        }
+        rule
+    }
+
+    /// The candidate starts of one period, in order, or `None` once the
+    /// period lies past the dates chrono can represent.
+    fn period(&self, dtstart: NaiveDateTime, period: i64) -> Option<Vec<NaiveDateTime>> {
+        let time = dtstart.time();
+        let start = dtstart.date();
+        let step = period.checked_mul(self.interval)?;
+        let mut dates: Vec<NaiveDate> = match self.frequency {
+            Frequency::Daily => {
+                let date = start.checked_add_signed(TimeDelta::try_days(step)?)?;
+                if self.by_day.is_empty() || self.by_day.iter().any(|(_, d)| *d == date.weekday()) {
+                    vec![date]
+                } else {
+                    vec![]
+                }
+            }
+            Frequency::Weekly => {
+                let weekday = start.weekday().num_days_from_monday() as i64;
+                let week = start
+                    .checked_add_signed(TimeDelta::try_weeks(step)?)?
+                    .checked_sub_signed(TimeDelta::try_days(weekday)?)?;
+                let days: Vec<i64> = if self.by_day.is_empty() {
+                    vec![weekday]
+                } else {
+                    self.by_day
+                        .iter()
+                        .map(|(_, day)| day.num_days_from_monday() as i64)
+                        .collect()
+                };
+                days.into_iter()
+                    .filter_map(|d| week.checked_add_signed(TimeDelta::try_days(d)?))
+                    .collect()
+            }
+            Frequency::Monthly => {
+                let months =
+                    (start.year() as i64 * 12 + start.month0() as i64).checked_add(step)?;
+                let year = i32::try_from(months.div_euclid(12)).ok()?;
+                let month = months.rem_euclid(12) as u32 + 1;
+                NaiveDate::from_ymd_opt(year, month, 1)?;
+                self.month_days(year, month, start.day())
+            }
+            Frequency::Yearly => {
+                let year = i32::try_from(step).ok()?.checked_add(start.year())?;
+                NaiveDate::from_ymd_opt(year, 1, 1)?;
+                NaiveDate::from_ymd_opt(year, start.month(), start.day())
+                    .into_iter()
+                    .collect()
+            }
+        };
+        dates.sort();
+        dates.dedup();
+        Some(dates.into_iter().map(|date| date.and_time(time)).collect())
+    }
+
+    fn month_days(&self, year: i32, month: u32, default_day: u32) -> Vec<NaiveDate> {
+        let Some(first) = NaiveDate::from_ymd_opt(year, month, 1) else {
+            return Vec::new();
+        };
+        let length = days_in_month(first);
+        let day = |d: i32| {
+            let d = if d < 0 { length as i32 + 1 + d } else { d };
+            (1..=length as i32)
+                .contains(&d)
+                .then(|| first + TimeDelta::days(d as i64 - 1))
+        };
+        if !self.by_month_day.is_empty() {
+            return self.by_month_day.iter().filter_map(|&d| day(d)).collect();
+        }
+        if self.by_day.is_empty() {
+            return day(default_day as i32).into_iter().collect();
+        }
+        let mut dates = Vec::new();
+        for &(ordinal, weekday) in &self.by_day {
+            let matching: Vec<NaiveDate> = (1..=length as i32)
+                .filter_map(day)
+                .filter(|date| date.weekday() == weekday)
+                .collect();
+            match ordinal {
+                None => dates.extend(matching),
+                Some(n) if n > 0 => dates.extend(matching.get(n as usize - 1)),
+                Some(n) => dates.extend(
+                    matching
+                        .len()
+                        .checked_sub(n.unsigned_abs() as usize)
+                        .map(|i| matching[i]),
+                ),
+            }
+        }
+        dates
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self.frequency {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        };
+        write!(f, "FREQ={}", frequency)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", format_utc(until))?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self
+                .by_day
+                .iter()
+                .map(|(ordinal, day)| {
+                    let ordinal = ordinal.map(|n| n.to_string()).unwrap_or_default();
+                    format!("{}{}", ordinal, weekday_code(*day))
+                })
+                .collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if !self.by_month_day.is_empty() {
+            let days: Vec<String> = self.by_month_day.iter().map(i32::to_string).collect();
+            write!(f, ";BYMONTHDAY={}", days.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// The lazy expansion of a `Rule`, one period at a time.
+pub struct Starts {
+    rule: Rule,
+    dtstart: NaiveDateTime,
+    period: i64,
+    pending: VecDeque<NaiveDateTime>,
+    generated: u32,
+    done: bool,
+}
+
+impl Iterator for Starts {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(candidate) = self.pending.pop_front() {
+                if candidate < self.dtstart {
+                    continue;
+                }
+                let start = candidate.and_utc().timestamp_millis();
+                let past_until = self.rule.until.is_some_and(|until| start > until);
+                let counted_out = self.rule.count.is_some_and(|c| self.generated >= c);
+                if past_until || counted_out {
+                    self.done = true;
+                    return None;
+                }
+                self.generated += 1;
+                return Some(start);
+            }
+            if self.period >= MAX_PERIODS {
+                self.done = true;
+                return None;
+            }
+            let Some(candidates) = self.rule.period(self.dtstart, self.period) else {
+                self.done = true;
+                return None;
+            };
+            self.pending = candidates.into();
+            self.period += 1;
+        }
+    }
+}
+
+fn days_in_month(first: NaiveDate) -> u32 {
+    let next = if first.month() == 12 {
+        NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1)
+    };
+    next.map(|next| (next - first).num_days() as u32)
+        .unwrap_or(31)
+}
+
+fn parse_by_day(value: &str) -> Result<(Option<i32>, Weekday), String> {
+    let value = value.trim().to_ascii_uppercase();
+    if value.len() < 2 {
+        return Err(format!("BYDAY value {} is not a day", value));
+    }
+    let (ordinal, code) = value.split_at(value.len() - 2);
+    let day = match code {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return Err(format!("BYDAY value {} is not a day", value)),
+    };
+    let ordinal = match ordinal {
+        "" => None,
+        n => Some(
+            n.trim_start_matches('+')
+                .parse()
+                .ok()
+                .filter(|n: &i32| (1..=5).contains(&n.abs()))
+                .ok_or_else(|| format!("BYDAY ordinal {} must be within 1..5 or -5..-1", n))?,
+        ),
+    };
+    Ok((ordinal, day))
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+/// `UNTIL` as a UTC date-time, or a date meaning through the end of that day.
+fn parse_until(value: &str) -> Result<i64, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        let end = date.and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap_or_default());
+        return Ok(end.and_utc().timestamp_millis());
+    }
+    parse_utc(value)
+}
+
+fn parse_utc(value: &str) -> Result<i64, String> {
+    NaiveDateTime::parse_from_str(value.trim(), "%Y%m%dT%H%M%SZ")
+        .map(|t| t.and_utc().timestamp_millis())
+        .map_err(|_| format!("{} is not a UTC date-time like 20240903T170000Z", value))
+}
+
+fn format_utc(millis: i64) -> String {
+    to_naive(millis).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn to_naive(millis: i64) -> NaiveDateTime {
+    DateTime::from_timestamp_millis(millis)
+        .map(|t| t.naive_utc())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    fn expand(rrule: &str, dtstart: &str, n: usize) -> Vec<String> {
+        Rule::parse(rrule)
+            .unwrap()
+            .starts(at(dtstart))
+            .take(n)
+            .map(format_utc)
+            .collect()
+    }
+
+    #[test]
+    fn test_weekly_on_several_days() {
+        // Tuesday 2024-09-03
+        assert_eq!(
+            expand("FREQ=WEEKLY;BYDAY=TU,TH", "2024-09-03T17:00:00Z", 5),
+            vec![
+                "20240903T170000Z",
+                "20240905T170000Z",
+                "20240910T170000Z",
+                "20240912T170000Z",
+                "20240917T170000Z"
+            ]
+        );
+        assert_eq!(
+            expand("FREQ=WEEKLY;INTERVAL=2", "2024-09-03T17:00:00Z", 3),
+            vec!["20240903T170000Z", "20240917T170000Z", "20241001T170000Z"]
+        );
+    }
+
+    #[test]
+    fn test_monthly_rules() {
+        // last Friday of each month
+        assert_eq!(
+            expand("FREQ=MONTHLY;BYDAY=-1FR", "2024-09-01T18:00:00Z", 3),
+            vec!["20240927T180000Z", "20241025T180000Z", "20241129T180000Z"]
+        );
+        // the 31st only exists in some months
+        assert_eq!(
+            expand("FREQ=MONTHLY", "2024-01-31T12:00:00Z", 3),
+            vec!["20240131T120000Z", "20240331T120000Z", "20240531T120000Z"]
+        );
+        assert_eq!(
+            expand("FREQ=MONTHLY;BYMONTHDAY=1,-1", "2024-02-01T09:00:00Z", 3),
+            vec!["20240201T090000Z", "20240229T090000Z", "20240301T090000Z"]
+        );
+    }
+
+    #[test]
+    fn test_count_and_until_end_the_series() {
+        assert_eq!(
+            expand("FREQ=DAILY;COUNT=3", "2024-09-03T17:00:00Z", 10).len(),
+            3
+        );
+        assert_eq!(
+            expand(
+                "FREQ=DAILY;UNTIL=20240905T170000Z",
+                "2024-09-03T17:00:00Z",
+                10
+            ),
+            vec!["20240903T170000Z", "20240904T170000Z", "20240905T170000Z"]
+        );
+        assert_eq!(
+            expand("FREQ=YEARLY;UNTIL=20300101", "2024-02-29T10:00:00Z", 10),
+            vec!["20240229T100000Z", "20280229T100000Z"]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_or_invalid_rules_are_rejected() {
+        for rrule in [
+            "",
+            "FREQ=HOURLY",
+            "FREQ=WEEKLY;BYSETPOS=1",
+            "FREQ=DAILY;COUNT=2;UNTIL=20240101",
+            "FREQ=WEEKLY;BYDAY=1MO",
+            "FREQ=WEEKLY;INTERVAL=0",
+            "FREQ=DAILY;INTERVAL=1000000000",
+            "FREQ=WEEKLY;INTERVAL=9223372036854775807",
+            "FREQ=MONTHLY;BYDAY=XX",
+        ] {
+            assert!(Rule::parse(rrule).is_err(), "{}", rrule);
+        }
+        let rule = Rule::parse("RRULE:FREQ=monthly;BYDAY=+2TU;COUNT=4").unwrap();
+        assert_eq!(rule.to_string(), "FREQ=MONTHLY;COUNT=4;BYDAY=2TU");
+    }
+
+    #[test]
+    fn test_wide_intervals_run_out_of_calendar_without_overflow() {
+        let widest = format!("INTERVAL={}", MAX_INTERVAL);
+        for frequency in ["DAILY", "WEEKLY", "MONTHLY", "YEARLY"] {
+            let rrule = format!("FREQ={};{}", frequency, widest);
+            let starts = expand(&rrule, "2024-09-03T17:00:00Z", 1_000_000);
+            assert!(!starts.is_empty() && starts.len() < 1_000_000, "{}", rrule);
+        }
+    }
+
+    #[test]
+    fn test_exdates_and_ical_round_trip() {
+        let recurrence = Recurrence {
+            rrule: "FREQ=WEEKLY;COUNT=4".to_string(),
+            exdates: vec![at("2024-09-10T17:00:00Z")],
+        };
+        let starts: Vec<String> = recurrence
+            .starts(at("2024-09-03T17:00:00Z"))
+            .unwrap()
+            .map(format_utc)
+            .collect();
+        // COUNT is reached before the EXDATE is taken out
+        assert_eq!(
+            starts,
+            vec!["20240903T170000Z", "20240917T170000Z", "20240924T170000Z"]
+        );
+        let ical = recurrence.to_ical();
+        assert_eq!(ical, "RRULE:FREQ=WEEKLY;COUNT=4\r\nEXDATE:20240910T170000Z");
+        assert_eq!(Recurrence::from_ical(&ical).unwrap(), recurrence);
+    }
+
+    #[test]
+    fn test_splitting_a_series() {
+        let dtstart = at("2024-09-03T17:00:00Z");
+        let split = at("2024-09-17T17:00:00Z");
+        let rule = Rule::parse("FREQ=WEEKLY;COUNT=5").unwrap();
+        assert_eq!(rule.ending_before(dtstart, split).count, Some(2));
+        assert_eq!(rule.continuing_from(dtstart, split).count, Some(3));
+
+        let open = Rule::parse("FREQ=WEEKLY").unwrap();
+        let head: Vec<i64> = open.ending_before(dtstart, split).starts(dtstart).collect();
+        assert_eq!(head.len(), 2);
+    }
+}
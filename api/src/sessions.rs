@@ -0,0 +1,485 @@
+use crate::config::Config;
+use crate::crypto::{constant_time_eq, random_token, sha256_hex};
+use crate::error::ApiError;
+use crate::middleware::auth::AuthToken;
+use crate::middleware::client::ClientInfo;
+use crate::store::{Sessions, Users};
+use crate::users::{generate_token, User};
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use rocket::{get, post};
+use uuid::Uuid;
+
+/// A login session. The refresh token is `<session_id>.<secret>`; only the
+/// SHA-256 of the secret is stored.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub refresh_hash: String,
+    /// The hash `refresh_hash` replaced at the last rotation, to recognize a
+    /// replayed token.
+    pub previous_refresh_hash: Option<String>,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub revoked: bool,
+}
+
+impl Session {
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.revoked && self.expires_at > now
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TokenPair {
+    /// The session the tokens belong to; already inside both, so not sent.
+    #[serde(skip)]
+    pub session_id: Uuid,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Seconds until `access_token` expires.
+    pub expires_in: i64,
+}
+
+/// One entry of a user's login history.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginRecord {
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub entry_id: Uuid,
+    pub logged_in_at: i64,
+    /// The session the login opened; `None` if it failed.
+    pub session_id: Option<Uuid>,
+    /// Empty when unknown.
+    pub ip: String,
+    pub user_agent: String,
+    /// `success`, or why the login failed.
+    pub outcome: String,
+}
+
+/// A live session as its owner sees it. Where it came from is looked up in
+/// the recent login history, so very old sessions may lack it.
+#[derive(Debug, Serialize)]
+pub struct ActiveSession {
+    pub session_id: Uuid,
+    pub created_at: i64,
+    pub expires_at: i64,
+    /// Whether this is the session making the request.
+    pub current: bool,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Body of `GET /users/me/sessions`.
+#[derive(Debug, Serialize)]
+pub struct Activity {
+    pub sessions: Vec<ActiveSession>,
+    pub recent_logins: Vec<LoginRecord>,
+}
+
+/// How much login history `GET /users/me/sessions` shows.
+const RECENT_LOGINS: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+fn refresh_token(session_id: Uuid, secret: &str) -> String {
+    format!("{}.{}", session_id, secret)
+}
+
+fn invalid_refresh() -> ApiError {
+    ApiError::unauthorized("invalid_refresh_token", "Refresh token is invalid")
+}
+
+/// Opens a session for a user whose credentials were just checked.
+pub async fn start_session(
+    sessions: &Sessions,
+    config: &Config,
+    user: &User,
+) -> Result<TokenPair, ApiError> {
+    let now = Utc::now().timestamp_millis();
+    let secret = random_token(32);
+    let session = Session {
+        session_id: Uuid::new_v4(),
+        user_id: user.user_id,
+        refresh_hash: sha256_hex(secret.as_bytes()),
+        previous_refresh_hash: None,
+        created_at: now,
+        expires_at: now + config.auth.refresh_lifetime_secs * 1000,
+        revoked: false,
+    };
+    sessions.insert_session(&session).await?;
+
+    Ok(TokenPair {
+        session_id: session.session_id,
+        access_token: generate_token(config, user, session.session_id)?,
+        refresh_token: refresh_token(session.session_id, &secret),
+        expires_in: config.auth.token_lifetime_secs,
+    })
+}
+
+/// Exchanges a refresh token for a new pair. The refresh token rotates on
+/// every use; presenting the one it rotated out revokes the whole session,
+/// since it means two parties hold it. Any other wrong secret is only
+/// refused: the session id is no secret, so anyone could send one.
+pub async fn refresh(
+    users: &Users,
+    sessions: &Sessions,
+    config: &Config,
+    token: &str,
+) -> Result<TokenPair, ApiError> {
+    let (session_id, secret) = token.split_once('.').ok_or_else(invalid_refresh)?;
+    let session_id = Uuid::parse_str(session_id).map_err(|_| invalid_refresh())?;
+
+    let session = sessions
+        .get_session(session_id)
+        .await?
+        .ok_or_else(invalid_refresh)?;
+    if !session.is_active(Utc::now().timestamp_millis()) {
+        return Err(invalid_refresh());
+    }
+    let presented = sha256_hex(secret.as_bytes());
+    if !constant_time_eq(&presented, &session.refresh_hash) {
+        let reused = session
+            .previous_refresh_hash
+            .as_deref()
+            .is_some_and(|previous| constant_time_eq(&presented, previous));
+        if reused {
+            eprintln!(
+                "Refresh token reuse on session {}, revoking it",
+                session.session_id
+            );
+            sessions.revoke_session(&session).await?;
+        }
+        return Err(invalid_refresh());
+    }
+
+    let user = users
+        .get_user_by_id(session.user_id)
+        .await?
+        .ok_or_else(invalid_refresh)?;
+
+    let new_secret = random_token(32);
+    if !sessions
+        .rotate_refresh(&session, &sha256_hex(new_secret.as_bytes()))
+        .await?
+    {
+        // lost a race with another refresh of the same token
+        return Err(invalid_refresh());
+    }
+
+    Ok(TokenPair {
+        session_id: session.session_id,
+        access_token: generate_token(config, &user, session.session_id)?,
+        refresh_token: refresh_token(session.session_id, &new_secret),
+        expires_in: config.auth.token_lifetime_secs,
+    })
+}
+
+/// Fails unless the session behind an access token is still live.
+pub async fn check_active(sessions: &Sessions, session_id: Uuid) -> Result<(), ApiError> {
+    match sessions.get_session(session_id).await? {
+        Some(session) if session.is_active(Utc::now().timestamp_millis()) => Ok(()),
+        _ => Err(ApiError::unauthorized(
+            "session_revoked",
+            "Session has ended, please log in again",
+        )),
+    }
+}
+
+pub async fn revoke(sessions: &Sessions, session_id: Uuid) -> Result<(), ApiError> {
+    if let Some(session) = sessions.get_session(session_id).await? {
+        sessions.revoke_session(&session).await?;
+    }
+    Ok(())
+}
+
+/// Appends a login to the user's history.
+pub async fn record_login(
+    sessions: &Sessions,
+    config: &Config,
+    user_id: Uuid,
+    session_id: Option<Uuid>,
+    client: &ClientInfo,
+    outcome: &str,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp_millis();
+    let record = LoginRecord {
+        user_id,
+        entry_id: Uuid::new_v4(),
+        logged_in_at: now,
+        session_id,
+        ip: client.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        user_agent: client.user_agent.clone(),
+        outcome: outcome.to_string(),
+    };
+    sessions
+        .insert_login(
+            &record,
+            now + config.login.audit_retention_days * 24 * 3600 * 1000,
+        )
+        .await?;
+    Ok(())
+}
+
+/// The caller's live sessions, newest first, and their recent logins.
+pub async fn activity(sessions: &Sessions, auth: &AuthToken) -> Result<Activity, ApiError> {
+    let now = Utc::now().timestamp_millis();
+    let recent_logins = sessions.login_history(auth.user_id, RECENT_LOGINS).await?;
+
+    let mut live: Vec<ActiveSession> = sessions
+        .sessions_for_user(auth.user_id)
+        .await?
+        .into_iter()
+        .filter(|session| session.is_active(now))
+        .map(|session| {
+            let origin = recent_logins
+                .iter()
+                .find(|record| record.session_id == Some(session.session_id));
+            ActiveSession {
+                session_id: session.session_id,
+                created_at: session.created_at,
+                expires_at: session.expires_at,
+                current: session.session_id == auth.session_id,
+                ip: origin.map(|record| record.ip.clone()),
+                user_agent: origin.map(|record| record.user_agent.clone()),
+            }
+        })
+        .collect();
+    live.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+
+    Ok(Activity {
+        sessions: live,
+        recent_logins,
+    })
+}
+
+/// Revokes every live session of a user except `keep`, e.g. the one that just
+/// changed the password.
+pub async fn revoke_all(
+    sessions: &Sessions,
+    user_id: Uuid,
+    keep: Option<Uuid>,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp_millis();
+    for session in sessions.sessions_for_user(user_id).await? {
+        if session.is_active(now) && Some(session.session_id) != keep {
+            sessions.revoke_session(&session).await?;
+        }
+    }
+    Ok(())
+}
+
+#[post("/token/refresh", data = "<request>")]
+pub async fn frontend_refresh_token(
+    users: &State<Users>,
+    sessions: &State<Sessions>,
+    config: &State<Config>,
+    request: Json<RefreshRequest>,
+) -> Result<Json<TokenPair>, ApiError> {
+    Ok(Json(
+        refresh(users, sessions, config, &request.refresh_token).await?,
+    ))
+}
+
+/// Lets users see where they are logged in and spot logins that were not them.
+#[get("/users/me/sessions")]
+pub async fn my_sessions(
+    sessions: &State<Sessions>,
+    auth: AuthToken,
+) -> Result<Json<Activity>, ApiError> {
+    Ok(Json(activity(sessions, &auth).await?))
+}
+
+/// Ends the caller's session; both its access and refresh token stop working.
+#[post("/logout")]
+pub async fn logout(sessions: &State<Sessions>, auth: AuthToken) -> Result<Status, ApiError> {
+    revoke(sessions, auth.session_id).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::auth::verify_token;
+    use crate::store::{Attempts, MemoryStore, Mfa};
+    use crate::users::{create_user, login, LoginOutcome, Role, UserLogin};
+    use std::sync::Arc;
+
+    async fn setup() -> (Users, Sessions, Config, User) {
+        let store = Arc::new(MemoryStore::default());
+        let users: Users = store.clone();
+        let sessions: Sessions = store;
+        let config = Config::for_tests();
+        let user = User {
+            user_id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: "testuserSESSION@example.com".to_string(),
+            password_hash: "password123".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            last_login: 0,
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
+        };
+        let user = create_user(&users, &config, user).await.unwrap();
+        (users, sessions, config, user)
+    }
+
+    fn session_id(pair: &TokenPair) -> Uuid {
+        Uuid::parse_str(pair.refresh_token.split_once('.').unwrap().0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_token() {
+        let (users, sessions, config, user) = setup().await;
+        let first = start_session(&sessions, &config, &user).await.unwrap();
+
+        let second = refresh(&users, &sessions, &config, &first.refresh_token)
+            .await
+            .unwrap();
+        assert_ne!(first.refresh_token, second.refresh_token);
+        assert_eq!(session_id(&first), session_id(&second));
+        assert!(check_active(&sessions, session_id(&second)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_reuse_revokes_session() {
+        let (users, sessions, config, user) = setup().await;
+        let first = start_session(&sessions, &config, &user).await.unwrap();
+        let second = refresh(&users, &sessions, &config, &first.refresh_token)
+            .await
+            .unwrap();
+
+        // replaying the old token kills the session, including the new token
+        assert!(refresh(&users, &sessions, &config, &first.refresh_token)
+            .await
+            .is_err());
+        assert!(refresh(&users, &sessions, &config, &second.refresh_token)
+            .await
+            .is_err());
+        assert!(check_active(&sessions, session_id(&first)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_secret_leaves_session_alone() {
+        let (users, sessions, config, user) = setup().await;
+        let first = start_session(&sessions, &config, &user).await.unwrap();
+        let second = refresh(&users, &sessions, &config, &first.refresh_token)
+            .await
+            .unwrap();
+
+        // the session id is public; a made-up secret must not log anyone out
+        let forged = format!("{}.garbage", session_id(&second));
+        let err = refresh(&users, &sessions, &config, &forged)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_refresh_token");
+        assert!(check_active(&sessions, session_id(&second)).await.is_ok());
+        assert!(refresh(&users, &sessions, &config, &second.refresh_token)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_ends_session() {
+        let (users, sessions, config, user) = setup().await;
+        let pair = start_session(&sessions, &config, &user).await.unwrap();
+
+        revoke(&sessions, session_id(&pair)).await.unwrap();
+
+        assert!(check_active(&sessions, session_id(&pair)).await.is_err());
+        assert!(refresh(&users, &sessions, &config, &pair.refresh_token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rejects_garbage() {
+        let (users, sessions, config, _) = setup().await;
+        for token in [
+            "",
+            "abc",
+            "not-a-uuid.secret",
+            &format!("{}.x", Uuid::new_v4()),
+        ] {
+            let err = refresh(&users, &sessions, &config, token)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "invalid_refresh_token");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logins_show_up_in_activity() {
+        let (users, sessions, mut config, user) = setup().await;
+        config.login.backoff_base_secs = 0;
+        let attempts: Attempts = Arc::new(MemoryStore::default());
+        let mfa: Mfa = Arc::new(MemoryStore::default());
+        let client = ClientInfo {
+            ip: Some("198.51.100.4".parse().unwrap()),
+            user_agent: "test-agent/1.0".to_string(),
+        };
+        let mut credentials = UserLogin {
+            email: user.email.clone(),
+            password: "wrong-password".to_string(),
+        };
+        assert!(login(
+            &users,
+            &sessions,
+            &attempts,
+            &mfa,
+            &config,
+            &credentials,
+            &client
+        )
+        .await
+        .is_err());
+        credentials.password = "password123".to_string();
+        let outcome = login(
+            &users,
+            &sessions,
+            &attempts,
+            &mfa,
+            &config,
+            &credentials,
+            &client,
+        )
+        .await
+        .unwrap();
+        let LoginOutcome::LoggedIn(logged_in, pair) = outcome else {
+            panic!("no second factor was set up");
+        };
+        let stored = users.get_user_by_id(user.user_id).await.unwrap().unwrap();
+        assert!(logged_in.last_login > 0);
+        assert_eq!(stored.last_login, logged_in.last_login);
+
+        let other = start_session(&sessions, &config, &user).await.unwrap();
+        let auth = verify_token(&config, &pair.access_token).unwrap();
+        let seen = activity(&sessions, &auth).await.unwrap();
+        assert_eq!(seen.sessions.len(), 2);
+        let current = seen.sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(current.session_id, pair.session_id);
+        assert_eq!(current.ip.as_deref(), Some("198.51.100.4"));
+        assert_eq!(current.user_agent.as_deref(), Some("test-agent/1.0"));
+        let mut outcomes: Vec<&str> = seen
+            .recent_logins
+            .iter()
+            .map(|record| record.outcome.as_str())
+            .collect();
+        outcomes.sort();
+        assert_eq!(outcomes, ["success", "wrong_password"]);
+
+        revoke(&sessions, other.session_id).await.unwrap();
+        let seen = activity(&sessions, &auth).await.unwrap();
+        assert_eq!(seen.sessions.len(), 1);
+    }
+}
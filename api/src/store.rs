@@ -0,0 +1,325 @@
+pub mod cassandra;
+pub mod memory;
+pub mod row;
+
+use crate::attendance::{Comment, Rsvp};
+use crate::config::{Backend, Config};
+use crate::db::Db;
+use crate::events::{Event, EventLocation, EventPage, EventQuery};
+use crate::lockout::{FailedLogin, LoginThrottle};
+use crate::mfa::MfaEnrollment;
+use crate::migrations;
+use crate::occurrences::Occurrence;
+use crate::passwords::PasswordReset;
+use crate::sessions::{LoginRecord, Session};
+use crate::users::{Role, User};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::{Build, Orbit, Rocket};
+use row::RowError;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub use self::cassandra::CassandraStore;
+pub use self::memory::MemoryStore;
+
+/// Shared handle to the user store, managed as Rocket state.
+pub type Users = Arc<dyn UserStore>;
+/// Shared handle to the event store, managed as Rocket state.
+pub type Events = Arc<dyn EventStore>;
+/// Shared handle to the session store, managed as Rocket state.
+pub type Sessions = Arc<dyn SessionStore>;
+/// Shared handle to the password reset store, managed as Rocket state.
+pub type Resets = Arc<dyn ResetStore>;
+/// Shared handle to the failed login store, managed as Rocket state.
+pub type Attempts = Arc<dyn AttemptStore>;
+/// Shared handle to the two-factor store, managed as Rocket state.
+pub type Mfa = Arc<dyn MfaStore>;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The backend failed; the message is for logs, not clients.
+    Backend(String),
+    /// A stored row does not match the shape the code expects.
+    Row(RowError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Backend(msg) => write!(f, "storage error: {}", msg),
+            StoreError::Row(e) => write!(f, "schema mismatch: {}", e),
+        }
+    }
+}
+
+impl From<RowError> for StoreError {
+    fn from(e: RowError) -> Self {
+        StoreError::Row(e)
+    }
+}
+
+impl From<cassandra_cpp::Error> for StoreError {
+    fn from(e: cassandra_cpp::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+#[rocket::async_trait]
+pub trait UserStore: Send + Sync {
+    /// Writes a new user unless another account holds `user.email`, in which
+    /// case it returns `false` and writes nothing.
+    async fn insert_user(&self, user: &User) -> Result<bool, StoreError>;
+    async fn email_exists(&self, email: &str) -> Result<bool, StoreError>;
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, StoreError>;
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, StoreError>;
+    async fn get_all_users(&self) -> Result<Vec<User>, StoreError>;
+    async fn delete_user(&self, user_id: Uuid, email: &str) -> Result<(), StoreError>;
+    async fn set_role(&self, user_id: Uuid, role: Role, updated_at: i64) -> Result<(), StoreError>;
+    async fn set_password(
+        &self,
+        user_id: Uuid,
+        password_hash: &str,
+        updated_at: i64,
+    ) -> Result<(), StoreError>;
+    async fn set_email_verified(&self, user_id: Uuid, updated_at: i64) -> Result<(), StoreError>;
+    async fn set_verification_sent(&self, user_id: Uuid, sent_at: i64) -> Result<(), StoreError>;
+    async fn set_last_login(&self, user_id: Uuid, last_login: i64) -> Result<(), StoreError>;
+    /// Moves `user` to `new_email`, marked verified. Returns `false`, changing
+    /// nothing, if another account holds `new_email`.
+    async fn change_email(
+        &self,
+        user: &User,
+        new_email: &str,
+        updated_at: i64,
+    ) -> Result<bool, StoreError>;
+}
+
+#[rocket::async_trait]
+pub trait EventStore: Send + Sync {
+    async fn insert_event(&self, event: &Event) -> Result<(), StoreError>;
+    async fn get_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<Option<Event>, StoreError>;
+    /// Finds an event by id alone, through the `events_by_id` index.
+    async fn get_event_by_id(&self, event_id: Uuid) -> Result<Option<Event>, StoreError>;
+    /// Finds an event in one creator's partition without knowing its start time.
+    async fn get_event_for_creator(
+        &self,
+        creator_id: Uuid,
+        event_id: Uuid,
+    ) -> Result<Option<Event>, StoreError>;
+    /// One page of the events matching `query`, continuing from
+    /// `paging_state` if given.
+    async fn list_events(
+        &self,
+        query: &EventQuery,
+        paging_state: Option<&[u8]>,
+    ) -> Result<EventPage, StoreError>;
+    /// The events stored under one `events_by_location` bucket that start in
+    /// `[from, to)`.
+    async fn events_in_bucket(
+        &self,
+        bucket: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<EventLocation>, StoreError>;
+    /// One creator's recurring series whose first occurrence is before
+    /// `before`, however long ago they started.
+    async fn recurring_events(
+        &self,
+        creator_id: Uuid,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError>;
+    /// The recurring series located in one `events_by_location` bucket whose
+    /// first occurrence is before `before`.
+    async fn recurring_in_bucket(
+        &self,
+        bucket: &str,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError>;
+    /// Replaces `current` with `updated` unless the stored row changed since
+    /// `current` was read, i.e. its `updated_at` moved on. A new `start_time`
+    /// moves the row. Returns `false`, writing nothing, if the row changed.
+    async fn update_event(&self, current: &Event, updated: &Event) -> Result<bool, StoreError>;
+    /// Deletes an event along with the overrides, RSVPs and comments of its
+    /// occurrences.
+    async fn delete_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<(), StoreError>;
+    /// The changed or cancelled occurrences of a recurring event.
+    async fn overrides(&self, event_id: Uuid) -> Result<Vec<Occurrence>, StoreError>;
+    /// Stores an occurrence under its `occurrence_start`, replacing any
+    /// earlier override of it.
+    async fn put_override(&self, occurrence: &Occurrence) -> Result<(), StoreError>;
+    async fn delete_overrides(
+        &self,
+        event_id: Uuid,
+        occurrence_starts: &[i64],
+    ) -> Result<(), StoreError>;
+    /// Stores a user's RSVP to an occurrence, replacing their earlier one.
+    async fn put_rsvp(&self, rsvp: &Rsvp) -> Result<(), StoreError>;
+    async fn rsvps(&self, event_id: Uuid, occurrence_start: i64) -> Result<Vec<Rsvp>, StoreError>;
+    async fn insert_comment(&self, comment: &Comment) -> Result<(), StoreError>;
+    /// The newest comments on an occurrence, newest first.
+    async fn comments(
+        &self,
+        event_id: Uuid,
+        occurrence_start: i64,
+        limit: usize,
+    ) -> Result<Vec<Comment>, StoreError>;
+}
+
+#[rocket::async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn insert_session(&self, session: &Session) -> Result<(), StoreError>;
+    async fn get_session(&self, session_id: Uuid) -> Result<Option<Session>, StoreError>;
+    /// Every stored session of a user, live or not.
+    async fn sessions_for_user(&self, user_id: Uuid) -> Result<Vec<Session>, StoreError>;
+    /// Swaps the refresh token hash only if it is still `session.refresh_hash`,
+    /// so two concurrent refreshes cannot both succeed. The old hash is kept
+    /// as `previous_refresh_hash`.
+    async fn rotate_refresh(&self, session: &Session, new_hash: &str) -> Result<bool, StoreError>;
+    async fn revoke_session(&self, session: &Session) -> Result<(), StoreError>;
+    /// Appends to the user's login history; the backend may drop the entry
+    /// after `expires_at`.
+    async fn insert_login(&self, record: &LoginRecord, expires_at: i64) -> Result<(), StoreError>;
+    /// The most recent entries of a user's login history, newest first.
+    async fn login_history(
+        &self,
+        user_id: Uuid,
+        limit: usize,
+    ) -> Result<Vec<LoginRecord>, StoreError>;
+}
+
+#[rocket::async_trait]
+pub trait ResetStore: Send + Sync {
+    async fn insert_reset(&self, reset: &PasswordReset) -> Result<(), StoreError>;
+    /// Removes the reset and returns it. Of concurrent callers with the same
+    /// hash at most one gets `Some`, which makes reset tokens single-use.
+    async fn take_reset(&self, token_hash: &str) -> Result<Option<PasswordReset>, StoreError>;
+}
+
+#[rocket::async_trait]
+pub trait AttemptStore: Send + Sync {
+    async fn get_throttle(&self, key: &str) -> Result<Option<LoginThrottle>, StoreError>;
+    /// Overwrites the counter; the backend may drop it after `expires_at`.
+    async fn put_throttle(
+        &self,
+        throttle: &LoginThrottle,
+        expires_at: i64,
+    ) -> Result<(), StoreError>;
+    async fn clear_throttle(&self, key: &str) -> Result<(), StoreError>;
+    async fn insert_failed_login(
+        &self,
+        attempt: &FailedLogin,
+        expires_at: i64,
+    ) -> Result<(), StoreError>;
+    /// The most recent failed logins for `email`, newest first.
+    async fn failed_logins(
+        &self,
+        email: &str,
+        limit: usize,
+    ) -> Result<Vec<FailedLogin>, StoreError>;
+}
+
+#[rocket::async_trait]
+pub trait MfaStore: Send + Sync {
+    async fn get_mfa(&self, user_id: Uuid) -> Result<Option<MfaEnrollment>, StoreError>;
+    /// Writes a new, not yet enabled enrollment over any previous one.
+    async fn put_mfa(&self, enrollment: &MfaEnrollment) -> Result<(), StoreError>;
+    /// Enables the enrollment and replaces the user's recovery codes.
+    async fn enable_mfa(
+        &self,
+        user_id: Uuid,
+        enabled_at: i64,
+        recovery_hashes: &[String],
+    ) -> Result<(), StoreError>;
+    /// Removes the enrollment and the recovery codes.
+    async fn delete_mfa(&self, user_id: Uuid) -> Result<(), StoreError>;
+    /// Records `step` as used if it is newer than the last one. Of concurrent
+    /// callers with the same step at most one gets `true`.
+    async fn use_step(&self, user_id: Uuid, step: i64) -> Result<bool, StoreError>;
+    /// Removes the recovery code, returning whether it existed; at most one
+    /// concurrent caller gets `true`.
+    async fn take_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool, StoreError>;
+}
+
+/// Picks the storage backend at ignite from the managed `Config` and manages
+/// the `Users`/`Events`/`Sessions`/`Resets`/`Attempts`/`Mfa` handles.
+///
+/// For Cassandra the schema is checked against the embedded migrations.
+/// Pending ones are applied when `migrate_on_startup` is set; otherwise they
+/// fail ignite, so the app never serves against a schema it does not expect.
+pub struct StoreFairing;
+
+#[rocket::async_trait]
+impl Fairing for StoreFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Storage backend",
+            kind: Kind::Ignite | Kind::Shutdown,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = match rocket.state::<Config>() {
+            Some(config) => config.clone(),
+            None => {
+                eprintln!("StoreFairing needs a managed Config");
+                return Err(rocket);
+            }
+        };
+        match config.storage {
+            Backend::Memory => {
+                let store = Arc::new(MemoryStore::default());
+                Ok(rocket
+                    .manage(store.clone() as Users)
+                    .manage(store.clone() as Events)
+                    .manage(store.clone() as Sessions)
+                    .manage(store.clone() as Resets)
+                    .manage(store.clone() as Attempts)
+                    .manage(store as Mfa))
+            }
+            Backend::Cassandra => match Db::connect(&config.cassandra).await {
+                Ok(db) => {
+                    let mode = if config.migrate_on_startup {
+                        migrations::Mode::Apply
+                    } else {
+                        migrations::Mode::Verify
+                    };
+                    if let Err(e) = migrations::run(&db, &config.cassandra, mode).await {
+                        eprintln!("Schema migration failed: {}", e);
+                        return Err(rocket);
+                    }
+                    let db = Arc::new(db);
+                    let store = Arc::new(CassandraStore::new(db.clone()));
+                    Ok(rocket
+                        .manage(db)
+                        .manage(store.clone() as Users)
+                        .manage(store.clone() as Events)
+                        .manage(store.clone() as Sessions)
+                        .manage(store.clone() as Resets)
+                        .manage(store.clone() as Attempts)
+                        .manage(store as Mfa))
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(rocket)
+                }
+            },
+        }
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        if let Some(db) = rocket.state::<Arc<Db>>() {
+            db.close().await;
+        }
+    }
+}
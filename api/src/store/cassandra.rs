@@ -0,0 +1,1614 @@
+use crate::attendance::{Comment, Rsvp};
+use crate::db::Db;
+use crate::events::{Event, EventLocation, EventPage, EventQuery};
+use crate::lockout::{FailedLogin, LoginThrottle};
+use crate::mfa::MfaEnrollment;
+use crate::occurrences::Occurrence;
+use crate::passwords::PasswordReset;
+use crate::sessions::{LoginRecord, Session};
+use crate::store::row::{column, nullable, FromRow, RowError};
+use crate::store::{
+    AttemptStore, EventStore, MfaStore, ResetStore, SessionStore, StoreError, UserStore,
+};
+use crate::users::{Role, User};
+use cassandra_cpp::{BatchType, BindRustType, LendingIterator, Row, Statement};
+use chrono::Utc;
+use rocket::futures::future::join_all;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const LOCATION_COLUMNS: &str = "location_bucket, event_id, creator_id, title, start_time, lat, lon";
+const EVENT_COLUMNS: &str = "event_id, creator_id, title, description, start_time, end_time, lat, lon, address, created_at, updated_at, recurrence";
+const OVERRIDE_COLUMNS: &str = "event_id, occurrence_start, creator_id, title, description, start_time, end_time, lat, lon, address, cancelled";
+const RSVP_COLUMNS: &str = "event_id, occurrence_start, user_id, rsvp_status, is_host, updated_at";
+const COMMENT_COLUMNS: &str =
+    "event_id, occurrence_start, comment_id, user_id, content, created_at, updated_at";
+
+/// Cassandra-backed implementation of every store trait, sharing one session.
+pub struct CassandraStore {
+    db: Arc<Db>,
+}
+
+impl CassandraStore {
+    pub fn new(db: Arc<Db>) -> Self {
+        CassandraStore { db }
+    }
+
+    fn keyspace(&self) -> &str {
+        self.db.keyspace()
+    }
+
+    async fn session(&self) -> Result<cassandra_cpp::Session, StoreError> {
+        self.db.session().await.map_err(StoreError::Backend)
+    }
+
+    /// An unexecuted insert of every column of `event`.
+    fn event_insert(
+        &self,
+        session: &cassandra_cpp::Session,
+        event: &Event,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "INSERT INTO {ks}.events ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            EVENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event.event_id)?;
+        statement.bind(1, event.creator_id)?;
+        statement.bind(2, event.title.as_str())?;
+        statement.bind(3, event.description.as_str())?;
+        statement.bind(4, event.start_time)?;
+        statement.bind(5, event.end_time)?;
+        statement.bind(6, event.lat)?;
+        statement.bind(7, event.lon)?;
+        statement.bind(8, event.address.as_str())?;
+        statement.bind(9, event.created_at)?;
+        statement.bind(10, event.updated_at)?;
+        bind_recurrence(&mut statement, 11, event)?;
+        Ok(statement)
+    }
+
+    /// An unexecuted upsert of the `events_by_id` row of `event`.
+    fn event_index(
+        &self,
+        session: &cassandra_cpp::Session,
+        event: &Event,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "INSERT INTO {ks}.events_by_id (event_id, creator_id, start_time) VALUES (?, ?, ?)",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event.event_id)?;
+        statement.bind(1, event.creator_id)?;
+        statement.bind(2, event.start_time)?;
+        Ok(statement)
+    }
+
+    /// An unexecuted upsert of an `events_by_location` row.
+    fn location_insert(
+        &self,
+        session: &cassandra_cpp::Session,
+        location: &EventLocation,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "INSERT INTO {ks}.events_by_location ({}) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            LOCATION_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, location.location_bucket.as_str())?;
+        statement.bind(1, location.event_id)?;
+        statement.bind(2, location.creator_id)?;
+        statement.bind(3, location.title.as_str())?;
+        statement.bind(4, location.start_time)?;
+        statement.bind(5, location.lat)?;
+        statement.bind(6, location.lon)?;
+        Ok(statement)
+    }
+
+    /// An unexecuted delete of an `events_by_location` row.
+    fn location_delete(
+        &self,
+        session: &cassandra_cpp::Session,
+        location: &EventLocation,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "DELETE FROM {ks}.events_by_location WHERE location_bucket = ? AND start_time = ? AND event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, location.location_bucket.as_str())?;
+        statement.bind(1, location.start_time)?;
+        statement.bind(2, location.event_id)?;
+        Ok(statement)
+    }
+
+    /// Unexecuted upserts indexing a recurring `event` under its creator and
+    /// its location bucket.
+    fn series_insert(
+        &self,
+        session: &cassandra_cpp::Session,
+        event: &Event,
+    ) -> Result<Vec<Statement>, StoreError> {
+        let query = format!(
+            "INSERT INTO {ks}.recurring_events_by_creator (creator_id, event_id, start_time) VALUES (?, ?, ?)",
+            ks = self.keyspace()
+        );
+        let mut by_creator = session.statement(&query);
+        by_creator.bind(0, event.creator_id)?;
+        by_creator.bind(1, event.event_id)?;
+        by_creator.bind(2, event.start_time)?;
+        let query = format!(
+            "INSERT INTO {ks}.recurring_events_by_location (location_bucket, event_id, creator_id, start_time) VALUES (?, ?, ?, ?)",
+            ks = self.keyspace()
+        );
+        let mut by_location = session.statement(&query);
+        by_location.bind(0, EventLocation::from(event).location_bucket.as_str())?;
+        by_location.bind(1, event.event_id)?;
+        by_location.bind(2, event.creator_id)?;
+        by_location.bind(3, event.start_time)?;
+        Ok(vec![by_creator, by_location])
+    }
+
+    /// An unexecuted delete of `event`'s `recurring_events_by_creator` row.
+    fn series_creator_delete(
+        &self,
+        session: &cassandra_cpp::Session,
+        event: &Event,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "DELETE FROM {ks}.recurring_events_by_creator WHERE creator_id = ? AND event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event.creator_id)?;
+        statement.bind(1, event.event_id)?;
+        Ok(statement)
+    }
+
+    /// An unexecuted delete of `event`'s `recurring_events_by_location` row.
+    fn series_location_delete(
+        &self,
+        session: &cassandra_cpp::Session,
+        event: &Event,
+    ) -> Result<Statement, StoreError> {
+        let query = format!(
+            "DELETE FROM {ks}.recurring_events_by_location WHERE location_bucket = ? AND event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, EventLocation::from(event).location_bucket.as_str())?;
+        statement.bind(1, event.event_id)?;
+        Ok(statement)
+    }
+
+    /// The series one of the `recurring_events_by_*` indexes lists under a
+    /// partition, read through to their events rows. `cql` selects
+    /// `event_id, creator_id, start_time` and `bind` fills in its key.
+    async fn indexed_series(
+        &self,
+        cql: &str,
+        bind: impl Fn(&mut Statement) -> Result<(), StoreError> + Send + Sync,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError> {
+        let session = self.session().await?;
+
+        let mut keys = Vec::new();
+        let mut paging_state: Option<Vec<u8>> = None;
+        loop {
+            let mut statement = session.statement(cql);
+            bind(&mut statement)?;
+            if let Some(state) = &paging_state {
+                statement.set_paging_state_token(state)?;
+            }
+            let result = self.db.execute(statement).await?;
+            let mut iter = result.iter();
+            while let Some(row) = iter.next() {
+                let start_time: i64 = column(&row, "start_time")?;
+                if before.iter().all(|&before| start_time < before) {
+                    let event_id: Uuid = column(&row, "event_id")?;
+                    let creator_id: Uuid = column(&row, "creator_id")?;
+                    keys.push((event_id, creator_id, start_time));
+                }
+            }
+            if !result.has_more_pages() {
+                break;
+            }
+            paging_state = result.paging_state_token()?;
+            if paging_state.is_none() {
+                break;
+            }
+        }
+
+        let reads = keys.into_iter().map(|(event_id, creator_id, start_time)| {
+            self.get_event(event_id, creator_id, start_time)
+        });
+        let mut series = Vec::new();
+        for event in join_all(reads).await {
+            // an index row can outlive its series for the moment between the
+            // two writes of a move; the events row is what counts
+            series.extend(event?.filter(|e| e.recurrence.is_some()));
+        }
+        Ok(series)
+    }
+
+    /// Points `email` at `user_id` unless another account holds it. The LWT is
+    /// what keeps two accounts from ever sharing an address.
+    async fn claim_email(&self, email: &str, user_id: Uuid) -> Result<bool, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.email_index (email, user_id) VALUES (?, ?) IF NOT EXISTS",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, email)?;
+        statement.bind(1, user_id)?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(column(&row, "[applied]")?),
+            None => Ok(false),
+        }
+    }
+
+    /// Deletes an `email_index` row, but only while it still points at
+    /// `user_id`, so a release never frees an address someone else claimed.
+    async fn release_email(&self, email: &str, user_id: Uuid) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.email_index WHERE email = ? IF user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, email)?;
+        statement.bind(1, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+}
+
+impl FromRow for User {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(User {
+            user_id: column(row, "user_id")?,
+            username: column(row, "username")?,
+            email: column(row, "email")?,
+            password_hash: column(row, "password_hash")?,
+            created_at: nullable(row, "created_at")?.unwrap_or_default(),
+            updated_at: nullable(row, "updated_at")?.unwrap_or_default(),
+            // NULL until the first login
+            last_login: nullable(row, "last_login")?.unwrap_or_default(),
+            // rows from before roles existed
+            role: nullable(row, "role")?.unwrap_or_default(),
+            description: nullable(row, "description")?.unwrap_or_default(),
+            interests: nullable(row, "interests")?.unwrap_or_default(),
+            // accounts from before verification existed count as verified
+            email_verified: nullable(row, "email_verified")?.unwrap_or(true),
+            verification_sent_at: nullable(row, "verification_sent_at")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for Event {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Event {
+            event_id: column(row, "event_id")?,
+            creator_id: column(row, "creator_id")?,
+            title: column(row, "title")?,
+            description: nullable(row, "description")?.unwrap_or_default(),
+            start_time: column(row, "start_time")?,
+            end_time: column(row, "end_time")?,
+            lat: column(row, "lat")?,
+            lon: column(row, "lon")?,
+            address: nullable(row, "address")?.unwrap_or_default(),
+            created_at: nullable(row, "created_at")?.unwrap_or_default(),
+            updated_at: nullable(row, "updated_at")?.unwrap_or_default(),
+            recurrence: nullable(row, "recurrence")?,
+        })
+    }
+}
+
+impl FromRow for Occurrence {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Occurrence {
+            event_id: column(row, "event_id")?,
+            occurrence_start: column(row, "occurrence_start")?,
+            creator_id: column(row, "creator_id")?,
+            title: nullable(row, "title")?.unwrap_or_default(),
+            description: nullable(row, "description")?.unwrap_or_default(),
+            start_time: column(row, "start_time")?,
+            end_time: column(row, "end_time")?,
+            lat: column(row, "lat")?,
+            lon: column(row, "lon")?,
+            address: nullable(row, "address")?.unwrap_or_default(),
+            cancelled: nullable(row, "cancelled")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for Rsvp {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Rsvp {
+            event_id: column(row, "event_id")?,
+            occurrence_start: column(row, "occurrence_start")?,
+            user_id: column(row, "user_id")?,
+            status: column(row, "rsvp_status")?,
+            is_host: nullable(row, "is_host")?.unwrap_or_default(),
+            updated_at: nullable(row, "updated_at")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for Comment {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Comment {
+            event_id: column(row, "event_id")?,
+            occurrence_start: column(row, "occurrence_start")?,
+            comment_id: column(row, "comment_id")?,
+            user_id: column(row, "user_id")?,
+            content: nullable(row, "content")?.unwrap_or_default(),
+            created_at: column(row, "created_at")?,
+            updated_at: nullable(row, "updated_at")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for EventLocation {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(EventLocation {
+            location_bucket: column(row, "location_bucket")?,
+            event_id: column(row, "event_id")?,
+            creator_id: column(row, "creator_id")?,
+            title: nullable(row, "title")?.unwrap_or_default(),
+            start_time: column(row, "start_time")?,
+            lat: column(row, "lat")?,
+            lon: column(row, "lon")?,
+        })
+    }
+}
+
+impl FromRow for Session {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(Session {
+            session_id: column(row, "session_id")?,
+            user_id: column(row, "user_id")?,
+            refresh_hash: column(row, "refresh_hash")?,
+            previous_refresh_hash: nullable(row, "previous_refresh_hash")?,
+            created_at: column(row, "created_at")?,
+            expires_at: column(row, "expires_at")?,
+            revoked: nullable(row, "revoked")?.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRow for PasswordReset {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(PasswordReset {
+            token_hash: column(row, "token_hash")?,
+            user_id: column(row, "user_id")?,
+            created_at: column(row, "created_at")?,
+            expires_at: column(row, "expires_at")?,
+        })
+    }
+}
+
+impl FromRow for LoginRecord {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(LoginRecord {
+            user_id: column(row, "user_id")?,
+            entry_id: column(row, "entry_id")?,
+            logged_in_at: column(row, "logged_in_at")?,
+            session_id: nullable(row, "session_id")?,
+            ip: nullable(row, "ip")?.unwrap_or_default(),
+            user_agent: nullable(row, "user_agent")?.unwrap_or_default(),
+            outcome: column(row, "outcome")?,
+        })
+    }
+}
+
+impl FromRow for MfaEnrollment {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(MfaEnrollment {
+            user_id: column(row, "user_id")?,
+            secret: column(row, "secret")?,
+            enabled: column(row, "enabled")?,
+            created_at: column(row, "created_at")?,
+            enabled_at: nullable(row, "enabled_at")?.unwrap_or_default(),
+            last_used_step: column(row, "last_used_step")?,
+        })
+    }
+}
+
+impl FromRow for LoginThrottle {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(LoginThrottle {
+            key: column(row, "key")?,
+            failures: column(row, "failures")?,
+            last_failure_at: column(row, "last_failure_at")?,
+            locked_until: column(row, "locked_until")?,
+        })
+    }
+}
+
+impl FromRow for FailedLogin {
+    fn from_row(row: &Row) -> Result<Self, RowError> {
+        Ok(FailedLogin {
+            attempt_id: column(row, "attempt_id")?,
+            email: column(row, "email")?,
+            attempted_at: column(row, "attempted_at")?,
+            ip: column(row, "ip")?,
+            reason: column(row, "reason")?,
+        })
+    }
+}
+
+/// Seconds left before `expires_at`, for `USING TTL` on writes to a row that
+/// must disappear with it. Cassandra rejects a TTL of 0, hence the floor of 1.
+fn ttl_until(expires_at: i64) -> i32 {
+    let secs = (expires_at - Utc::now().timestamp_millis()) / 1000;
+    secs.clamp(1, i32::MAX as i64) as i32
+}
+
+/// Binds an event's recurrence, or NULL for an event that happens once.
+fn bind_recurrence(
+    statement: &mut Statement,
+    index: usize,
+    event: &Event,
+) -> Result<(), StoreError> {
+    match &event.recurrence {
+        Some(recurrence) => statement.bind(index, recurrence.to_ical().as_str())?,
+        None => statement.bind_null(index)?,
+    };
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl UserStore for CassandraStore {
+    async fn insert_user(&self, user: &User) -> Result<bool, StoreError> {
+        // claim the address before the account exists, so a crash in between
+        // leaves at worst an unused claim rather than a user without one
+        if !self.claim_email(&user.email, user.user_id).await? {
+            return Ok(false);
+        }
+
+        let session = self.session().await?;
+        let query = format!("INSERT INTO {ks}.users (user_id, username, email, password_hash, created_at, updated_at, last_login, role, email_verified, verification_sent_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", ks = self.keyspace());
+        let mut statement = session.statement(&query);
+        statement.bind(0, user.user_id)?;
+        statement.bind(1, user.username.as_str())?;
+        statement.bind(2, user.email.as_str())?;
+        statement.bind(3, user.password_hash.as_str())?;
+        statement.bind(4, user.created_at)?;
+        statement.bind(5, user.updated_at)?;
+        statement.bind(6, user.last_login)?;
+        statement.bind(7, user.role.as_str())?;
+        statement.bind(8, user.email_verified)?;
+        statement.bind(9, user.verification_sent_at)?;
+        if let Err(e) = self.db.execute(statement).await {
+            self.release_email(&user.email, user.user_id).await?;
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+
+    async fn email_exists(&self, email: &str) -> Result<bool, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT user_id FROM {ks}.email_index WHERE email = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, email)?;
+        let result = self.db.execute(statement).await?;
+        Ok(result.first_row().is_some())
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT * FROM {ks}.users WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        let result = self.db.execute(statement).await?;
+        let row = result.first_row();
+        Ok(row.map(|row| User::from_row(&row)).transpose()?)
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT * FROM {ks}.users WHERE email = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, email)?;
+        let result = self.db.execute(statement).await?;
+        let row = result.first_row();
+        Ok(row.map(|row| User::from_row(&row)).transpose()?)
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!("SELECT * FROM {ks}.users", ks = self.keyspace());
+        let statement = session.statement(&query);
+        let result = self.db.execute(statement).await?;
+
+        let mut users = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            users.push(User::from_row(&row)?);
+        }
+        Ok(users)
+    }
+
+    async fn delete_user(&self, user_id: Uuid, email: &str) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.users WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        self.db.execute(statement).await?;
+
+        // also delete from email_index
+        self.release_email(email, user_id).await
+    }
+
+    async fn set_role(&self, user_id: Uuid, role: Role, updated_at: i64) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.users SET role = ?, updated_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, role.as_str())?;
+        statement.bind(1, updated_at)?;
+        statement.bind(2, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn set_password(
+        &self,
+        user_id: Uuid,
+        password_hash: &str,
+        updated_at: i64,
+    ) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.users SET password_hash = ?, updated_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, password_hash)?;
+        statement.bind(1, updated_at)?;
+        statement.bind(2, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, user_id: Uuid, updated_at: i64) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.users SET email_verified = true, updated_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, updated_at)?;
+        statement.bind(1, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn set_verification_sent(&self, user_id: Uuid, sent_at: i64) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.users SET verification_sent_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, sent_at)?;
+        statement.bind(1, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn set_last_login(&self, user_id: Uuid, last_login: i64) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.users SET last_login = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, last_login)?;
+        statement.bind(1, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn change_email(
+        &self,
+        user: &User,
+        new_email: &str,
+        updated_at: i64,
+    ) -> Result<bool, StoreError> {
+        if !self.claim_email(new_email, user.user_id).await? {
+            return Ok(false);
+        }
+
+        let session = self.session().await?;
+        let query = format!(
+            "UPDATE {ks}.users SET email = ?, email_verified = true, updated_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, new_email)?;
+        statement.bind(1, updated_at)?;
+        statement.bind(2, user.user_id)?;
+        if let Err(e) = self.db.execute(statement).await {
+            // give the address back so it is not held by nobody
+            self.release_email(new_email, user.user_id).await?;
+            return Err(e.into());
+        }
+
+        self.release_email(&user.email, user.user_id).await?;
+        Ok(true)
+    }
+}
+
+#[rocket::async_trait]
+impl EventStore for CassandraStore {
+    async fn insert_event(&self, event: &Event) -> Result<(), StoreError> {
+        let session = self.session().await?;
+        let mut batch = session.batch(BatchType::LOGGED);
+        batch.add_statement(self.event_insert(&session, event)?)?;
+        batch.add_statement(self.event_index(&session, event)?)?;
+        batch.add_statement(self.location_insert(&session, &EventLocation::from(event))?)?;
+        if event.recurrence.is_some() {
+            for statement in self.series_insert(&session, event)? {
+                batch.add_statement(statement)?;
+            }
+        }
+        self.db.execute_batch(batch).await?;
+        Ok(())
+    }
+
+    async fn get_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<Option<Event>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT {} FROM {ks}.events WHERE event_id = ? AND creator_id = ? AND start_time = ?;",
+            EVENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        statement.bind(1, creator_id)?;
+        statement.bind(2, start_time)?;
+        let result = self.db.execute(statement).await?;
+        let row = result.first_row();
+        Ok(row.map(|row| Event::from_row(&row)).transpose()?)
+    }
+
+    async fn get_event_by_id(&self, event_id: Uuid) -> Result<Option<Event>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT creator_id, start_time FROM {ks}.events_by_id WHERE event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        let result = self.db.execute(statement).await?;
+        let Some(row) = result.first_row() else {
+            return Ok(None);
+        };
+        let creator_id: Uuid = column(&row, "creator_id")?;
+        let start_time: i64 = column(&row, "start_time")?;
+        match self.get_event(event_id, creator_id, start_time).await? {
+            Some(event) => Ok(Some(event)),
+            // the index trails a reschedule by one write; the creator is
+            // enough to find the row meanwhile
+            None => self.get_event_for_creator(creator_id, event_id).await,
+        }
+    }
+
+    async fn get_event_for_creator(
+        &self,
+        creator_id: Uuid,
+        event_id: Uuid,
+    ) -> Result<Option<Event>, StoreError> {
+        let session = self.session().await?;
+
+        // event_id is the last clustering column, so it can only be filtered on;
+        // the scan never leaves the creator's partition
+        let query = format!(
+            "SELECT {} FROM {ks}.events WHERE creator_id = ? AND event_id = ? ALLOW FILTERING;",
+            EVENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, creator_id)?;
+        statement.bind(1, event_id)?;
+        let result = self.db.execute(statement).await?;
+        let row = result.first_row();
+        Ok(row.map(|row| Event::from_row(&row)).transpose()?)
+    }
+
+    async fn list_events(
+        &self,
+        query: &EventQuery,
+        paging_state: Option<&[u8]>,
+    ) -> Result<EventPage, StoreError> {
+        let session = self.session().await?;
+
+        // the bounds slice the creator's partition along its clustering order
+        let mut cql = format!(
+            "SELECT {} FROM {ks}.events WHERE creator_id = ?",
+            EVENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        if query.from.is_some() {
+            cql.push_str(" AND start_time >= ?");
+        }
+        if query.to.is_some() {
+            cql.push_str(" AND start_time < ?");
+        }
+
+        let mut statement = session.statement(&cql);
+        statement.bind(0, query.creator_id)?;
+        let mut index = 1;
+        if let Some(from) = query.from {
+            statement.bind(index, from)?;
+            index += 1;
+        }
+        if let Some(to) = query.to {
+            statement.bind(index, to)?;
+        }
+        statement.set_paging_size(query.limit as i32)?;
+        if let Some(state) = paging_state {
+            statement.set_paging_state_token(state)?;
+        }
+        let result = self.db.execute(statement).await?;
+
+        let mut events = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            events.push(Event::from_row(&row)?);
+        }
+        let paging_state = if result.has_more_pages() {
+            result.paging_state_token()?
+        } else {
+            None
+        };
+        Ok(EventPage {
+            events,
+            paging_state,
+        })
+    }
+
+    async fn events_in_bucket(
+        &self,
+        bucket: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<EventLocation>, StoreError> {
+        let session = self.session().await?;
+
+        let mut cql = format!(
+            "SELECT {} FROM {ks}.events_by_location WHERE location_bucket = ?",
+            LOCATION_COLUMNS,
+            ks = self.keyspace()
+        );
+        if from.is_some() {
+            cql.push_str(" AND start_time >= ?");
+        }
+        if to.is_some() {
+            cql.push_str(" AND start_time < ?");
+        }
+
+        // a busy bucket can span several pages; read them all
+        let mut locations = Vec::new();
+        let mut paging_state: Option<Vec<u8>> = None;
+        loop {
+            let mut statement = session.statement(&cql);
+            statement.bind(0, bucket)?;
+            let mut index = 1;
+            if let Some(from) = from {
+                statement.bind(index, from)?;
+                index += 1;
+            }
+            if let Some(to) = to {
+                statement.bind(index, to)?;
+            }
+            if let Some(state) = &paging_state {
+                statement.set_paging_state_token(state)?;
+            }
+            let result = self.db.execute(statement).await?;
+            let mut iter = result.iter();
+            while let Some(row) = iter.next() {
+                locations.push(EventLocation::from_row(&row)?);
+            }
+            if !result.has_more_pages() {
+                break;
+            }
+            paging_state = result.paging_state_token()?;
+            if paging_state.is_none() {
+                break;
+            }
+        }
+        Ok(locations)
+    }
+
+    async fn recurring_events(
+        &self,
+        creator_id: Uuid,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError> {
+        let cql = format!(
+            "SELECT event_id, creator_id, start_time FROM {ks}.recurring_events_by_creator WHERE creator_id = ?",
+            ks = self.keyspace()
+        );
+        let bind = |statement: &mut Statement| -> Result<(), StoreError> {
+            statement.bind(0, creator_id)?;
+            Ok(())
+        };
+        self.indexed_series(&cql, bind, before).await
+    }
+
+    async fn recurring_in_bucket(
+        &self,
+        bucket: &str,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError> {
+        let cql = format!(
+            "SELECT event_id, creator_id, start_time FROM {ks}.recurring_events_by_location WHERE location_bucket = ?",
+            ks = self.keyspace()
+        );
+        let bind = |statement: &mut Statement| -> Result<(), StoreError> {
+            statement.bind(0, bucket)?;
+            Ok(())
+        };
+        self.indexed_series(&cql, bind, before).await
+    }
+
+    async fn update_event(&self, current: &Event, updated: &Event) -> Result<bool, StoreError> {
+        let session = self.session().await?;
+
+        let result = if updated.start_time == current.start_time {
+            let query = format!(
+                "UPDATE {ks}.events SET title = ?, description = ?, end_time = ?, lat = ?, lon = ?, address = ?, updated_at = ?, recurrence = ? WHERE creator_id = ? AND start_time = ? AND event_id = ? IF updated_at = ?",
+                ks = self.keyspace()
+            );
+            let mut statement = session.statement(&query);
+            statement.bind(0, updated.title.as_str())?;
+            statement.bind(1, updated.description.as_str())?;
+            statement.bind(2, updated.end_time)?;
+            statement.bind(3, updated.lat)?;
+            statement.bind(4, updated.lon)?;
+            statement.bind(5, updated.address.as_str())?;
+            statement.bind(6, updated.updated_at)?;
+            bind_recurrence(&mut statement, 7, updated)?;
+            statement.bind(8, current.creator_id)?;
+            statement.bind(9, current.start_time)?;
+            statement.bind(10, current.event_id)?;
+            statement.bind(11, current.updated_at)?;
+            self.db.execute(statement).await?
+        } else {
+            // start_time is a clustering column, so the row has to be rewritten
+            // under its new key. Both rows share the creator's partition, which
+            // lets one conditional batch delete and insert atomically.
+            let query = format!(
+                "DELETE FROM {ks}.events WHERE creator_id = ? AND start_time = ? AND event_id = ? IF updated_at = ?",
+                ks = self.keyspace()
+            );
+            let mut delete = session.statement(&query);
+            delete.bind(0, current.creator_id)?;
+            delete.bind(1, current.start_time)?;
+            delete.bind(2, current.event_id)?;
+            delete.bind(3, current.updated_at)?;
+            let mut batch = session.batch(BatchType::LOGGED);
+            batch.add_statement(delete)?;
+            batch.add_statement(self.event_insert(&session, updated)?)?;
+            self.db.execute_batch(batch).await?
+        };
+        let applied = match result.first_row() {
+            Some(row) => column(&row, "[applied]")?,
+            None => false,
+        };
+        if applied {
+            // a conditional batch cannot span partitions, so the lookup tables
+            // follow in their own write
+            let old = EventLocation::from(current);
+            let new = EventLocation::from(updated);
+            let mut batch = session.batch(BatchType::LOGGED);
+            if updated.start_time != current.start_time {
+                batch.add_statement(self.event_index(&session, updated)?)?;
+            }
+            if (&old.location_bucket, old.start_time) != (&new.location_bucket, new.start_time) {
+                batch.add_statement(self.location_delete(&session, &old)?)?;
+            }
+            batch.add_statement(self.location_insert(&session, &new)?)?;
+            if current.recurrence.is_some() {
+                if updated.recurrence.is_none() {
+                    batch.add_statement(self.series_creator_delete(&session, current)?)?;
+                }
+                if updated.recurrence.is_none() || old.location_bucket != new.location_bucket {
+                    batch.add_statement(self.series_location_delete(&session, current)?)?;
+                }
+            }
+            if updated.recurrence.is_some() {
+                for statement in self.series_insert(&session, updated)? {
+                    batch.add_statement(statement)?;
+                }
+            }
+            self.db.execute_batch(batch).await?;
+        }
+        Ok(applied)
+    }
+
+    async fn delete_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<(), StoreError> {
+        let session = self.session().await?;
+        // the location rows are keyed by where the event is, which only the
+        // event row knows
+        let stored = self.get_event(event_id, creator_id, start_time).await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.events WHERE event_id = ? and start_time = ? and creator_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        statement.bind(1, start_time)?;
+        statement.bind(2, creator_id)?;
+        let query = format!(
+            "DELETE FROM {ks}.events_by_id WHERE event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut index = session.statement(&query);
+        index.bind(0, event_id)?;
+        let query = format!(
+            "DELETE FROM {ks}.event_overrides WHERE event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut overrides = session.statement(&query);
+        overrides.bind(0, event_id)?;
+        let query = format!(
+            "DELETE FROM {ks}.event_attendees WHERE event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut rsvps = session.statement(&query);
+        rsvps.bind(0, event_id)?;
+        let query = format!(
+            "DELETE FROM {ks}.comments WHERE event_id = ?",
+            ks = self.keyspace()
+        );
+        let mut comments = session.statement(&query);
+        comments.bind(0, event_id)?;
+        let mut batch = session.batch(BatchType::LOGGED);
+        batch.add_statement(statement)?;
+        batch.add_statement(index)?;
+        batch.add_statement(overrides)?;
+        batch.add_statement(rsvps)?;
+        batch.add_statement(comments)?;
+        if let Some(event) = &stored {
+            batch.add_statement(self.location_delete(&session, &EventLocation::from(event))?)?;
+            if event.recurrence.is_some() {
+                batch.add_statement(self.series_creator_delete(&session, event)?)?;
+                batch.add_statement(self.series_location_delete(&session, event)?)?;
+            }
+        }
+        self.db.execute_batch(batch).await?;
+        Ok(())
+    }
+
+    async fn overrides(&self, event_id: Uuid) -> Result<Vec<Occurrence>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT {} FROM {ks}.event_overrides WHERE event_id = ?",
+            OVERRIDE_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut overrides = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            overrides.push(Occurrence::from_row(&row)?);
+        }
+        Ok(overrides)
+    }
+
+    async fn put_override(&self, occurrence: &Occurrence) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.event_overrides ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            OVERRIDE_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, occurrence.event_id)?;
+        statement.bind(1, occurrence.occurrence_start)?;
+        statement.bind(2, occurrence.creator_id)?;
+        statement.bind(3, occurrence.title.as_str())?;
+        statement.bind(4, occurrence.description.as_str())?;
+        statement.bind(5, occurrence.start_time)?;
+        statement.bind(6, occurrence.end_time)?;
+        statement.bind(7, occurrence.lat)?;
+        statement.bind(8, occurrence.lon)?;
+        statement.bind(9, occurrence.address.as_str())?;
+        statement.bind(10, occurrence.cancelled)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn delete_overrides(
+        &self,
+        event_id: Uuid,
+        occurrence_starts: &[i64],
+    ) -> Result<(), StoreError> {
+        if occurrence_starts.is_empty() {
+            return Ok(());
+        }
+        let session = self.session().await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.event_overrides WHERE event_id = ? AND occurrence_start = ?",
+            ks = self.keyspace()
+        );
+        let mut batch = session.batch(BatchType::LOGGED);
+        for &occurrence_start in occurrence_starts {
+            let mut statement = session.statement(&query);
+            statement.bind(0, event_id)?;
+            statement.bind(1, occurrence_start)?;
+            batch.add_statement(statement)?;
+        }
+        self.db.execute_batch(batch).await?;
+        Ok(())
+    }
+
+    async fn put_rsvp(&self, rsvp: &Rsvp) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.event_attendees ({}) VALUES (?, ?, ?, ?, ?, ?)",
+            RSVP_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, rsvp.event_id)?;
+        statement.bind(1, rsvp.occurrence_start)?;
+        statement.bind(2, rsvp.user_id)?;
+        statement.bind(3, rsvp.status.as_str())?;
+        statement.bind(4, rsvp.is_host)?;
+        statement.bind(5, rsvp.updated_at)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn rsvps(&self, event_id: Uuid, occurrence_start: i64) -> Result<Vec<Rsvp>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT {} FROM {ks}.event_attendees WHERE event_id = ? AND occurrence_start = ?",
+            RSVP_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        statement.bind(1, occurrence_start)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut rsvps = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            rsvps.push(Rsvp::from_row(&row)?);
+        }
+        Ok(rsvps)
+    }
+
+    async fn insert_comment(&self, comment: &Comment) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.comments ({}) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            COMMENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, comment.event_id)?;
+        statement.bind(1, comment.occurrence_start)?;
+        statement.bind(2, comment.comment_id)?;
+        statement.bind(3, comment.user_id)?;
+        statement.bind(4, comment.content.as_str())?;
+        statement.bind(5, comment.created_at)?;
+        statement.bind(6, comment.updated_at)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn comments(
+        &self,
+        event_id: Uuid,
+        occurrence_start: i64,
+        limit: usize,
+    ) -> Result<Vec<Comment>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT {} FROM {ks}.comments WHERE event_id = ? AND occurrence_start = ? LIMIT ?",
+            COMMENT_COLUMNS,
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, event_id)?;
+        statement.bind(1, occurrence_start)?;
+        statement.bind(2, limit.min(i32::MAX as usize) as i32)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut comments = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            comments.push(Comment::from_row(&row)?);
+        }
+        Ok(comments)
+    }
+}
+
+#[rocket::async_trait]
+impl SessionStore for CassandraStore {
+    async fn insert_session(&self, session: &Session) -> Result<(), StoreError> {
+        let session_handle = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.sessions (session_id, user_id, refresh_hash, created_at, expires_at, revoked) VALUES (?, ?, ?, ?, ?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session_handle.statement(&query);
+        statement.bind(0, session.session_id)?;
+        statement.bind(1, session.user_id)?;
+        statement.bind(2, session.refresh_hash.as_str())?;
+        statement.bind(3, session.created_at)?;
+        statement.bind(4, session.expires_at)?;
+        statement.bind(5, session.revoked)?;
+        statement.bind(6, ttl_until(session.expires_at))?;
+        self.db.execute(statement).await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.sessions_by_user (user_id, session_id) VALUES (?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session_handle.statement(&query);
+        statement.bind(0, session.user_id)?;
+        statement.bind(1, session.session_id)?;
+        statement.bind(2, ttl_until(session.expires_at))?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> Result<Option<Session>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT session_id, user_id, refresh_hash, previous_refresh_hash, created_at, expires_at, revoked FROM {ks}.sessions WHERE session_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, session_id)?;
+        let result = self.db.execute(statement).await?;
+        let row = result.first_row();
+        Ok(row.map(|row| Session::from_row(&row)).transpose()?)
+    }
+
+    async fn sessions_for_user(&self, user_id: Uuid) -> Result<Vec<Session>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT session_id FROM {ks}.sessions_by_user WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut session_ids = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            session_ids.push(column::<Uuid>(&row, "session_id")?);
+        }
+
+        let mut sessions = Vec::new();
+        for session_id in session_ids {
+            if let Some(session) = self.get_session(session_id).await? {
+                sessions.push(session);
+            }
+        }
+        Ok(sessions)
+    }
+
+    async fn rotate_refresh(&self, session: &Session, new_hash: &str) -> Result<bool, StoreError> {
+        let session_handle = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.sessions USING TTL ? SET refresh_hash = ?, previous_refresh_hash = ? WHERE session_id = ? IF refresh_hash = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session_handle.statement(&query);
+        statement.bind(0, ttl_until(session.expires_at))?;
+        statement.bind(1, new_hash)?;
+        statement.bind(2, session.refresh_hash.as_str())?;
+        statement.bind(3, session.session_id)?;
+        statement.bind(4, session.refresh_hash.as_str())?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(column(&row, "[applied]")?),
+            None => Ok(false),
+        }
+    }
+
+    async fn revoke_session(&self, session: &Session) -> Result<(), StoreError> {
+        let session_handle = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.sessions USING TTL ? SET revoked = true WHERE session_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session_handle.statement(&query);
+        statement.bind(0, ttl_until(session.expires_at))?;
+        statement.bind(1, session.session_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn insert_login(&self, record: &LoginRecord, expires_at: i64) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.login_history (user_id, logged_in_at, entry_id, session_id, ip, user_agent, outcome) VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, record.user_id)?;
+        statement.bind(1, record.logged_in_at)?;
+        statement.bind(2, record.entry_id)?;
+        match record.session_id {
+            Some(session_id) => statement.bind(3, session_id)?,
+            None => statement.bind_null(3)?,
+        };
+        statement.bind(4, record.ip.as_str())?;
+        statement.bind(5, record.user_agent.as_str())?;
+        statement.bind(6, record.outcome.as_str())?;
+        statement.bind(7, ttl_until(expires_at))?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn login_history(
+        &self,
+        user_id: Uuid,
+        limit: usize,
+    ) -> Result<Vec<LoginRecord>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT user_id, logged_in_at, entry_id, session_id, ip, user_agent, outcome FROM {ks}.login_history WHERE user_id = ? LIMIT ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        statement.bind(1, limit.min(i32::MAX as usize) as i32)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut records = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            records.push(LoginRecord::from_row(&row)?);
+        }
+        Ok(records)
+    }
+}
+
+#[rocket::async_trait]
+impl ResetStore for CassandraStore {
+    async fn insert_reset(&self, reset: &PasswordReset) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.password_resets (token_hash, user_id, created_at, expires_at) VALUES (?, ?, ?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, reset.token_hash.as_str())?;
+        statement.bind(1, reset.user_id)?;
+        statement.bind(2, reset.created_at)?;
+        statement.bind(3, reset.expires_at)?;
+        statement.bind(4, ttl_until(reset.expires_at))?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn take_reset(&self, token_hash: &str) -> Result<Option<PasswordReset>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT token_hash, user_id, created_at, expires_at FROM {ks}.password_resets WHERE token_hash = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, token_hash)?;
+        let result = self.db.execute(statement).await?;
+        let reset = match result.first_row() {
+            Some(row) => PasswordReset::from_row(&row)?,
+            None => return Ok(None),
+        };
+
+        // only the caller whose delete applies gets to use the token
+        let query = format!(
+            "DELETE FROM {ks}.password_resets WHERE token_hash = ? IF EXISTS",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, token_hash)?;
+        let result = self.db.execute(statement).await?;
+        let applied = match result.first_row() {
+            Some(row) => column(&row, "[applied]")?,
+            None => false,
+        };
+        Ok(applied.then_some(reset))
+    }
+}
+
+#[rocket::async_trait]
+impl AttemptStore for CassandraStore {
+    async fn get_throttle(&self, key: &str) -> Result<Option<LoginThrottle>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT key, failures, last_failure_at, locked_until FROM {ks}.login_throttle WHERE key = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, key)?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(Some(LoginThrottle::from_row(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_throttle(
+        &self,
+        throttle: &LoginThrottle,
+        expires_at: i64,
+    ) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.login_throttle (key, failures, last_failure_at, locked_until) VALUES (?, ?, ?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, throttle.key.as_str())?;
+        statement.bind(1, throttle.failures)?;
+        statement.bind(2, throttle.last_failure_at)?;
+        statement.bind(3, throttle.locked_until)?;
+        statement.bind(4, ttl_until(expires_at))?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn clear_throttle(&self, key: &str) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.login_throttle WHERE key = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, key)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn insert_failed_login(
+        &self,
+        attempt: &FailedLogin,
+        expires_at: i64,
+    ) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.failed_logins (email, attempted_at, attempt_id, ip, reason) VALUES (?, ?, ?, ?, ?) USING TTL ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, attempt.email.as_str())?;
+        statement.bind(1, attempt.attempted_at)?;
+        statement.bind(2, attempt.attempt_id)?;
+        statement.bind(3, attempt.ip.as_str())?;
+        statement.bind(4, attempt.reason.as_str())?;
+        statement.bind(5, ttl_until(expires_at))?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn failed_logins(
+        &self,
+        email: &str,
+        limit: usize,
+    ) -> Result<Vec<FailedLogin>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT email, attempted_at, attempt_id, ip, reason FROM {ks}.failed_logins WHERE email = ? LIMIT ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, email)?;
+        statement.bind(1, limit.min(i32::MAX as usize) as i32)?;
+        let result = self.db.execute(statement).await?;
+
+        let mut attempts = Vec::new();
+        let mut iter = result.iter();
+        while let Some(row) = iter.next() {
+            attempts.push(FailedLogin::from_row(&row)?);
+        }
+        Ok(attempts)
+    }
+}
+
+#[rocket::async_trait]
+impl MfaStore for CassandraStore {
+    async fn get_mfa(&self, user_id: Uuid) -> Result<Option<MfaEnrollment>, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "SELECT user_id, secret, enabled, created_at, enabled_at, last_used_step FROM {ks}.mfa WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(Some(MfaEnrollment::from_row(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_mfa(&self, enrollment: &MfaEnrollment) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.mfa (user_id, secret, enabled, created_at, enabled_at, last_used_step) VALUES (?, ?, ?, ?, ?, ?)",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, enrollment.user_id)?;
+        statement.bind(1, enrollment.secret.as_str())?;
+        statement.bind(2, enrollment.enabled)?;
+        statement.bind(3, enrollment.created_at)?;
+        statement.bind(4, enrollment.enabled_at)?;
+        statement.bind(5, enrollment.last_used_step)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn enable_mfa(
+        &self,
+        user_id: Uuid,
+        enabled_at: i64,
+        recovery_hashes: &[String],
+    ) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        // codes first: an enrollment is never enabled without its codes
+        let query = format!(
+            "DELETE FROM {ks}.mfa_recovery_codes WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        self.db.execute(statement).await?;
+
+        let query = format!(
+            "INSERT INTO {ks}.mfa_recovery_codes (user_id, code_hash) VALUES (?, ?)",
+            ks = self.keyspace()
+        );
+        for code_hash in recovery_hashes {
+            let mut statement = session.statement(&query);
+            statement.bind(0, user_id)?;
+            statement.bind(1, code_hash.as_str())?;
+            self.db.execute(statement).await?;
+        }
+
+        let query = format!(
+            "UPDATE {ks}.mfa SET enabled = true, enabled_at = ? WHERE user_id = ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, enabled_at)?;
+        statement.bind(1, user_id)?;
+        self.db.execute(statement).await?;
+        Ok(())
+    }
+
+    async fn delete_mfa(&self, user_id: Uuid) -> Result<(), StoreError> {
+        let session = self.session().await?;
+
+        for table in ["mfa", "mfa_recovery_codes"] {
+            let query = format!(
+                "DELETE FROM {ks}.{table} WHERE user_id = ?",
+                ks = self.keyspace()
+            );
+            let mut statement = session.statement(&query);
+            statement.bind(0, user_id)?;
+            self.db.execute(statement).await?;
+        }
+        Ok(())
+    }
+
+    async fn use_step(&self, user_id: Uuid, step: i64) -> Result<bool, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "UPDATE {ks}.mfa SET last_used_step = ? WHERE user_id = ? IF last_used_step < ?",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, step)?;
+        statement.bind(1, user_id)?;
+        statement.bind(2, step)?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(column(&row, "[applied]")?),
+            None => Ok(false),
+        }
+    }
+
+    async fn take_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool, StoreError> {
+        let session = self.session().await?;
+
+        let query = format!(
+            "DELETE FROM {ks}.mfa_recovery_codes WHERE user_id = ? AND code_hash = ? IF EXISTS",
+            ks = self.keyspace()
+        );
+        let mut statement = session.statement(&query);
+        statement.bind(0, user_id)?;
+        statement.bind(1, code_hash)?;
+        let result = self.db.execute(statement).await?;
+        match result.first_row() {
+            Some(row) => Ok(column(&row, "[applied]")?),
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,605 @@
+use crate::attendance::{Comment, Rsvp};
+use crate::events::{Event, EventLocation, EventPage, EventQuery};
+use crate::lockout::{FailedLogin, LoginThrottle};
+use crate::mfa::MfaEnrollment;
+use crate::occurrences::Occurrence;
+use crate::passwords::PasswordReset;
+use crate::sessions::{LoginRecord, Session};
+use crate::store::{
+    AttemptStore, EventStore, MfaStore, ResetStore, SessionStore, StoreError, UserStore,
+};
+use crate::users::{Role, User};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// In-process implementation of every store trait, for tests and for running
+/// the API without a database. Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    users: RwLock<HashMap<Uuid, User>>,
+    email_index: RwLock<HashMap<String, Uuid>>,
+    events: RwLock<Vec<Event>>,
+    overrides: RwLock<Vec<Occurrence>>,
+    rsvps: RwLock<Vec<Rsvp>>,
+    comments: RwLock<Vec<Comment>>,
+    sessions: RwLock<HashMap<Uuid, Session>>,
+    resets: RwLock<HashMap<String, PasswordReset>>,
+    throttles: RwLock<HashMap<String, LoginThrottle>>,
+    failed_logins: RwLock<Vec<FailedLogin>>,
+    logins: RwLock<Vec<LoginRecord>>,
+    mfa: RwLock<HashMap<Uuid, MfaEnrollment>>,
+    recovery_codes: RwLock<HashMap<Uuid, HashSet<String>>>,
+}
+
+#[rocket::async_trait]
+impl UserStore for MemoryStore {
+    async fn insert_user(&self, user: &User) -> Result<bool, StoreError> {
+        let mut email_index = self.email_index.write().unwrap();
+        if email_index.contains_key(&user.email) {
+            return Ok(false);
+        }
+        email_index.insert(user.email.clone(), user.user_id);
+        self.users
+            .write()
+            .unwrap()
+            .insert(user.user_id, user.clone());
+        Ok(true)
+    }
+
+    async fn email_exists(&self, email: &str) -> Result<bool, StoreError> {
+        Ok(self.email_index.read().unwrap().contains_key(email))
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, StoreError> {
+        Ok(self.users.read().unwrap().get(&user_id).cloned())
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>, StoreError> {
+        Ok(self
+            .users
+            .read()
+            .unwrap()
+            .values()
+            .find(|user| user.email == email)
+            .cloned())
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>, StoreError> {
+        Ok(self.users.read().unwrap().values().cloned().collect())
+    }
+
+    async fn delete_user(&self, user_id: Uuid, email: &str) -> Result<(), StoreError> {
+        self.users.write().unwrap().remove(&user_id);
+        self.email_index.write().unwrap().remove(email);
+        Ok(())
+    }
+
+    async fn set_role(&self, user_id: Uuid, role: Role, updated_at: i64) -> Result<(), StoreError> {
+        if let Some(user) = self.users.write().unwrap().get_mut(&user_id) {
+            user.role = role;
+            user.updated_at = updated_at;
+        }
+        Ok(())
+    }
+
+    async fn set_password(
+        &self,
+        user_id: Uuid,
+        password_hash: &str,
+        updated_at: i64,
+    ) -> Result<(), StoreError> {
+        if let Some(user) = self.users.write().unwrap().get_mut(&user_id) {
+            user.password_hash = password_hash.to_string();
+            user.updated_at = updated_at;
+        }
+        Ok(())
+    }
+
+    async fn set_email_verified(&self, user_id: Uuid, updated_at: i64) -> Result<(), StoreError> {
+        if let Some(user) = self.users.write().unwrap().get_mut(&user_id) {
+            user.email_verified = true;
+            user.updated_at = updated_at;
+        }
+        Ok(())
+    }
+
+    async fn set_verification_sent(&self, user_id: Uuid, sent_at: i64) -> Result<(), StoreError> {
+        if let Some(user) = self.users.write().unwrap().get_mut(&user_id) {
+            user.verification_sent_at = sent_at;
+        }
+        Ok(())
+    }
+
+    async fn set_last_login(&self, user_id: Uuid, last_login: i64) -> Result<(), StoreError> {
+        if let Some(user) = self.users.write().unwrap().get_mut(&user_id) {
+            user.last_login = last_login;
+        }
+        Ok(())
+    }
+
+    async fn change_email(
+        &self,
+        user: &User,
+        new_email: &str,
+        updated_at: i64,
+    ) -> Result<bool, StoreError> {
+        let mut email_index = self.email_index.write().unwrap();
+        if email_index.contains_key(new_email) {
+            return Ok(false);
+        }
+        email_index.insert(new_email.to_string(), user.user_id);
+        if email_index.get(&user.email) == Some(&user.user_id) {
+            email_index.remove(&user.email);
+        }
+        if let Some(stored) = self.users.write().unwrap().get_mut(&user.user_id) {
+            stored.email = new_email.to_string();
+            stored.email_verified = true;
+            stored.updated_at = updated_at;
+        }
+        Ok(true)
+    }
+}
+
+#[rocket::async_trait]
+impl EventStore for MemoryStore {
+    async fn insert_event(&self, event: &Event) -> Result<(), StoreError> {
+        let mut events = self.events.write().unwrap();
+        events.retain(|e| {
+            !(e.event_id == event.event_id
+                && e.creator_id == event.creator_id
+                && e.start_time == event.start_time)
+        });
+        events.push(event.clone());
+        Ok(())
+    }
+
+    async fn get_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<Option<Event>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .find(|e| {
+                e.event_id == event_id && e.creator_id == creator_id && e.start_time == start_time
+            })
+            .cloned())
+    }
+
+    async fn get_event_by_id(&self, event_id: Uuid) -> Result<Option<Event>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_id == event_id)
+            .cloned())
+    }
+
+    async fn get_event_for_creator(
+        &self,
+        creator_id: Uuid,
+        event_id: Uuid,
+    ) -> Result<Option<Event>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_id == event_id && e.creator_id == creator_id)
+            .cloned())
+    }
+
+    async fn list_events(
+        &self,
+        query: &EventQuery,
+        paging_state: Option<&[u8]>,
+    ) -> Result<EventPage, StoreError> {
+        let mut matching: Vec<Event> = self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|e| {
+                e.creator_id == query.creator_id
+                    && query.from.iter().all(|&from| e.start_time >= from)
+                    && query.to.iter().all(|&to| e.start_time < to)
+            })
+            .cloned()
+            .collect();
+        matching.sort_by_key(|e| (std::cmp::Reverse(e.start_time), e.event_id));
+
+        // the paging state here is just the offset of the next page
+        let offset = paging_state
+            .and_then(|state| state.try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0) as usize;
+        let total = matching.len();
+        let events: Vec<Event> = matching
+            .into_iter()
+            .skip(offset)
+            .take(query.limit)
+            .collect();
+        let next = offset + events.len();
+        let paging_state = (next < total).then(|| (next as u64).to_be_bytes().to_vec());
+        Ok(EventPage {
+            events,
+            paging_state,
+        })
+    }
+
+    async fn events_in_bucket(
+        &self,
+        bucket: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Vec<EventLocation>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .map(EventLocation::from)
+            .filter(|e| {
+                e.location_bucket == bucket
+                    && from.iter().all(|&from| e.start_time >= from)
+                    && to.iter().all(|&to| e.start_time < to)
+            })
+            .collect())
+    }
+
+    async fn recurring_events(
+        &self,
+        creator_id: Uuid,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|e| {
+                e.recurrence.is_some()
+                    && e.creator_id == creator_id
+                    && before.iter().all(|&before| e.start_time < before)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn recurring_in_bucket(
+        &self,
+        bucket: &str,
+        before: Option<i64>,
+    ) -> Result<Vec<Event>, StoreError> {
+        Ok(self
+            .events
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|e| {
+                e.recurrence.is_some()
+                    && EventLocation::from(*e).location_bucket == bucket
+                    && before.iter().all(|&before| e.start_time < before)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn update_event(&self, current: &Event, updated: &Event) -> Result<bool, StoreError> {
+        let mut events = self.events.write().unwrap();
+        let position = events.iter().position(|e| {
+            e.event_id == current.event_id
+                && e.creator_id == current.creator_id
+                && e.start_time == current.start_time
+        });
+        match position {
+            Some(i) if events[i].updated_at == current.updated_at => {
+                events[i] = updated.clone();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn delete_event(
+        &self,
+        event_id: Uuid,
+        creator_id: Uuid,
+        start_time: i64,
+    ) -> Result<(), StoreError> {
+        self.events.write().unwrap().retain(|e| {
+            !(e.event_id == event_id && e.creator_id == creator_id && e.start_time == start_time)
+        });
+        self.overrides
+            .write()
+            .unwrap()
+            .retain(|o| o.event_id != event_id);
+        self.rsvps
+            .write()
+            .unwrap()
+            .retain(|r| r.event_id != event_id);
+        self.comments
+            .write()
+            .unwrap()
+            .retain(|c| c.event_id != event_id);
+        Ok(())
+    }
+
+    async fn overrides(&self, event_id: Uuid) -> Result<Vec<Occurrence>, StoreError> {
+        let mut overrides: Vec<Occurrence> = self
+            .overrides
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|o| o.event_id == event_id)
+            .cloned()
+            .collect();
+        overrides.sort_by_key(|o| o.occurrence_start);
+        Ok(overrides)
+    }
+
+    async fn put_override(&self, occurrence: &Occurrence) -> Result<(), StoreError> {
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.retain(|o| {
+            (o.event_id, o.occurrence_start) != (occurrence.event_id, occurrence.occurrence_start)
+        });
+        overrides.push(occurrence.clone());
+        Ok(())
+    }
+
+    async fn delete_overrides(
+        &self,
+        event_id: Uuid,
+        occurrence_starts: &[i64],
+    ) -> Result<(), StoreError> {
+        self.overrides.write().unwrap().retain(|o| {
+            !(o.event_id == event_id && occurrence_starts.contains(&o.occurrence_start))
+        });
+        Ok(())
+    }
+
+    async fn put_rsvp(&self, rsvp: &Rsvp) -> Result<(), StoreError> {
+        let mut rsvps = self.rsvps.write().unwrap();
+        rsvps.retain(|r| {
+            (r.event_id, r.occurrence_start, r.user_id)
+                != (rsvp.event_id, rsvp.occurrence_start, rsvp.user_id)
+        });
+        rsvps.push(rsvp.clone());
+        Ok(())
+    }
+
+    async fn rsvps(&self, event_id: Uuid, occurrence_start: i64) -> Result<Vec<Rsvp>, StoreError> {
+        let mut rsvps: Vec<Rsvp> = self
+            .rsvps
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| r.event_id == event_id && r.occurrence_start == occurrence_start)
+            .cloned()
+            .collect();
+        rsvps.sort_by_key(|r| r.user_id);
+        Ok(rsvps)
+    }
+
+    async fn insert_comment(&self, comment: &Comment) -> Result<(), StoreError> {
+        self.comments.write().unwrap().push(comment.clone());
+        Ok(())
+    }
+
+    async fn comments(
+        &self,
+        event_id: Uuid,
+        occurrence_start: i64,
+        limit: usize,
+    ) -> Result<Vec<Comment>, StoreError> {
+        let mut comments: Vec<Comment> = self
+            .comments
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|c| c.event_id == event_id && c.occurrence_start == occurrence_start)
+            .cloned()
+            .collect();
+        comments.sort_by_key(|c| (std::cmp::Reverse(c.created_at), c.comment_id));
+        comments.truncate(limit);
+        Ok(comments)
+    }
+}
+
+#[rocket::async_trait]
+impl SessionStore for MemoryStore {
+    async fn insert_session(&self, session: &Session) -> Result<(), StoreError> {
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.session_id, session.clone());
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> Result<Option<Session>, StoreError> {
+        Ok(self.sessions.read().unwrap().get(&session_id).cloned())
+    }
+
+    async fn sessions_for_user(&self, user_id: Uuid) -> Result<Vec<Session>, StoreError> {
+        Ok(self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn rotate_refresh(&self, session: &Session, new_hash: &str) -> Result<bool, StoreError> {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.get_mut(&session.session_id) {
+            Some(stored) if stored.refresh_hash == session.refresh_hash => {
+                stored.previous_refresh_hash = Some(stored.refresh_hash.clone());
+                stored.refresh_hash = new_hash.to_string();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke_session(&self, session: &Session) -> Result<(), StoreError> {
+        if let Some(stored) = self.sessions.write().unwrap().get_mut(&session.session_id) {
+            stored.revoked = true;
+        }
+        Ok(())
+    }
+
+    async fn insert_login(&self, record: &LoginRecord, _expires_at: i64) -> Result<(), StoreError> {
+        self.logins.write().unwrap().push(record.clone());
+        Ok(())
+    }
+
+    async fn login_history(
+        &self,
+        user_id: Uuid,
+        limit: usize,
+    ) -> Result<Vec<LoginRecord>, StoreError> {
+        let mut records: Vec<LoginRecord> = self
+            .logins
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|record| record.user_id == user_id)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.logged_in_at));
+        records.truncate(limit);
+        Ok(records)
+    }
+}
+
+#[rocket::async_trait]
+impl ResetStore for MemoryStore {
+    async fn insert_reset(&self, reset: &PasswordReset) -> Result<(), StoreError> {
+        self.resets
+            .write()
+            .unwrap()
+            .insert(reset.token_hash.clone(), reset.clone());
+        Ok(())
+    }
+
+    async fn take_reset(&self, token_hash: &str) -> Result<Option<PasswordReset>, StoreError> {
+        Ok(self.resets.write().unwrap().remove(token_hash))
+    }
+}
+
+/// Nothing expires here; `lockout` ignores counters past their window anyway.
+#[rocket::async_trait]
+impl AttemptStore for MemoryStore {
+    async fn get_throttle(&self, key: &str) -> Result<Option<LoginThrottle>, StoreError> {
+        Ok(self.throttles.read().unwrap().get(key).cloned())
+    }
+
+    async fn put_throttle(
+        &self,
+        throttle: &LoginThrottle,
+        _expires_at: i64,
+    ) -> Result<(), StoreError> {
+        self.throttles
+            .write()
+            .unwrap()
+            .insert(throttle.key.clone(), throttle.clone());
+        Ok(())
+    }
+
+    async fn clear_throttle(&self, key: &str) -> Result<(), StoreError> {
+        self.throttles.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn insert_failed_login(
+        &self,
+        attempt: &FailedLogin,
+        _expires_at: i64,
+    ) -> Result<(), StoreError> {
+        self.failed_logins.write().unwrap().push(attempt.clone());
+        Ok(())
+    }
+
+    async fn failed_logins(
+        &self,
+        email: &str,
+        limit: usize,
+    ) -> Result<Vec<FailedLogin>, StoreError> {
+        let mut attempts: Vec<FailedLogin> = self
+            .failed_logins
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|attempt| attempt.email == email)
+            .cloned()
+            .collect();
+        attempts.sort_by_key(|attempt| std::cmp::Reverse(attempt.attempted_at));
+        attempts.truncate(limit);
+        Ok(attempts)
+    }
+}
+
+#[rocket::async_trait]
+impl MfaStore for MemoryStore {
+    async fn get_mfa(&self, user_id: Uuid) -> Result<Option<MfaEnrollment>, StoreError> {
+        Ok(self.mfa.read().unwrap().get(&user_id).cloned())
+    }
+
+    async fn put_mfa(&self, enrollment: &MfaEnrollment) -> Result<(), StoreError> {
+        self.mfa
+            .write()
+            .unwrap()
+            .insert(enrollment.user_id, enrollment.clone());
+        Ok(())
+    }
+
+    async fn enable_mfa(
+        &self,
+        user_id: Uuid,
+        enabled_at: i64,
+        recovery_hashes: &[String],
+    ) -> Result<(), StoreError> {
+        if let Some(enrollment) = self.mfa.write().unwrap().get_mut(&user_id) {
+            enrollment.enabled = true;
+            enrollment.enabled_at = enabled_at;
+        }
+        self.recovery_codes
+            .write()
+            .unwrap()
+            .insert(user_id, recovery_hashes.iter().cloned().collect());
+        Ok(())
+    }
+
+    async fn delete_mfa(&self, user_id: Uuid) -> Result<(), StoreError> {
+        self.mfa.write().unwrap().remove(&user_id);
+        self.recovery_codes.write().unwrap().remove(&user_id);
+        Ok(())
+    }
+
+    async fn use_step(&self, user_id: Uuid, step: i64) -> Result<bool, StoreError> {
+        match self.mfa.write().unwrap().get_mut(&user_id) {
+            Some(enrollment) if enrollment.last_used_step < step => {
+                enrollment.last_used_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn take_recovery_code(&self, user_id: Uuid, code_hash: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .recovery_codes
+            .write()
+            .unwrap()
+            .get_mut(&user_id)
+            .is_some_and(|codes| codes.remove(code_hash)))
+    }
+}
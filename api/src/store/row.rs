@@ -0,0 +1,130 @@
+use crate::attendance::RsvpStatus;
+use crate::recurrence::Recurrence;
+use crate::users::Role;
+use cassandra_cpp::{LendingIterator, Row, Value};
+use std::fmt;
+use uuid::Uuid;
+
+/// Maps a Cassandra result row onto a typed struct.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, RowError>;
+}
+
+/// A row did not have the shape its `FromRow` impl expects.
+#[derive(Debug)]
+pub enum RowError {
+    /// The column is not part of the result set.
+    MissingColumn(&'static str),
+    /// The column is declared non-null by the mapping but the row holds NULL.
+    UnexpectedNull(&'static str),
+    /// The column holds a value of a different CQL type.
+    WrongType {
+        column: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowError::MissingColumn(column) => write!(f, "column `{}` is missing", column),
+            RowError::UnexpectedNull(column) => write!(f, "column `{}` is NULL", column),
+            RowError::WrongType { column, message } => {
+                write!(f, "column `{}` has the wrong type: {}", column, message)
+            }
+        }
+    }
+}
+
+/// A Rust type that can be read out of a single (non-null) column value.
+pub trait ColumnValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, String>;
+}
+
+impl ColumnValue for String {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_string().map_err(|e| e.to_string())
+    }
+}
+
+impl ColumnValue for i32 {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_i32().map_err(|e| e.to_string())
+    }
+}
+
+impl ColumnValue for i64 {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_i64().map_err(|e| e.to_string())
+    }
+}
+
+impl ColumnValue for f64 {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_f64().map_err(|e| e.to_string())
+    }
+}
+
+impl ColumnValue for bool {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_bool().map_err(|e| e.to_string())
+    }
+}
+
+impl ColumnValue for Uuid {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_uuid().map(Into::into).map_err(|e| e.to_string())
+    }
+}
+
+/// A `SET<TEXT>` or `LIST<TEXT>` column.
+impl ColumnValue for Vec<String> {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let mut items = Vec::new();
+        let mut iter = value.get_set().map_err(|e| e.to_string())?;
+        while let Some(item) = iter.next() {
+            items.push(String::from_value(&item)?);
+        }
+        Ok(items)
+    }
+}
+
+impl ColumnValue for Role {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_string().map_err(|e| e.to_string())?.parse()
+    }
+}
+
+impl ColumnValue for RsvpStatus {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.get_string().map_err(|e| e.to_string())?.parse()
+    }
+}
+
+/// A `TEXT` column holding iCalendar `RRULE` and `EXDATE` lines.
+impl ColumnValue for Recurrence {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        Recurrence::from_ical(&value.get_string().map_err(|e| e.to_string())?)
+    }
+}
+
+/// Reads a column that must be present and non-null.
+pub fn column<T: ColumnValue>(row: &Row, name: &'static str) -> Result<T, RowError> {
+    nullable(row, name)?.ok_or(RowError::UnexpectedNull(name))
+}
+
+/// Reads a column that may legitimately be NULL.
+pub fn nullable<T: ColumnValue>(row: &Row, name: &'static str) -> Result<Option<T>, RowError> {
+    let value = row
+        .get_column_by_name(name)
+        .map_err(|_| RowError::MissingColumn(name))?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    T::from_value(&value)
+        .map(Some)
+        .map_err(|message| RowError::WrongType {
+            column: name,
+            message,
+        })
+}
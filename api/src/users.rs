@@ -1,16 +1,26 @@
-use crate::init_cluster;
-use bcrypt::{hash, verify, DEFAULT_COST};
-use cassandra_cpp::BindRustType;
+use crate::config::{CassandraConfig, Config};
+use crate::db::Db;
+use crate::error::ApiError;
+use crate::lockout;
+use crate::mfa::{self, MfaChallenge};
+use crate::middleware::auth::AuthToken;
+use crate::middleware::client::ClientInfo;
+use crate::passwords::validate_password;
+use crate::policy::{authorize, Action};
+use crate::sessions::{self, TokenPair};
+use crate::store::{Attempts, CassandraStore, Mfa, Sessions, StoreError, Users};
+use bcrypt::{hash, verify};
 use chrono::Utc;
+use jsonwebtoken::{encode, EncodingKey, Header};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
 use uuid::Uuid;
-use jsonwebtoken::{encode, Header, EncodingKey};
-use crate::Json;
-use cassandra_cpp::LendingIterator;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(crate = "rocket::serde")]
+/// The stored user record. It deliberately does not implement `Serialize`:
+/// responses go through `PublicProfile`, `SelfView` or `AdminView`, none of
+/// which carry the password hash.
+#[derive(Debug, Clone)]
 pub struct User {
     pub user_id: Uuid,
     pub username: String,
@@ -19,6 +29,109 @@ pub struct User {
     pub created_at: i64,
     pub updated_at: i64,
     pub last_login: i64,
+    pub role: Role,
+    pub description: String,
+    pub interests: Vec<String>,
+    pub email_verified: bool,
+    /// When the last verification mail went out, for resend throttling.
+    pub verification_sent_at: i64,
+}
+
+/// What anyone may see about a user.
+#[derive(Debug, Serialize)]
+pub struct PublicProfile {
+    pub user_id: Uuid,
+    pub username: String,
+    pub description: String,
+    pub interests: Vec<String>,
+}
+
+/// A user looking at their own account.
+#[derive(Debug, Serialize)]
+pub struct SelfView {
+    #[serde(flatten)]
+    pub profile: PublicProfile,
+    pub email: String,
+    pub email_verified: bool,
+    pub role: Role,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub last_login: i64,
+}
+
+/// An admin looking at any account. Currently the same fields as `SelfView`;
+/// kept separate so admin-only fields do not leak into the self view.
+#[derive(Debug, Serialize)]
+pub struct AdminView {
+    #[serde(flatten)]
+    pub account: SelfView,
+}
+
+impl From<&User> for PublicProfile {
+    fn from(user: &User) -> Self {
+        PublicProfile {
+            user_id: user.user_id,
+            username: user.username.clone(),
+            description: user.description.clone(),
+            interests: user.interests.clone(),
+        }
+    }
+}
+
+impl From<&User> for SelfView {
+    fn from(user: &User) -> Self {
+        SelfView {
+            profile: user.into(),
+            email: user.email.clone(),
+            email_verified: user.email_verified,
+            role: user.role,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            last_login: user.last_login,
+        }
+    }
+}
+
+impl From<&User> for AdminView {
+    fn from(user: &User) -> Self {
+        AdminView {
+            account: user.into(),
+        }
+    }
+}
+
+/// What a user may do, from least to most privileged. See `policy` for the
+/// rules that use it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    Organizer,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Organizer => "organizer",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Role::Member),
+            "organizer" => Ok(Role::Organizer),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role `{}`", other)),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,273 +147,342 @@ pub struct UserRegister {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RoleUpdate {
+    pub role: Role,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// The session this token belongs to; revoking it invalidates the token.
+    pub sid: String,
+    /// The user's role when the token was issued.
+    pub role: Role,
+    /// Whether the user's email was verified when the token was issued.
+    #[serde(default)]
+    pub email_verified: bool,
 }
 
+/// The form addresses are stored and compared in, trimmed and lowercased, so
+/// `Ann@Example.com ` and `ann@example.com` are one account.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The account holding `email`. Accounts made before addresses were
+/// normalized may hold theirs as typed, so that is tried second.
+pub async fn user_with_email(users: &Users, email: &str) -> Result<Option<User>, StoreError> {
+    let normalized = normalize_email(email);
+    if let Some(user) = users.get_user_by_email(&normalized).await? {
+        return Ok(Some(user));
+    }
+    if normalized == email {
+        return Ok(None);
+    }
+    users.get_user_by_email(email).await
+}
 
 pub async fn is_valid_email(email: &str) -> bool {
     let email_regex = Regex::new(r"^[\w\.-]+@[\w\.-]+\.\w+$").unwrap();
     email_regex.is_match(email)
 }
 
-async fn check_email_exists(session: &cassandra_cpp::Session, email: &str) -> Result<bool, String> {
-    let email_check_query = "SELECT user_id FROM openmeet.email_index WHERE email = ?";
-    let mut email_check_statement = session.statement(email_check_query);
-    email_check_statement
-        .bind(0, email)
-        .map_err(|e| e.to_string())?;
-    let email_check_result = email_check_statement
-        .execute()
-        .await
-        .map_err(|e| e.to_string())?;
-    Ok(email_check_result.first_row().is_some())
-}
-async fn insert_user(
-    session: &cassandra_cpp::Session,
-    user: &User,
-    now: i64,
-) -> Result<(), String> {
-    let query = "INSERT INTO openmeet.users (user_id, username, email, password_hash, created_at, updated_at, last_login) VALUES (?, ?, ?, ?, ?, ?, ?)";
-    let mut statement = session.statement(query);
-
-    statement.bind(0, user.user_id).map_err(|e| e.to_string())?;
-    statement
-        .bind(1, user.username.as_str())
-        .map_err(|e| e.to_string())?;
-    statement
-        .bind(2, user.email.as_str())
-        .map_err(|e| e.to_string())?;
-    statement
-        .bind(3, user.password_hash.as_str())
-        .map_err(|e| e.to_string())?;
-    statement.bind(4, now).map_err(|e| e.to_string())?;
-    statement.bind(5, now).map_err(|e| e.to_string())?;
-    statement.bind(6, now).map_err(|e| e.to_string())?;
-
-    statement.execute().await.map_err(|e| e.to_string())?;
-    Ok(())
-}
-async fn insert_email_index(
-    session: &cassandra_cpp::Session,
-    email: &str,
-    user_id: Uuid,
-) -> Result<(), String> {
-    let insert_email_index_query =
-        "INSERT INTO openmeet.email_index (email, user_id) VALUES (?, ?)";
-    let mut insert_email_index_statement = session.statement(insert_email_index_query);
-    insert_email_index_statement
-        .bind(0, email)
-        .map_err(|e| e.to_string())?;
-    insert_email_index_statement
-        .bind(1, user_id)
-        .map_err(|e| e.to_string())?;
-    insert_email_index_statement
-        .execute()
-        .await
-        .map_err(|e| e.to_string())?;
-    Ok(())
-}
-
 // passed a user with unencrypted password, that becomes a bcrypted password_hash
-pub async fn create_user(original_user: User) -> Result<User, String> {
-    // let cloned_original_user: User = original_user.clone();
+pub async fn create_user(
+    users: &Users,
+    config: &Config,
+    original_user: User,
+) -> Result<User, ApiError> {
     let mut user = original_user;
+    user.email = normalize_email(&user.email);
+
+    // check email is valid
+    if !is_valid_email(&user.email).await {
+        return Err(ApiError::validation("invalid_email", "Invalid email"));
+    }
+    validate_password(&user.password_hash)?;
 
     // create bcrypted password_hash
-    user.password_hash = hash(&user.password_hash, DEFAULT_COST).map_err(|e| e.to_string())?;
+    user.password_hash = hash(&user.password_hash, config.auth.bcrypt_cost)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
 
     // create uuid
     user.user_id = Uuid::new_v4();
 
-    // check email is valid
-    if !is_valid_email(&user.email).await {
-        return Err("Invalid email".to_string());
-    }
+    // roles are only ever granted by an admin
+    user.role = Role::Member;
+    user.email_verified = false;
+    user.verification_sent_at = 0;
 
     // time now
     let now = Utc::now().timestamp_millis();
+    user.created_at = now;
+    user.updated_at = now;
+    // set by the first login
+    user.last_login = 0;
 
-    let mut cluster = init_cluster().await?;
-    let session = cluster.connect().await.map_err(|e| e.to_string())?;
-
-    if check_email_exists(&session, &user.email).await? {
-        return Err("Email already exists".to_string());
+    if !users.insert_user(&user).await? {
+        return Err(ApiError::conflict("email_taken", "Email already exists"));
     }
-    insert_user(&session, &user, now).await?;
-    insert_email_index(&session, &user.email, user.user_id).await?;
 
     Ok(user)
 }
 
-pub async fn get_user_by_id(user_id: Uuid) -> Option<User> {
-
-    let mut cluster = init_cluster().await.unwrap();
-    let session = cluster.connect().await.unwrap();
-
-    let query = "SELECT * FROM openmeet.users WHERE user_id = ?";
-    let mut statement = session.statement(query);
-    statement.bind(0, user_id).unwrap();
-
-    let result = statement.execute().await.unwrap();
-    let row = result.first_row()?;
-
-    Some(User {
-        user_id: row.get_column_by_name("user_id").unwrap().get_uuid().unwrap().into(),
-        username: row.get_column_by_name("username").unwrap().get_string().unwrap(),
-        email: row.get_column_by_name("email").unwrap().get_string().unwrap(),
-        password_hash: row.get_column_by_name("password_hash").unwrap().get_string().unwrap(),
-        created_at: row.get_column_by_name("created_at").unwrap().get_i64().unwrap(),
-        updated_at: row.get_column_by_name("updated_at").unwrap().get_i64().unwrap(),
-        last_login: row.get_column_by_name("last_login").unwrap().get_i64().unwrap(),
+pub async fn get_user_by_id(users: &Users, user_id: Uuid) -> Option<User> {
+    users.get_user_by_id(user_id).await.unwrap_or_else(|e| {
+        eprintln!("Failed to get user {}: {}", user_id, e);
+        None
     })
 }
 
-pub async fn get_user_by_email(email: &str) -> Option<User> {
-    let mut cluster = init_cluster().await.unwrap();
-    let session = cluster.connect().await.unwrap();
-
-    let query = "SELECT * FROM openmeet.users WHERE email = ?";
-    let mut statement = session.statement(query);
-    statement.bind(0, email).unwrap();
-
-    let result = statement.execute().await.unwrap();
-    let row = result.first_row()?;
-
-    Some(User {
-        user_id: row
-            .get_column_by_name("user_id")
-            .ok()?
-            .get_uuid()
-            .ok()?
-            .into(),
-        username: row.get_column_by_name("username").ok()?.get_string().ok()?,
-        email: row.get_column_by_name("email").ok()?.get_string().ok()?,
-        password_hash: row
-            .get_column_by_name("password_hash")
-            .ok()?
-            .get_string()
-            .ok()?,
-        created_at: row
-            .get_column_by_name("created_at")
-            .ok()?
-            .get_i64()
-            .unwrap_or_default(),
-        updated_at: row
-            .get_column_by_name("updated_at")
-            .ok()?
-            .get_i64()
-            .unwrap_or_default(),
-        last_login: row
-            .get_column_by_name("last_login")
-            .ok()?
-            .get_i64()
-            .unwrap_or_default(),
+pub async fn get_user_by_email(users: &Users, email: &str) -> Option<User> {
+    user_with_email(users, email).await.unwrap_or_else(|e| {
+        eprintln!("Failed to get user by email: {}", e);
+        None
     })
 }
 
-
-fn generate_token(user: &User) -> Result<String, String> {
+pub fn generate_token(config: &Config, user: &User, session_id: Uuid) -> Result<String, ApiError> {
     let claims = Claims {
         sub: user.user_id.to_string(),
-        exp: (Utc::now().timestamp() + 3600) as usize, // Token valid for 1 hour
+        exp: (Utc::now().timestamp() + config.auth.token_lifetime_secs) as usize,
+        sid: session_id.to_string(),
+        role: user.role,
+        email_verified: user.email_verified,
     };
 
-    let encoding_key = EncodingKey::from_secret("your_secret_key".as_ref());
-    encode(&Header::default(), &claims, &encoding_key).map_err(|e| e.to_string())
+    let encoding_key = EncodingKey::from_secret(config.token_secret());
+    encode(&Header::default(), &claims, &encoding_key)
+        .map_err(|e| ApiError::Internal(e.to_string()))
 }
 
-pub async fn login(email: &str, password: &str) -> Result<String, String> {
-    if let Some(user) = get_user_by_email(email).await {
-        let password_verified = verify(password, &user.password_hash);
+fn invalid_credentials() -> ApiError {
+    ApiError::unauthorized("invalid_credentials", "Invalid email or password")
+}
 
-        match password_verified {
-            Ok(true) => {
-                let token = generate_token(&user)?; 
-                Ok(token)
-            }
-            Ok(false) => Err("Invalid credentials".to_string()),
-            Err(e) => Err(e.to_string()),
-        }
-    } else {
-        Err("User not found".to_string())
-    }
+/// A hash to check passwords against when the email matches no account, so a
+/// miss costs the same bcrypt verify as a wrong password and its timing does
+/// not reveal who has an account.
+fn dummy_hash(cost: u32) -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash("openmeet-no-such-account", cost).unwrap_or_default())
 }
 
-pub async fn delete_user(user_id: &Uuid, email: &str) -> Result<(), String> {
-    let mut cluster = init_cluster().await?;
-    let session = cluster.connect().await.map_err(|e| e.to_string())?;
+/// Where the password step of a login leads.
+#[derive(Debug)]
+pub enum LoginOutcome {
+    /// No second factor: the session is open.
+    LoggedIn(Box<User>, TokenPair),
+    /// The account has two-factor authentication; `mfa::complete_login`
+    /// exchanges the challenge and a code for the session.
+    MfaRequired(MfaChallenge),
+}
 
-    let user = get_user_by_email(email).await;
-    if user.is_none() {
-        return Err("User not found".to_string());
+/// Checks the password, refusing throttled or locked accounts before
+/// spending a bcrypt verify on them, and starts a session unless a second
+/// factor is needed. An unknown email and a wrong password fail alike, with
+/// `invalid_credentials`. Logins to an existing account, failed or not, go
+/// into its login history.
+pub async fn login(
+    users: &Users,
+    sessions: &Sessions,
+    attempts: &Attempts,
+    mfa: &Mfa,
+    config: &Config,
+    credentials: &UserLogin,
+    client: &ClientInfo,
+) -> Result<LoginOutcome, ApiError> {
+    let (email, password) = (credentials.email.as_str(), credentials.password.as_str());
+    lockout::check(attempts, config, email, client.ip).await?;
+
+    let user = match user_with_email(users, email).await? {
+        Some(user) => user,
+        None => {
+            let _ = verify(password, dummy_hash(config.auth.bcrypt_cost));
+            lockout::record_failure(attempts, config, email, client.ip, "unknown_account").await?;
+            return Err(invalid_credentials());
+        }
+    };
+    let matches =
+        verify(password, &user.password_hash).map_err(|e| ApiError::Internal(e.to_string()))?;
+    if !matches {
+        lockout::record_failure(attempts, config, email, client.ip, "wrong_password").await?;
+        sessions::record_login(
+            sessions,
+            config,
+            user.user_id,
+            None,
+            client,
+            "wrong_password",
+        )
+        .await?;
+        return Err(invalid_credentials());
     }
-    // let user = user.unwrap();
 
-    let query = "DELETE FROM openmeet.users WHERE user_id = ?";
-    let mut statement = session.statement(query);
-    statement.bind(0, *user_id).map_err(|e| e.to_string())?;
-    statement.execute().await.map_err(|e| e.to_string())?;
+    // failures are only forgiven once the second factor passes too, or the
+    // password step would reset the count of wrong codes
+    if mfa::is_enabled(mfa, user.user_id).await? {
+        return Ok(LoginOutcome::MfaRequired(mfa::challenge(config, &user)));
+    }
+    let (user, tokens) = finish_login(users, sessions, attempts, config, user, client).await?;
+    Ok(LoginOutcome::LoggedIn(Box::new(user), tokens))
+}
 
-    // also delete from email_index
-    let query = "DELETE FROM openmeet.email_index WHERE email = ?";
-    let mut statement = session.statement(query);
-    statement.bind(0, email).map_err(|e| e.to_string())?;
-    statement.execute().await.map_err(|e| e.to_string())?;
+/// Opens the session once every factor has been checked.
+pub async fn finish_login(
+    users: &Users,
+    sessions: &Sessions,
+    attempts: &Attempts,
+    config: &Config,
+    mut user: User,
+    client: &ClientInfo,
+) -> Result<(User, TokenPair), ApiError> {
+    lockout::record_success(attempts, &user.email).await?;
+    let tokens = sessions::start_session(sessions, config, &user).await?;
+    user.last_login = Utc::now().timestamp_millis();
+    users.set_last_login(user.user_id, user.last_login).await?;
+    sessions::record_login(
+        sessions,
+        config,
+        user.user_id,
+        Some(tokens.session_id),
+        client,
+        "success",
+    )
+    .await?;
+    Ok((user, tokens))
+}
 
-    Ok(())
+pub async fn delete_user(users: &Users, user_id: &Uuid, email: &str) -> Result<(), ApiError> {
+    let user = match get_user_by_email(users, email).await {
+        Some(user) => user,
+        None => return Err(ApiError::not_found("user_not_found", "User not found")),
+    };
+
+    Ok(users.delete_user(*user_id, &user.email).await?)
+}
+
+pub async fn get_all_users(users: &Users) -> Result<Vec<User>, ApiError> {
+    Ok(users.get_all_users().await?)
 }
-pub async fn get_all_users() -> Result<Vec<User>, String> {
-    let mut cluster = init_cluster().await?;
-    let session = cluster.connect().await.map_err(|e| e.to_string())?;
-
-    let query = "SELECT * FROM openmeet.users";
-    let statement = session.statement(query);
-    let result = statement.execute().await.map_err(|e| e.to_string())?;
-
-    let mut users = Vec::new();
-    let mut iter = result.iter();
-    while let Some(row) = iter.next() {
-        users.push(User {
-            user_id: row.get_column_by_name("user_id").ok()
-                .and_then(|col| col.get_uuid().ok())
-                .ok_or("Invalid user_id".to_string())?
-                .into(),
-                username: row.get_column_by_name("username").ok()
-                .and_then(|col| col.get_string().ok())
-                .ok_or("Invalid username".to_string())?, // Convert Option to Result
-            email: row.get_column_by_name("email").ok()
-                .and_then(|col| col.get_string().ok())
-                .ok_or("Invalid email".to_string())?, 
-            password_hash: row.get_column_by_name("password_hash").ok()
-                .and_then(|col| col.get_string().ok())
-                .ok_or("Invalid password_hash".to_string())?,
-            created_at: row.get_column_by_name("created_at").ok()
-                .and_then(|col| col.get_i64().ok())
-                .unwrap_or_default(),
-            updated_at: row.get_column_by_name("updated_at").ok()
-                .and_then(|col| col.get_i64().ok())
-                .unwrap_or_default(),
-            last_login: row.get_column_by_name("last_login").ok()
-                .and_then(|col| col.get_i64().ok())
-                .unwrap_or_default(),
-        });
+
+/// Changes another user's role. Admins cannot change their own, so the last
+/// admin cannot lock everyone out by demoting themselves.
+pub async fn set_role(
+    users: &Users,
+    actor: &AuthToken,
+    user_id: Uuid,
+    role: Role,
+) -> Result<User, ApiError> {
+    authorize(actor, Action::ManageRoles)?;
+    if actor.user_id == user_id {
+        return Err(ApiError::forbidden(
+            "own_role",
+            "You cannot change your own role",
+        ));
     }
-    Ok(users)
+    let mut user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+
+    user.role = role;
+    user.updated_at = Utc::now().timestamp_millis();
+    users.set_role(user_id, role, user.updated_at).await?;
+    Ok(user)
 }
 
+/// `api set-role <email> <role>`: sets a role directly in Cassandra. This is
+/// how the first admin is made, since only admins can grant roles over HTTP.
+pub async fn set_role_cli(config: &CassandraConfig, args: &[String]) -> Result<(), String> {
+    let (email, role) = match args {
+        [email, role] => (email, role.parse::<Role>()?),
+        _ => return Err("Usage: api set-role <email> <member|organizer|admin>".to_string()),
+    };
 
+    let db = Arc::new(Db::connect(config).await?);
+    let users: Users = Arc::new(CassandraStore::new(db.clone()));
+    let result = match user_with_email(&users, email).await {
+        Ok(Some(user)) => users
+            .set_role(user.user_id, role, Utc::now().timestamp_millis())
+            .await
+            .map_err(|e| e.to_string()),
+        Ok(None) => Err(format!("No user with email {}", email)),
+        Err(e) => Err(e.to_string()),
+    };
+    db.close().await;
+    result?;
+    println!("{} is now {}", email, role.as_str());
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::MemoryStore;
+    use bcrypt::DEFAULT_COST;
     use chrono::Utc;
+    use rocket::serde::json::Json;
+    use std::sync::Arc;
     use uuid::Uuid;
 
+    /// A new account with password `password123`, as a registration hands it
+    /// to `create_user`.
+    fn user(email: &str, role: Role) -> User {
+        User {
+            user_id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: email.to_string(),
+            password_hash: "password123".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            last_login: 0,
+            role,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
+        }
+    }
+
+    /// Logs in to an account without a second factor.
+    async fn password_login(
+        users: &Users,
+        sessions: &Sessions,
+        attempts: &Attempts,
+        config: &Config,
+        email: &str,
+        password: &str,
+    ) -> Result<(User, TokenPair), ApiError> {
+        let mfa: Mfa = Arc::new(MemoryStore::default());
+        let credentials = UserLogin {
+            email: email.to_string(),
+            password: password.to_string(),
+        };
+        match login(
+            users,
+            sessions,
+            attempts,
+            &mfa,
+            config,
+            &credentials,
+            &ClientInfo::default(),
+        )
+        .await?
+        {
+            LoginOutcome::LoggedIn(user, tokens) => Ok((*user, tokens)),
+            LoginOutcome::MfaRequired(_) => panic!("no second factor was set up"),
+        }
+    }
+
     #[tokio::test]
     async fn test_login_success() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let sessions: Sessions = Arc::new(MemoryStore::default());
+        let attempts: Attempts = Arc::new(MemoryStore::default());
         // Setup: create a user and insert into the database
         let user = User {
             user_id: Uuid::new_v4(),
@@ -310,9 +492,14 @@ mod tests {
             created_at: 0,
             updated_at: 0,
             last_login: 0,
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
-        let created_user = create_user(user.clone()).await;
+        let created_user = create_user(&users, &config, user.clone()).await;
 
         if let Err(e) = created_user {
             if !e.to_string().contains("Email already exists") {
@@ -320,104 +507,156 @@ mod tests {
             }
         }
         // Act: attempt to login with correct credentials
-        let result = login(&user.email, &user.password_hash).await;
+        let result = password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            &user.email,
+            &user.password_hash,
+        )
+        .await;
 
         if let Err(e) = result {
             println!("result--->: {:?}", e);
         } else {
-            let token = result.unwrap().clone();
+            let token = result.unwrap().1.access_token;
             assert_eq!(token.len() > 0, true);
         }
     }
 
     #[tokio::test]
     async fn test_login_credentials_create_user() {
-
-    // Setup: create a user instance
-    let user = User {
-        user_id: Uuid::new_v4(),
-        username: "testuser".to_string(),
-        email: "testuserCREATE_LOGIN@example.com".to_string(),
-        password_hash: "password123".to_string(),
-        created_at: Utc::now().timestamp_millis(),
-        updated_at: Utc::now().timestamp_millis(),
-        last_login: Utc::now().timestamp_millis(),
-    };
-
-    //  delete any user with this email
-    let _ = delete_user(&user.user_id, &user.email).await;
-
-    // Act: create the user
-    let create_result = create_user(user.clone()).await;
-    assert!(create_result.is_ok());
-
-    // Act: attempt to login with the same user
-    let login_result = login(&user.email, &user.password_hash).await;
-
-    // Assert: check that login was successful
-    assert!(login_result.is_ok());
-    let token = login_result.unwrap();
-    assert!(!token.is_empty());
-    }
-
-    #[tokio::test]
-    async fn test_login_second_layer() {
-        // in a loop
-        // register a user with a random email and password
-        // login with the user
-        // delete the user
-    for i in 0..5 {
-        // Generate a random email and password
-        let email = format!("testuser{}@example.com", i);
-        let password = format!("password{}", i);
-
-        let user = get_user_by_email(email.as_str()).await;
-        if let Some(user) = user {
-            let _ = delete_user(&user.user_id, &user.email).await;
-        }
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let sessions: Sessions = Arc::new(MemoryStore::default());
+        let attempts: Attempts = Arc::new(MemoryStore::default());
 
         // Setup: create a user instance
         let user = User {
             user_id: Uuid::new_v4(),
-            username: format!("testuser{}", i),
-            email: email.clone(),
-            password_hash: password.clone(),
+            username: "testuser".to_string(),
+            email: "testuserCREATE_LOGIN@example.com".to_string(),
+            password_hash: "password123".to_string(),
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
+        //  delete any user with this email
+        let _ = delete_user(&users, &user.user_id, &user.email).await;
+
         // Act: create the user
-        let create_result = crate::register(Json(
-            UserRegister {
-                username: user.username.clone(),
-                email: email.clone(),
-                password: password.clone(),
-            }
-        )).await;
+        let create_result = create_user(&users, &config, user.clone()).await;
         assert!(create_result.is_ok());
 
         // Act: attempt to login with the same user
-        let login_result = crate::frontend_login(Json(
-            UserLogin {
-                email: email.clone(),
-                password: password.clone(),
-            }
-        )).await;
-              
-        let token = login_result.into_inner();
+        let login_result = password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            &user.email,
+            &user.password_hash,
+        )
+        .await;
+
         // Assert: check that login was successful
-        assert!(!token.is_string(), "Token should be a string");
-        // Act: delete the user
-        let delete_result = delete_user(&user.user_id, &user.email).await;
-        assert!(delete_result.is_ok());
+        assert!(login_result.is_ok());
+        let token = login_result.unwrap().1.access_token;
+        assert!(!token.is_empty());
     }
 
-        
+    #[tokio::test]
+    async fn test_login_second_layer() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let sessions: Sessions = Arc::new(MemoryStore::default());
+        let attempts: Attempts = Arc::new(MemoryStore::default());
+        let mfa: Mfa = Arc::new(MemoryStore::default());
+        // in a loop
+        // register a user with a random email and password
+        // login with the user
+        // delete the user
+        for i in 0..5 {
+            // Generate a random email and password
+            let email = format!("testuser{}@example.com", i);
+            let password = format!("password{}", i);
+
+            let user = get_user_by_email(&users, email.as_str()).await;
+            if let Some(user) = user {
+                let _ = delete_user(&users, &user.user_id, &user.email).await;
+            }
+
+            // Setup: create a user instance
+            let user = User {
+                user_id: Uuid::new_v4(),
+                username: format!("testuser{}", i),
+                email: email.clone(),
+                password_hash: password.clone(),
+                created_at: Utc::now().timestamp_millis(),
+                updated_at: Utc::now().timestamp_millis(),
+                last_login: Utc::now().timestamp_millis(),
+                role: Role::Member,
+                description: String::new(),
+                interests: Vec::new(),
+                email_verified: false,
+                verification_sent_at: 0,
+            };
+
+            // Act: create the user
+            let mailer = crate::mailer::from_config(&config.mail);
+            let create_result = crate::register(
+                rocket::State::from(&users),
+                rocket::State::from(&mailer),
+                rocket::State::from(&config),
+                Json(UserRegister {
+                    username: user.username.clone(),
+                    email: email.clone(),
+                    password: password.clone(),
+                }),
+            )
+            .await;
+            assert!(create_result.is_ok());
+
+            // Act: attempt to login with the same user
+            let login_result = crate::frontend_login(
+                rocket::State::from(&users),
+                rocket::State::from(&sessions),
+                rocket::State::from(&attempts),
+                rocket::State::from(&mfa),
+                rocket::State::from(&config),
+                ClientInfo::default(),
+                Json(UserLogin {
+                    email: email.clone(),
+                    password: password.clone(),
+                }),
+            )
+            .await;
+
+            let reply = serde_json::to_value(login_result.unwrap().into_inner()).unwrap();
+            // Assert: check that login was successful
+            assert!(reply["access_token"]
+                .as_str()
+                .is_some_and(|token| !token.is_empty()));
+            assert_eq!(reply["user"]["email"], email);
+            // Act: delete the user
+            let delete_result = delete_user(&users, &user.user_id, &user.email).await;
+            assert!(delete_result.is_ok());
+        }
     }
 
     #[tokio::test]
     async fn test_login_failure_wrong_password() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let sessions: Sessions = Arc::new(MemoryStore::default());
+        let attempts: Attempts = Arc::new(MemoryStore::default());
         // Setup: create a user and insert into the database
         let user = User {
             user_id: Uuid::new_v4(),
@@ -427,29 +666,109 @@ mod tests {
             created_at: 0,
             updated_at: 0,
             last_login: 0,
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
         // ignore any duplicate errors
-        let _ = create_user(user.clone()).await;
+        let _ = create_user(&users, &config, user.clone()).await;
 
         // Act: attempt to login with incorrect password
-        let result = login("testuser@example.com", "wrongpassword").await;
+        let result = password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            "testuser@example.com",
+            "wrongpassword",
+        )
+        .await;
 
         // Assert: check that login failed
-        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().code(), "invalid_credentials");
     }
 
     #[tokio::test]
     async fn test_login_failure_nonexistent_user() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let sessions: Sessions = Arc::new(MemoryStore::default());
+        let attempts: Attempts = Arc::new(MemoryStore::default());
         // Act: attempt to login with a non-existent user
-        let result = login("nonexistent@example.com", "password123").await;
+        let result = password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            "nonexistent@example.com",
+            "password123",
+        )
+        .await;
+
+        // Assert: it fails exactly like a wrong password, revealing nothing
+        let err = result.unwrap_err();
+        assert_eq!(err.code(), "invalid_credentials");
+        assert_eq!(err.status(), rocket::http::Status::Unauthorized);
+    }
 
-        // Assert: check that login failed
-        assert!(result.is_err());
+    #[tokio::test]
+    async fn test_locked_account_refuses_the_right_password() {
+        let store = Arc::new(MemoryStore::default());
+        let users: Users = store.clone();
+        let sessions: Sessions = store.clone();
+        let attempts: Attempts = store;
+        let mut config = Config::for_tests();
+        config.login.backoff_base_secs = 0;
+        let user = user("testuserLOCKED@example.com", Role::Member);
+        let user = create_user(&users, &config, user).await.unwrap();
+
+        for _ in 0..config.login.max_account_failures {
+            let err = password_login(
+                &users,
+                &sessions,
+                &attempts,
+                &config,
+                &user.email,
+                "wrongpassword",
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.code(), "invalid_credentials");
+        }
+        let err = password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            &user.email,
+            "password123",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "account_locked");
+
+        lockout::unlock(&users, &attempts, user.user_id)
+            .await
+            .unwrap();
+        assert!(password_login(
+            &users,
+            &sessions,
+            &attempts,
+            &config,
+            &user.email,
+            "password123"
+        )
+        .await
+        .is_ok());
     }
 
     #[tokio::test]
     async fn test_create_user_success() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         // Setup: create a user instance
         let user = User {
             user_id: Uuid::new_v4(),
@@ -459,16 +778,21 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
         // Act: create the user
-        let result = create_user(user.clone()).await;
+        let result = create_user(&users, &config, user.clone()).await;
 
         match result {
             Ok(created_user) => {
                 // Assert: check that user creation was successful
                 assert_eq!(created_user.username, user.username);
-                assert_eq!(created_user.email, user.email);
+                assert_eq!(created_user.email, "testusersuccess@example.com");
                 assert_ne!(created_user.user_id, user.user_id);
                 assert_ne!(created_user.password_hash, user.password_hash);
             }
@@ -483,8 +807,28 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_addresses_are_found_normalized_or_as_stored() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        // stored as typed, before addresses were normalized
+        let legacy = user("Legacy@Example.com", Role::Member);
+        users.insert_user(&legacy).await.unwrap();
+        let found = user_with_email(&users, "Legacy@Example.com").await.unwrap();
+        assert_eq!(found.unwrap().user_id, legacy.user_id);
+
+        let created = create_user(&users, &config, user(" New@Example.com", Role::Member))
+            .await
+            .unwrap();
+        assert_eq!(created.email, "new@example.com");
+        let found = user_with_email(&users, "NEW@example.com ").await.unwrap();
+        assert_eq!(found.unwrap().user_id, created.user_id);
+    }
+
     #[tokio::test]
     async fn test_create_user_duplicate_email() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         // Setup: create a user instance
 
         let user = User {
@@ -495,10 +839,15 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
         // Act: create the user
-        let result = create_user(user.clone()).await;
+        let result = create_user(&users, &config, user.clone()).await;
 
         if let Err(e) = result {
             if !e.to_string().contains("Email already exists") {
@@ -517,16 +866,57 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
-        let result = create_user(duplicate_user).await;
+        let result = create_user(&users, &config, duplicate_user).await;
 
         // Assert: check that user creation failed due to duplicate email
-        assert!(result.is_err());
+        match result {
+            Err(e) => assert_eq!(e.code(), "email_taken"),
+            Ok(_) => panic!("Duplicate email should be rejected"),
+        }
+
+        // the same address typed differently is still a duplicate
+        let variant = User {
+            email: " TestUserDuplicate@Example.COM ".to_string(),
+            ..user
+        };
+        let result = create_user(&users, &config, variant).await;
+        assert_eq!(result.unwrap_err().code(), "email_taken");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_registrations_claim_email_once() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let user = user("testuserRACE@example.com", Role::Member);
+
+        let attempts: Vec<_> = (0..8)
+            .map(|_| {
+                let (users, config, user) = (users.clone(), config.clone(), user.clone());
+                tokio::spawn(async move { create_user(&users, &config, user).await })
+            })
+            .collect();
+        let mut created = 0;
+        for attempt in attempts {
+            match attempt.await.unwrap() {
+                Ok(_) => created += 1,
+                Err(e) => assert_eq!(e.code(), "email_taken"),
+            }
+        }
+        assert_eq!(created, 1);
+        assert_eq!(get_all_users(&users).await.unwrap().len(), 1);
     }
 
     #[tokio::test]
     async fn test_create_user_invalid_email() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         // Setup: create a user instance with an invalid email
         let user = User {
             user_id: Uuid::new_v4(),
@@ -536,17 +926,39 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
         // Act: attempt to create the user
-        let result = create_user(user).await;
+        let result = create_user(&users, &config, user).await;
 
         // Assert: check that user creation failed due to invalid email
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_create_user_weak_password() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let user = User {
+            password_hash: "a".to_string(),
+            ..user("weak@example.com", Role::Member)
+        };
+
+        let result = create_user(&users, &config, user).await;
+
+        assert_eq!(result.unwrap_err().code(), "weak_password");
+        assert!(get_all_users(&users).await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_delete_user_success() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         // Setup: create a user and insert into the database
         let random_email = format!("{uuid}@example.com", uuid = Uuid::new_v4());
         let user_sample = User {
@@ -557,15 +969,20 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
-        let create_result = create_user(user_sample.clone()).await;
+        let create_result = create_user(&users, &config, user_sample.clone()).await;
         assert!(create_result.is_ok());
 
         let user_id = create_result.unwrap().user_id;
 
         // Act: delete the user
-        let delete_result = delete_user(&user_id, &user_sample.email).await;
+        let delete_result = delete_user(&users, &user_id, &user_sample.email).await;
         if let Err(e) = delete_result.clone() {
             println!("delete_result: {:?}", e);
         }
@@ -573,15 +990,50 @@ mod tests {
         assert!(delete_result.is_ok(), "User deletion should succeed");
 
         // Verify that the user no longer exists
-        let user_after_deletion = get_user_by_email(&user_sample.email).await;
+        let user_after_deletion = get_user_by_email(&users, &user_sample.email).await;
         assert!(
             user_after_deletion.is_none(),
             "User should not exist after deletion"
         );
     }
 
+    #[tokio::test]
+    async fn test_set_role_only_by_admin_and_not_on_self() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let user_sample = user("testuserROLE@example.com", Role::Admin);
+        let user = create_user(&users, &config, user_sample).await.unwrap();
+        assert_eq!(user.role, Role::Member, "registration never grants a role");
+
+        let actor = |role| AuthToken {
+            user_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            role,
+            email_verified: true,
+        };
+        let member = actor(Role::Member);
+        let err = set_role(&users, &member, user.user_id, Role::Organizer)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "not_allowed");
+
+        let admin = actor(Role::Admin);
+        let err = set_role(&users, &admin, admin.user_id, Role::Member)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "own_role");
+
+        set_role(&users, &admin, user.user_id, Role::Organizer)
+            .await
+            .unwrap();
+        let stored = get_user_by_id(&users, user.user_id).await.unwrap();
+        assert_eq!(stored.role, Role::Organizer);
+    }
+
     #[tokio::test]
     async fn test_get_user_by_email_success() {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
         // Setup: create a user and insert into the database
         let user = User {
             user_id: Uuid::new_v4(),
@@ -591,27 +1043,69 @@ mod tests {
             created_at: Utc::now().timestamp_millis(),
             updated_at: Utc::now().timestamp_millis(),
             last_login: Utc::now().timestamp_millis(),
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: false,
+            verification_sent_at: 0,
         };
 
-        let create_result = create_user(user.clone()).await;
+        let create_result = create_user(&users, &config, user.clone()).await;
         if let Err(e) = create_result {
             println!("create_result: {:?}", e);
         }
         // Act: get the user by email
-        let result = get_user_by_email(&user.email).await;
+        let result = get_user_by_email(&users, &user.email).await;
         assert!(result.is_some());
         let retrieved_user = result.unwrap();
-        assert_eq!(retrieved_user.email, user.email);
+        assert_eq!(retrieved_user.email, normalize_email(&user.email));
+    }
+
+    #[test]
+    fn test_views_never_include_password_hash() {
+        let user = User {
+            user_id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: "testuserVIEW@example.com".to_string(),
+            password_hash: "$2b$04$secret".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            last_login: 0,
+            role: Role::Member,
+            description: "hi".to_string(),
+            interests: vec!["hiking".to_string()],
+            email_verified: false,
+            verification_sent_at: 0,
+        };
+
+        let public = serde_json::to_value(PublicProfile::from(&user)).unwrap();
+        assert!(public.get("email").is_none());
+        assert_eq!(public["interests"][0], "hiking");
+
+        for view in [
+            serde_json::to_value(SelfView::from(&user)).unwrap(),
+            serde_json::to_value(AdminView::from(&user)).unwrap(),
+        ] {
+            assert_eq!(view["email"], user.email);
+            assert_eq!(view["username"], user.username);
+            assert!(view.get("password_hash").is_none());
+        }
     }
-    
+
     #[tokio::test]
     async fn test_verify_password() {
         let password = "password123";
         let hashed_password = hash(password, DEFAULT_COST).unwrap();
         let hashed_password_2 = hash(password, DEFAULT_COST).unwrap();
 
-        println!("password: {}, hashed_password: {}", password, hashed_password);
-        println!("password: {}, hashed_password_2: {}", password, hashed_password_2);
+        println!(
+            "password: {}, hashed_password: {}",
+            password, hashed_password
+        );
+        println!(
+            "password: {}, hashed_password_2: {}",
+            password, hashed_password_2
+        );
         assert!(verify(password, &hashed_password).unwrap());
         assert!(!verify("wrongpassword", &hashed_password).unwrap());
     }
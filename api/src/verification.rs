@@ -0,0 +1,510 @@
+use crate::config::Config;
+use crate::crypto::{constant_time_eq, hex, hmac_sha256_hex, unhex};
+use crate::error::ApiError;
+use crate::mailer::{Email, Mail};
+use crate::middleware::auth::AuthToken;
+use crate::store::Users;
+use crate::users::{is_valid_email, normalize_email, User};
+use bcrypt::verify as verify_password;
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Deserialize};
+use rocket::State;
+use rocket::{get, post, put};
+use uuid::Uuid;
+
+/// Verification links are not stored: the token is
+/// `<user_id>.<expires_at>.<hmac>`, signed with the token secret over the
+/// user id, the address being verified and the expiry. Changing the address
+/// therefore voids any link sent to the old one.
+fn signature(config: &Config, user_id: Uuid, email: &str, expires_at: i64) -> String {
+    sign(
+        config,
+        &[
+            "verify-email",
+            &user_id.to_string(),
+            email,
+            &expires_at.to_string(),
+        ],
+    )
+}
+
+/// HMAC over newline-separated fields; the first names the purpose so a
+/// signature for one kind of link is never valid for another.
+pub fn sign(config: &Config, fields: &[&str]) -> String {
+    hmac_sha256_hex(config.token_secret(), fields.join("\n").as_bytes())
+}
+
+pub fn verification_token(config: &Config, user: &User, expires_at: i64) -> String {
+    format!(
+        "{}.{}.{}",
+        user.user_id,
+        expires_at,
+        signature(config, user.user_id, &user.email, expires_at)
+    )
+}
+
+fn invalid_link() -> ApiError {
+    ApiError::validation(
+        "invalid_verification_token",
+        "Verification link is invalid or has expired",
+    )
+}
+
+/// Mails a verification link to the user's current address.
+pub async fn send_verification(
+    users: &Users,
+    mailer: &Mail,
+    config: &Config,
+    user: &User,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp_millis();
+    let expires_at = now + config.auth.verification_token_lifetime_secs * 1000;
+    let link = format!(
+        "{}/VerifyEmail?token={}",
+        config.mail.public_url.trim_end_matches('/'),
+        verification_token(config, user, expires_at)
+    );
+    let message = Email {
+        to: user.email.clone(),
+        subject: "Confirm your OpenMeet email address".to_string(),
+        body: format!(
+            "Hi {},\n\nPlease confirm this is your address by opening this link:\n\n{}\n\nIf you did not sign up for OpenMeet, ignore this message.\n",
+            user.username, link
+        ),
+    };
+    mailer
+        .send(&message)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to send verification mail: {}", e)))?;
+    users.set_verification_sent(user.user_id, now).await?;
+    Ok(())
+}
+
+/// Checks a link and marks the address verified. Using a link twice is fine.
+pub async fn verify(users: &Users, config: &Config, token: &str) -> Result<User, ApiError> {
+    let mut parts = token.splitn(3, '.');
+    let (user_id, expires_at, mac) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(user_id), Some(expires_at), Some(mac)) => (user_id, expires_at, mac),
+        _ => return Err(invalid_link()),
+    };
+    let user_id = Uuid::parse_str(user_id).map_err(|_| invalid_link())?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| invalid_link())?;
+
+    let mut user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(invalid_link)?;
+    if !constant_time_eq(mac, &signature(config, user_id, &user.email, expires_at)) {
+        return Err(invalid_link());
+    }
+    let now = Utc::now().timestamp_millis();
+    if expires_at <= now {
+        return Err(invalid_link());
+    }
+
+    if !user.email_verified {
+        users.set_email_verified(user_id, now).await?;
+        user.email_verified = true;
+        user.updated_at = now;
+    }
+    Ok(user)
+}
+
+/// Sends a new link, at most once per `verification_resend_interval_secs`.
+pub async fn resend(
+    users: &Users,
+    mailer: &Mail,
+    config: &Config,
+    auth: &AuthToken,
+) -> Result<(), ApiError> {
+    let user = users
+        .get_user_by_id(auth.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    if user.email_verified {
+        return Err(ApiError::conflict(
+            "already_verified",
+            "Email address is already verified",
+        ));
+    }
+
+    let next_allowed =
+        user.verification_sent_at + config.auth.verification_resend_interval_secs * 1000;
+    let now = Utc::now().timestamp_millis();
+    if now < next_allowed {
+        let wait = ((next_allowed - now) as u64).div_ceil(1000);
+        return Err(ApiError::too_many_requests(
+            "resend_throttled",
+            format!("Wait {} seconds before asking for another link", wait),
+            wait,
+        ));
+    }
+    send_verification(users, mailer, config, &user).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeRequest {
+    pub new_email: String,
+    pub password: String,
+}
+
+/// Address change links are `<user_id>.<expires_at>.<hex new_email>.<hmac>`,
+/// signed over the current address too, so they die if it changes first.
+fn change_signature(config: &Config, user: &User, new_email: &str, expires_at: i64) -> String {
+    sign(
+        config,
+        &[
+            "change-email",
+            &user.user_id.to_string(),
+            &user.email,
+            new_email,
+            &expires_at.to_string(),
+        ],
+    )
+}
+
+pub fn change_token(config: &Config, user: &User, new_email: &str, expires_at: i64) -> String {
+    format!(
+        "{}.{}.{}.{}",
+        user.user_id,
+        expires_at,
+        hex(new_email.as_bytes()),
+        change_signature(config, user, new_email, expires_at)
+    )
+}
+
+/// Starts moving the caller to `new_email` by mailing a link there. Nothing
+/// changes until the link is opened, which proves the address works.
+pub async fn request_change(
+    users: &Users,
+    mailer: &Mail,
+    config: &Config,
+    auth: &AuthToken,
+    new_email: &str,
+    password: &str,
+) -> Result<(), ApiError> {
+    let new_email = normalize_email(new_email);
+    let new_email = new_email.as_str();
+    let user = users
+        .get_user_by_id(auth.user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("user_not_found", "User not found"))?;
+    let matches = verify_password(password, &user.password_hash)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    if !matches {
+        return Err(ApiError::forbidden(
+            "wrong_password",
+            "Current password is incorrect",
+        ));
+    }
+    if !is_valid_email(new_email).await {
+        return Err(ApiError::validation("invalid_email", "Invalid email"));
+    }
+    if new_email == user.email {
+        return Err(ApiError::validation(
+            "same_email",
+            "That is already your email address",
+        ));
+    }
+    // only an early answer; `change_email` is what actually guards the address
+    if users.email_exists(new_email).await? {
+        return Err(ApiError::conflict("email_taken", "Email already exists"));
+    }
+
+    let expires_at =
+        Utc::now().timestamp_millis() + config.auth.verification_token_lifetime_secs * 1000;
+    let link = format!(
+        "{}/VerifyEmail?change=1&token={}",
+        config.mail.public_url.trim_end_matches('/'),
+        change_token(config, &user, new_email, expires_at)
+    );
+    let message = Email {
+        to: new_email.to_string(),
+        subject: "Confirm your new OpenMeet email address".to_string(),
+        body: format!(
+            "Hi {},\n\nTo use this address for your OpenMeet account, open this link:\n\n{}\n\nIf you did not ask for this, ignore this message.\n",
+            user.username, link
+        ),
+    };
+    mailer
+        .send(&message)
+        .await
+        .map_err(|e| ApiError::Internal(format!("Failed to send email change mail: {}", e)))
+}
+
+/// Applies an address change from a link made by `request_change`.
+pub async fn confirm_change(
+    users: &Users,
+    mailer: &Mail,
+    config: &Config,
+    token: &str,
+) -> Result<User, ApiError> {
+    let mut parts = token.splitn(4, '.');
+    let (user_id, expires_at, new_email, mac) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(user_id), Some(expires_at), Some(new_email), Some(mac)) => {
+                (user_id, expires_at, new_email, mac)
+            }
+            _ => return Err(invalid_link()),
+        };
+    let user_id = Uuid::parse_str(user_id).map_err(|_| invalid_link())?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| invalid_link())?;
+    let new_email = unhex(new_email)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(invalid_link)?;
+
+    let user = users
+        .get_user_by_id(user_id)
+        .await?
+        .ok_or_else(invalid_link)?;
+    if !constant_time_eq(
+        mac,
+        &change_signature(config, &user, &new_email, expires_at),
+    ) {
+        return Err(invalid_link());
+    }
+    let now = Utc::now().timestamp_millis();
+    if expires_at <= now {
+        return Err(invalid_link());
+    }
+
+    // links mailed before addresses were normalized carry them as typed
+    let new_email = normalize_email(&new_email);
+    if !users.change_email(&user, &new_email, now).await? {
+        return Err(ApiError::conflict("email_taken", "Email already exists"));
+    }
+
+    // let the old address know, in case it was not its owner who did this
+    let notice = Email {
+        to: user.email.clone(),
+        subject: "Your OpenMeet email address was changed".to_string(),
+        body: format!(
+            "Hi {},\n\nYour OpenMeet account now uses {}. If you did not do this, reset your password and contact us.\n",
+            user.username, new_email
+        ),
+    };
+    if let Err(e) = mailer.send(&notice).await {
+        eprintln!("Failed to send email change notice: {}", e);
+    }
+
+    Ok(User {
+        email: new_email,
+        email_verified: true,
+        updated_at: now,
+        ..user
+    })
+}
+
+#[get("/verify-email/<token>")]
+pub async fn verify_email(
+    users: &State<Users>,
+    config: &State<Config>,
+    token: &str,
+) -> Result<Status, ApiError> {
+    verify(users, config, token).await?;
+    Ok(Status::NoContent)
+}
+
+#[post("/verify-email/resend")]
+pub async fn resend_verification(
+    users: &State<Users>,
+    mailer: &State<Mail>,
+    config: &State<Config>,
+    auth: AuthToken,
+) -> Result<Status, ApiError> {
+    resend(users, mailer, config, &auth).await?;
+    Ok(Status::Accepted)
+}
+
+#[put("/users/me/email", data = "<request>")]
+pub async fn change_email(
+    users: &State<Users>,
+    mailer: &State<Mail>,
+    config: &State<Config>,
+    auth: AuthToken,
+    request: Json<EmailChangeRequest>,
+) -> Result<Status, ApiError> {
+    request_change(
+        users,
+        mailer,
+        config,
+        &auth,
+        &request.new_email,
+        &request.password,
+    )
+    .await?;
+    Ok(Status::Accepted)
+}
+
+#[get("/verify-email-change/<token>")]
+pub async fn confirm_email_change(
+    users: &State<Users>,
+    mailer: &State<Mail>,
+    config: &State<Config>,
+    token: &str,
+) -> Result<Status, ApiError> {
+    confirm_change(users, mailer, config, token).await?;
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::Outbox;
+    use crate::store::MemoryStore;
+    use crate::users::{create_user, Role};
+    use std::sync::Arc;
+
+    async fn setup() -> (Users, Config, User) {
+        let users: Users = Arc::new(MemoryStore::default());
+        let config = Config::for_tests();
+        let user = User {
+            user_id: Uuid::new_v4(),
+            username: "testuser".to_string(),
+            email: "testuserVERIFY@example.com".to_string(),
+            password_hash: "password123".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            last_login: 0,
+            role: Role::Member,
+            description: String::new(),
+            interests: Vec::new(),
+            email_verified: true,
+            verification_sent_at: 0,
+        };
+        let user = create_user(&users, &config, user).await.unwrap();
+        (users, config, user)
+    }
+
+    fn auth(user: &User) -> AuthToken {
+        AuthToken {
+            user_id: user.user_id,
+            session_id: Uuid::new_v4(),
+            role: user.role,
+            email_verified: user.email_verified,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_users_start_unverified_and_link_verifies() {
+        let (users, config, user) = setup().await;
+        assert!(!user.email_verified);
+
+        let token = verification_token(&config, &user, Utc::now().timestamp_millis() + 60_000);
+        let verified = verify(&users, &config, &token).await.unwrap();
+        assert!(verified.email_verified);
+        let stored = users.get_user_by_id(user.user_id).await.unwrap().unwrap();
+        assert!(stored.email_verified);
+    }
+
+    #[tokio::test]
+    async fn test_forged_expired_or_stale_links_are_refused() {
+        let (users, config, mut user) = setup().await;
+        let later = Utc::now().timestamp_millis() + 60_000;
+
+        let token = verification_token(&config, &user, later);
+        let forged = format!("{}0", token);
+        let expired = verification_token(&config, &user, Utc::now().timestamp_millis() - 1);
+        user.email = "someone-else@example.com".to_string();
+        let other_address = verification_token(&config, &user, later);
+
+        for token in [forged.as_str(), &expired, &other_address, "garbage"] {
+            let err = verify(&users, &config, token).await.unwrap_err();
+            assert_eq!(err.code(), "invalid_verification_token");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_email_change_moves_address_once_confirmed() {
+        let (users, config, user) = setup().await;
+        let outbox = Arc::new(Outbox::default());
+        let mailer: Mail = outbox.clone();
+
+        let err = request_change(
+            &users,
+            &mailer,
+            &config,
+            &auth(&user),
+            "new@example.com",
+            "wrong",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), "wrong_password");
+
+        request_change(
+            &users,
+            &mailer,
+            &config,
+            &auth(&user),
+            "new@example.com",
+            "password123",
+        )
+        .await
+        .unwrap();
+        let sent = outbox.0.lock().unwrap().last().unwrap().clone();
+        assert_eq!(sent.to, "new@example.com");
+        // nothing moves before the link is used
+        assert!(!users.email_exists("new@example.com").await.unwrap());
+
+        let token = change_token(
+            &config,
+            &user,
+            "new@example.com",
+            Utc::now().timestamp_millis() + 60_000,
+        );
+        let changed = confirm_change(&users, &mailer, &config, &token)
+            .await
+            .unwrap();
+        assert_eq!(changed.email, "new@example.com");
+        assert!(changed.email_verified);
+        assert!(users.email_exists("new@example.com").await.unwrap());
+        assert!(!users.email_exists(&user.email).await.unwrap());
+
+        // the link was signed over the old address, so it cannot be replayed
+        let err = confirm_change(&users, &mailer, &config, &token)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_verification_token");
+    }
+
+    #[tokio::test]
+    async fn test_email_change_to_a_taken_address_fails() {
+        let (users, config, user) = setup().await;
+        let mailer: Mail = Arc::new(Outbox::default());
+        let token = change_token(
+            &config,
+            &user,
+            "taken@example.com",
+            Utc::now().timestamp_millis() + 60_000,
+        );
+        // someone registers the address after the link went out
+        let mut other = user.clone();
+        other.email = "taken@example.com".to_string();
+        create_user(&users, &config, other).await.unwrap();
+
+        let err = confirm_change(&users, &mailer, &config, &token)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "email_taken");
+        let stored = users.get_user_by_id(user.user_id).await.unwrap().unwrap();
+        assert_eq!(stored.email, user.email);
+    }
+
+    #[tokio::test]
+    async fn test_resend_is_throttled() {
+        let (users, config, user) = setup().await;
+        let outbox = Arc::new(Outbox::default());
+        let mailer: Mail = outbox.clone();
+
+        resend(&users, &mailer, &config, &auth(&user))
+            .await
+            .unwrap();
+        let err = resend(&users, &mailer, &config, &auth(&user))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "resend_throttled");
+        assert_eq!(outbox.0.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,49 @@
+const API_URL = "http://localhost:8000";
+
+// Swap the stored refresh token for a new token pair; false when there is
+// none or the server refused it (revoked, expired), so callers can give up.
+export async function refreshTokens() {
+  const refreshToken = localStorage.getItem("refresh_token");
+  if (!refreshToken) {
+    return false;
+  }
+  const response = await fetch(`${API_URL}/token/refresh`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ refresh_token: refreshToken }),
+  });
+  if (!response.ok) {
+    return false;
+  }
+  const tokens = await response.json();
+  localStorage.setItem("token", tokens.access_token);
+  localStorage.setItem("refresh_token", tokens.refresh_token);
+  return true;
+}
+
+async function isTokenExpired(response) {
+  if (response.status !== 401) {
+    return false;
+  }
+  const data = await response.clone().json().catch(() => ({}));
+  return data.code === "token_expired";
+}
+
+// fetch() against the API with the stored access token; when that token has
+// expired it is refreshed and the request retried once.
+export async function apiFetch(path, options = {}) {
+  const send = () => {
+    const headers = { ...options.headers };
+    const token = localStorage.getItem("token");
+    if (token) {
+      headers.Authorization = `Bearer ${token}`;
+    }
+    return fetch(`${API_URL}${path}`, { ...options, headers });
+  };
+
+  const response = await send();
+  if ((await isTokenExpired(response)) && (await refreshTokens())) {
+    return send();
+  }
+  return response;
+}